@@ -0,0 +1,34 @@
+//! Compares the streaming and the parallel `chash` of a file.
+//!
+//! Run with `cargo bench --bench chash`. `HD_BENCH_MB` sets the file size (default: 64 MiB).
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use hd_api::hashing;
+
+fn bench_chash(c: &mut Criterion) {
+    let mb: usize = std::env::var("HD_BENCH_MB")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(64);
+    let path = std::env::temp_dir().join(format!("hd_api_bench_{}", std::process::id()));
+    let data: Vec<u8> = (0..mb << 20).map(|i| (i * 7 % 251) as u8).collect();
+    std::fs::write(&path, data).unwrap();
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let mut g = c.benchmark_group("chash");
+    g.sample_size(10)
+        .throughput(Throughput::Bytes((mb << 20) as u64));
+    g.bench_function("chash_file", |b| {
+        b.iter(|| rt.block_on(hashing::chash_file(&path)).unwrap())
+    });
+    g.bench_function("chash_file_parallel", |b| {
+        b.iter(|| rt.block_on(hashing::chash_file_parallel(&path)).unwrap())
+    });
+    g.finish();
+    std::fs::remove_file(&path).unwrap();
+}
+
+criterion_group!(benches, bench_chash);
+criterion_main!(benches);
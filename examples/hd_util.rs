@@ -1,4 +1,5 @@
-use clap::{Parser, Subcommand};
+use clap::{Args as ClapArgs, Parser, Subcommand};
+use futures_util::StreamExt;
 use log::info;
 use serde_json::to_string_pretty;
 use tokio::io::{AsyncRead, AsyncWrite};
@@ -10,16 +11,98 @@ use hd_api::{Identifier, Params};
 
 #[derive(Subcommand)]
 enum Commands {
-    List { folder: String },
-    Delete { file: String },
-    Get { file: String },
-    Put { file: String, folder: String },
-    Mvfile { from: String, to: String },
-    Thumbnail { path: String },
-    Url { path: String },
-    Metadata { path: String },
-    Search { term: String },
-    Listen { },
+    List {
+        folder: String,
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    Delete {
+        file: String,
+    },
+    Get {
+        file: String,
+    },
+    Put {
+        file: String,
+        folder: String,
+    },
+    Mvfile {
+        from: String,
+        to: String,
+    },
+    Thumbnail {
+        path: String,
+    },
+    Url {
+        path: String,
+    },
+    Metadata {
+        path: String,
+    },
+    Search {
+        term: String,
+    },
+    Listen {
+        folder: Option<String>,
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    Share {
+        #[command(subcommand)]
+        action: ShareCommands,
+    },
+}
+
+#[derive(ClapArgs)]
+struct ShareOptions {
+    /// Validity in seconds.
+    #[arg(long)]
+    ttl: Option<u64>,
+    /// Maximum number of downloads.
+    #[arg(long)]
+    maxcount: Option<u64>,
+    /// Password; empty to remove it.
+    #[arg(long)]
+    password: Option<String>,
+}
+
+impl ShareOptions {
+    fn to_params(&self) -> Params {
+        let mut p = Params::new();
+        if let Some(ttl) = self.ttl {
+            p.add_str("ttl", ttl.to_string());
+        }
+        if let Some(maxcount) = self.maxcount {
+            p.add_str("maxcount", maxcount.to_string());
+        }
+        if let Some(ref password) = self.password {
+            p.add_str("password", password);
+        }
+        p
+    }
+}
+
+#[derive(Subcommand)]
+enum ShareCommands {
+    Create {
+        path: String,
+        #[command(flatten)]
+        opts: ShareOptions,
+    },
+    List {
+        path: Option<String>,
+    },
+    Get {
+        id: String,
+    },
+    Update {
+        id: String,
+        #[command(flatten)]
+        opts: ShareOptions,
+    },
+    Delete {
+        id: String,
+    },
 }
 
 #[derive(Parser)]
@@ -34,7 +117,7 @@ struct Home {
     id: String,
 }
 
-async fn list_me(mut u: hidrive::HiDriveUser<'_>) -> anyhow::Result<Home> {
+async fn list_me(u: hidrive::HiDriveUser<'_>) -> hd_api::Result<Home> {
     let mut p = Params::new();
     p.add_str("fields", "home,home_id");
     let me = u.me(Some(&p)).await?;
@@ -44,18 +127,44 @@ async fn list_me(mut u: hidrive::HiDriveUser<'_>) -> anyhow::Result<Home> {
     })
 }
 
-async fn listen<S: AsyncRead+AsyncWrite+Unpin>(mut u: hidrive::HiDriveNotifications<'_, S>) -> anyhow::Result<()> {
+async fn listen<S: AsyncRead + AsyncWrite + Unpin>(
+    mut u: hidrive::HiDriveNotifications<'_, S>,
+    pid: Option<String>,
+    recursive: bool,
+) -> hd_api::Result<()> {
+    if let Some(pid) = pid {
+        let subs_id = u.subscribe(&pid, recursive).await?;
+        info!("subscribed to {} as {}", pid, subs_id);
+    }
     while let Ok(Some(it)) = u.next().await {
         println!("{}", to_string_pretty(&it)?);
     }
     Ok(())
 }
 
+async fn dir_id(
+    u: hidrive::HiDriveFiles<'_>,
+    home: Home,
+    folder: impl AsRef<str>,
+) -> hd_api::Result<String> {
+    let it = u
+        .metadata(
+            Identifier::Relative {
+                id: home.id,
+                path: folder.as_ref().to_string(),
+            },
+            "id",
+            None,
+        )
+        .await?;
+    it.id.ok_or_else(|| hd_api::Error::msg("metadata lacks id"))
+}
+
 async fn delete_file(
-    mut u: hidrive::HiDriveFiles<'_>,
+    u: hidrive::HiDriveFiles<'_>,
     home: Home,
     file: impl AsRef<str>,
-) -> anyhow::Result<()> {
+) -> hd_api::Result<()> {
     let id = Identifier::Relative {
         id: home.id,
         path: file.as_ref().to_string(),
@@ -64,11 +173,11 @@ async fn delete_file(
 }
 
 async fn mv_file(
-    mut u: hidrive::HiDriveFiles<'_>,
+    u: hidrive::HiDriveFiles<'_>,
     home: Home,
     from: impl AsRef<str>,
     to: impl AsRef<str>,
-) -> anyhow::Result<()> {
+) -> hd_api::Result<()> {
     let from = Identifier::Relative {
         id: home.id.clone(),
         path: from.as_ref().to_string(),
@@ -82,44 +191,49 @@ async fn mv_file(
 }
 
 async fn list_files(
-    mut u: hidrive::HiDriveFiles<'_>,
+    u: hidrive::HiDriveFiles<'_>,
     home: Home,
     folder: impl AsRef<str>,
-) -> anyhow::Result<()> {
-    let mut p = Params::new();
-    p.add_str(
-        "fields",
-        "name,id,parent_id,nmembers,type,members,readable,writable,size,members.size,members.chash,members.nmembers",
-    );
+    recursive: bool,
+) -> hd_api::Result<()> {
     let id = Identifier::Relative {
         id: home.id,
         path: folder.as_ref().to_string(),
     };
     info!(target: "get_file", "Checking directory...");
-    let dir = u.get_dir(id, Some(&p)).await?;
-    let mapper = |f: types::Item| {
-        (
-            if let Some(s) = f.nmembers {
-                format!("{:3} sub", s)
-            } else {
-                format!("{} B", f.size.expect("file size"))
-            },
-            f.name.expect("missing file name in response"),
-        )
+    let mapper = |f: &types::Item| {
+        if let Some(s) = f.nmembers {
+            format!("{:3} sub", s)
+        } else {
+            format!("{} B", f.size.expect("file size"))
+        }
     };
-    let files = dir.members.into_iter().map(mapper).collect::<Vec<_>>();
-    for f in files.iter() {
-        println!("{:32} ({})", f.1, f.0);
+    if recursive {
+        let mut items = u.walk(id, "path,nmembers,size").stream();
+        while let Some(f) = items.next().await {
+            let f = f?;
+            println!("{:48} ({})", f.path, mapper(&f));
+        }
+    } else {
+        let mut members = u.list_dir(id, "name,nmembers,size", hidrive::DIR_PAGE_SIZE);
+        while let Some(f) = members.next().await {
+            let f = f?;
+            println!(
+                "{:32} ({})",
+                f.name.as_deref().expect("missing file name in response"),
+                mapper(&f)
+            );
+        }
     }
 
     Ok(())
 }
 
 async fn get_file(
-    mut u: hidrive::HiDriveFiles<'_>,
+    u: hidrive::HiDriveFiles<'_>,
     home: Home,
     file: impl AsRef<str>,
-) -> anyhow::Result<()> {
+) -> hd_api::Result<()> {
     let path = file.as_ref();
     let basename = Path::new(&path)
         .file_name()
@@ -140,10 +254,10 @@ async fn get_file(
 }
 
 async fn url(
-    mut u: hidrive::HiDriveFiles<'_>,
+    u: hidrive::HiDriveFiles<'_>,
     home: Home,
     file: impl AsRef<str>,
-) -> anyhow::Result<()> {
+) -> hd_api::Result<()> {
     let url = u
         .url(
             Identifier::Relative {
@@ -158,10 +272,10 @@ async fn url(
 }
 
 async fn metadata(
-    mut u: hidrive::HiDriveFiles<'_>,
+    u: hidrive::HiDriveFiles<'_>,
     home: Home,
     file: impl AsRef<str>,
-) -> anyhow::Result<()> {
+) -> hd_api::Result<()> {
     let it = u
         .metadata(
             Identifier::Relative {
@@ -177,10 +291,10 @@ async fn metadata(
 }
 
 async fn search(
-    mut u: hidrive::HiDriveFiles<'_>,
+    u: hidrive::HiDriveFiles<'_>,
     home: Home,
     term: impl AsRef<str>,
-) -> anyhow::Result<()> {
+) -> hd_api::Result<()> {
     let mut p = Params::new();
     p.add_str("pattern", term);
     let it = u.search(Identifier::Id(home.id), "", Some(&p)).await?;
@@ -191,10 +305,10 @@ async fn search(
 }
 
 async fn thumbnail(
-    mut u: hidrive::HiDriveFiles<'_>,
+    u: hidrive::HiDriveFiles<'_>,
     home: Home,
     file: impl AsRef<str>,
-) -> anyhow::Result<()> {
+) -> hd_api::Result<()> {
     let basename = Path::new(file.as_ref())
         .file_name()
         .expect("file name to string")
@@ -216,11 +330,11 @@ async fn thumbnail(
 }
 
 async fn put_file(
-    mut u: hidrive::HiDriveFiles<'_>,
+    u: hidrive::HiDriveFiles<'_>,
     home: Home,
     file: impl AsRef<str>,
     path: impl AsRef<str>,
-) -> anyhow::Result<()> {
+) -> hd_api::Result<()> {
     let filename = file.as_ref();
     let path = path.as_ref();
 
@@ -243,6 +357,40 @@ async fn put_file(
     Ok(())
 }
 
+async fn share(
+    u: hidrive::HiDriveShareLinks<'_>,
+    home: Home,
+    action: &ShareCommands,
+) -> hd_api::Result<()> {
+    let rel = |path: &str| Identifier::Relative {
+        id: home.id.clone(),
+        path: path.to_string(),
+    };
+    match action {
+        ShareCommands::Create { path, opts } => {
+            let s = u.create(rel(path), Some(&opts.to_params())).await?;
+            println!("{}", s.uri.unwrap_or_default());
+        }
+        ShareCommands::List { path } => {
+            for s in u.list(path.as_deref().map(rel), None).await? {
+                println!(
+                    "{:12} {:48} {}",
+                    s.id.unwrap_or_default(),
+                    s.path.unwrap_or_default(),
+                    s.uri.unwrap_or_default()
+                );
+            }
+        }
+        ShareCommands::Get { id } => println!("{}", to_string_pretty(&u.get(id, None).await?)?),
+        ShareCommands::Update { id, opts } => {
+            let s = u.update(id, Some(&opts.to_params())).await?;
+            println!("{}", to_string_pretty(&s)?);
+        }
+        ShareCommands::Delete { id } => u.delete(id).await?,
+    }
+    Ok(())
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
     simple_logger::init_with_level(log::Level::Info).unwrap();
@@ -251,19 +399,19 @@ async fn main() {
 
     let client = reqwest::Client::new();
 
-    // We assume that credentials already exist.
-    let cred = oauth2::Credentials::load("credentials.json").await.expect("Credentials couldn't be read: make sure they are there and/or authorize using the `user_me` example.");
+    // We assume that credentials already exist. Refreshed credentials are written back.
     let cid = oauth2::ClientSecret::load("clientsecret.json")
         .await
         .unwrap();
-    let authz = oauth2::Authorizer::new_with_client(cred, cid, client.clone());
+    let store = oauth2::FileTokenStore::new("credentials.json");
+    let authz = oauth2::Authorizer::new_with_store(store, cid, client.clone()).await.expect("Credentials couldn't be read: make sure they are there and/or authorize using the `user_me` example.");
 
-    let mut hd = hidrive::HiDrive::new(client, authz);
+    let hd = hidrive::HiDrive::new(client, authz);
 
     let home = list_me(hd.user()).await.expect("query user info");
 
     match &cli.command {
-        Commands::List { folder } => list_files(hd.files(), home, folder)
+        Commands::List { folder, recursive } => list_files(hd.files(), home, folder, *recursive)
             .await
             .expect("list_files"),
         Commands::Get { file } => get_file(hd.files(), home, file).await.expect("get_file"),
@@ -280,6 +428,19 @@ async fn main() {
         Commands::Url { path } => url(hd.files(), home, path).await.expect("url"),
         Commands::Metadata { path } => metadata(hd.files(), home, path).await.expect("metadata"),
         Commands::Search { term } => search(hd.files(), home, term).await.expect("search"),
-        Commands::Listen { } => listen(hd.notifications().await.expect("notifications")).await.expect("listen"),
+        Commands::Listen { folder, recursive } => {
+            let pid = match folder {
+                Some(f) => Some(dir_id(hd.files(), home, f).await.expect("dir_id")),
+                None => None,
+            };
+            listen(
+                hd.notifications().await.expect("notifications"),
+                pid,
+                *recursive,
+            )
+            .await
+            .expect("listen")
+        }
+        Commands::Share { action } => share(hd.sharelinks(), home, action).await.expect("share"),
     }
 }
@@ -1,4 +1,2 @@
-use tokio;
-
 #[tokio::main(flavor = "current_thread")]
 async fn main() {}
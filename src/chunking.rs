@@ -1,4 +1,4 @@
-use anyhow::{self, Result};
+use crate::error::Result;
 use rolling_dual_crc::RollingDualCrc;
 
 use tokio::io::{AsyncBufRead, AsyncReadExt};
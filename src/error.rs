@@ -0,0 +1,262 @@
+//! The error type returned by all fallible functions of this crate.
+//!
+//! Errors returned by the HiDrive API carry the HTTP status and the decoded `ApiError`, so that
+//! callers can distinguish e.g. a missing file from a conflict without downcasting:
+//!
+//! ```ignore
+//! match hd.files().delete(id, None).await {
+//!     Err(e) if e.is_not_found() => (),
+//!     r => r?,
+//! }
+//! ```
+
+use crate::oauth2::OAuthError;
+use crate::types::ApiError;
+
+use std::fmt::{self, Display, Formatter};
+
+use reqwest::StatusCode;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The HiDrive API answered with a non-success HTTP status.
+    Api { status: StatusCode, error: ApiError },
+    /// The OAuth2 provider refused to issue a token, or the authorization flow failed.
+    Auth(OAuthError),
+    /// The HTTP request couldn't be sent or its response couldn't be received.
+    Transport(reqwest::Error),
+    /// The notification WebSocket failed.
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+    /// A response or file couldn't be decoded.
+    Json(serde_json::Error),
+    /// Local I/O failed.
+    Io(std::io::Error),
+    /// A transfer was aborted through its `CancellationToken`.
+    Cancelled,
+    /// Any other error, described by a message.
+    Other(String),
+    /// An error annotated with a description of what was being done.
+    Context { context: String, source: Box<Error> },
+}
+
+impl Error {
+    pub fn msg<S: Into<String>>(msg: S) -> Error {
+        Error::Other(msg.into())
+    }
+
+    /// Wrap this error in a description of what was being done.
+    pub fn context<C: Into<String>>(self, context: C) -> Error {
+        Error::Context {
+            context: context.into(),
+            source: Box::new(self),
+        }
+    }
+
+    /// Returns the innermost error, skipping any `Context` layers.
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Context { source, .. } => source.root(),
+            e => e,
+        }
+    }
+
+    /// HTTP status of an API error.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self.root() {
+            Error::Api { status, .. } => Some(*status),
+            Error::Transport(e) => e.status(),
+            _ => None,
+        }
+    }
+
+    /// The error object returned by the HiDrive API, if any.
+    pub fn api_error(&self) -> Option<&ApiError> {
+        match self.root() {
+            Error::Api { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+
+    /// The file or directory doesn't exist (404).
+    pub fn is_not_found(&self) -> bool {
+        self.status() == Some(StatusCode::NOT_FOUND)
+    }
+
+    /// The target already exists, or a precondition such as `parent_mtime` failed (409).
+    pub fn is_conflict(&self) -> bool {
+        self.status() == Some(StatusCode::CONFLICT)
+    }
+
+    /// The access token was rejected (401), or the OAuth2 provider refused to issue one.
+    pub fn is_auth(&self) -> bool {
+        match self.root() {
+            Error::Auth(_) => true,
+            e => e.status() == Some(StatusCode::UNAUTHORIZED),
+        }
+    }
+
+    /// The operation was cancelled by the caller.
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self.root(), Error::Cancelled)
+    }
+
+    /// The failure is likely transient, and the request may succeed if repeated: connection
+    /// problems, timeouts, rate limiting (429), and server errors (500, 502, 503, 504).
+    pub fn is_retryable(&self) -> bool {
+        match self.root() {
+            Error::Transport(e) => {
+                e.is_timeout() || e.is_connect() || (e.is_request() && e.status().is_none())
+            }
+            Error::Api { status, .. } => is_transient_status(*status),
+            _ => false,
+        }
+    }
+}
+
+/// Statuses indicating that a request may succeed if repeated.
+pub(crate) fn is_transient_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Error::Api { status, error } => write!(f, "HTTP {}: {}", status, error),
+            Error::Auth(e) => e.fmt(f),
+            Error::Transport(e) => write!(f, "HTTP transport error: {}", e),
+            Error::WebSocket(e) => write!(f, "WebSocket error: {}", e),
+            Error::Json(e) => write!(f, "JSON error: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Cancelled => f.write_str("transfer cancelled"),
+            Error::Other(s) => f.write_str(s),
+            // The cause is left to `source()`; the alternate form `{:#}` prints the whole chain.
+            Error::Context { context, source } if f.alternate() => {
+                write!(f, "{}: {:#}", context, source)
+            }
+            Error::Context { context, .. } => f.write_str(context),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Api { error, .. } => Some(error),
+            Error::Auth(e) => Some(e),
+            Error::Transport(e) => Some(e),
+            Error::WebSocket(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Cancelled | Error::Other(_) => None,
+            Error::Context { source, .. } => Some(source),
+        }
+    }
+}
+
+impl From<OAuthError> for Error {
+    fn from(e: OAuthError) -> Error {
+        Error::Auth(e)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Error {
+        Error::Transport(e)
+    }
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for Error {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Error {
+        Error::WebSocket(Box::new(e))
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// Attach context to errors, in the manner of `anyhow::Context`.
+pub(crate) trait Context<T> {
+    fn context<C: Into<String>>(self, context: C) -> Result<T>;
+    fn with_context<C: Into<String>, F: FnOnce() -> C>(self, f: F) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> Context<T> for std::result::Result<T, E> {
+    fn context<C: Into<String>>(self, context: C) -> Result<T> {
+        self.map_err(|e| e.into().context(context))
+    }
+
+    fn with_context<C: Into<String>, F: FnOnce() -> C>(self, f: F) -> Result<T> {
+        self.map_err(|e| e.into().context(f()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_error(status: StatusCode) -> Error {
+        Error::Api {
+            status,
+            error: ApiError {
+                msg: "test".into(),
+                code: status.as_u16() as usize,
+                auth: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_predicates_see_through_context() {
+        let e = api_error(StatusCode::NOT_FOUND).context("GET /file");
+        assert!(e.is_not_found());
+        assert!(!e.is_conflict());
+        assert!(!e.is_retryable());
+        assert_eq!(404, e.api_error().unwrap().code);
+
+        let e = api_error(StatusCode::CONFLICT)
+            .context("POST /dir")
+            .context("mkdir");
+        assert!(e.is_conflict());
+        assert_eq!("mkdir", e.to_string());
+        assert_eq!(
+            "mkdir: POST /dir: HTTP 409 Conflict: ApiError 409: test",
+            format!("{:#}", e)
+        );
+        let causes = std::iter::successors(std::error::Error::source(&e), |e| e.source());
+        assert_eq!(
+            vec![
+                "POST /dir",
+                "HTTP 409 Conflict: ApiError 409: test",
+                "ApiError 409: test"
+            ],
+            causes.map(|c| c.to_string()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_retryable() {
+        assert!(api_error(StatusCode::SERVICE_UNAVAILABLE).is_retryable());
+        assert!(api_error(StatusCode::TOO_MANY_REQUESTS).is_retryable());
+        assert!(!api_error(StatusCode::FORBIDDEN).is_retryable());
+        assert!(!Error::msg("hash mismatch").is_retryable());
+        assert!(api_error(StatusCode::UNAUTHORIZED).is_auth());
+    }
+}
@@ -0,0 +1,399 @@
+//! A persistent cache of the content hashes of local files, so that unchanged files needn't be
+//! read again on every sync.
+//!
+//! Entries are keyed by device and inode, and are only valid while the size, mtime and ctime of the
+//! file are the same as when it was hashed. Any write to a file changes its mtime and ctime, and
+//! the ctime can't be set back by tools preserving mtimes; an inode which is freed and reused gets
+//! a new ctime, too. Additionally:
+//!
+//! * A file whose mtime is less than `RACY_SECS` before the time it was hashed isn't cached, as it
+//!   may be written again without a visible change of its timestamps on file systems with coarse
+//!   timestamp resolution.
+//! * A file whose metadata changed while it was being hashed isn't cached.
+//! * Entries which haven't been used since the cache was loaded can be dropped with `prune`.
+//!
+//! When a cache is installed with `set_global`, `hashing::file_hashes` and `hashing::DirHasher`
+//! (and thereby `sync::DirSync`) consult it automatically:
+//!
+//! ```ignore
+//! let cache = Arc::new(HashCache::open("/home/me/.cache/hd_hashes").await?);
+//! hashcache::set_global(Some(cache.clone()));
+//! sync.run(&hd.files()).await?;
+//! cache.prune();
+//! cache.save().await?;
+//! ```
+//!
+//! The file consists of a header (`MAGIC`, format version as little-endian `u32`) followed by
+//! records of little-endian integers: device, inode and size (`u64` each), mtime and ctime
+//! (seconds as `i64`, nanoseconds as `u32`), the `chash`, the number of level 1 hashes (`u32`)
+//! and the level 1 hashes, 20 bytes each. A file which can't be decoded is discarded.
+//!
+//! Files are only identified by device and inode on Unix. Elsewhere the cache stays empty: lookups
+//! miss and nothing is stored.
+
+use crate::error::{Context, Result};
+use crate::hashing::{ChashHasher, Hash};
+
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const MAGIC: &[u8; 4] = b"HDHC";
+const VERSION: u32 = 1;
+/// Files modified less than this many seconds before hashing aren't cached.
+pub const RACY_SECS: i64 = 2;
+
+const HASH_LEN: usize = 20;
+
+static GLOBAL: RwLock<Option<Arc<HashCache>>> = RwLock::new(None);
+
+/// Install `cache` as the cache consulted by the functions of `hashing`, or remove it.
+pub fn set_global(cache: Option<Arc<HashCache>>) {
+    *GLOBAL.write().unwrap() = cache;
+}
+
+/// The cache installed with `set_global`.
+pub fn global() -> Option<Arc<HashCache>> {
+    GLOBAL.read().unwrap().clone()
+}
+
+/// Cached hashes of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedHashes {
+    pub chash: Hash,
+    /// The hashes of level 1 of the tree, one per 1 MiB of content; empty for files of up to one
+    /// block.
+    pub level1: Vec<Hash>,
+}
+
+/// The metadata an entry is valid for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    size: u64,
+    mtime: (i64, u32),
+    ctime: (i64, u32),
+}
+
+/// The key and stamp of the file with metadata `md`.
+#[cfg(unix)]
+fn identify(md: &std::fs::Metadata) -> Option<((u64, u64), Stamp)> {
+    let stamp = Stamp {
+        size: md.len(),
+        mtime: (md.mtime(), md.mtime_nsec() as u32),
+        ctime: (md.ctime(), md.ctime_nsec() as u32),
+    };
+    Some(((md.dev(), md.ino()), stamp))
+}
+
+#[cfg(not(unix))]
+fn identify(_md: &std::fs::Metadata) -> Option<((u64, u64), Stamp)> {
+    None
+}
+
+#[derive(Debug)]
+struct Entry {
+    stamp: Stamp,
+    hashes: CachedHashes,
+    used: bool,
+}
+
+/// Hash cache, shared between tasks through an `Arc`.
+#[derive(Debug, Default)]
+pub struct HashCache {
+    path: Option<PathBuf>,
+    entries: Mutex<HashMap<(u64, u64), Entry>>,
+}
+
+impl HashCache {
+    /// A cache which isn't persisted.
+    pub fn in_memory() -> HashCache {
+        HashCache::default()
+    }
+
+    /// Load the cache file at `path`. It is created by `save` if it doesn't exist.
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<HashCache> {
+        let path = path.as_ref().to_path_buf();
+        let mut data = vec![];
+        match fs::File::open(&path).await {
+            Ok(mut f) => {
+                f.read_to_end(&mut data).await?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        let entries = if data.is_empty() {
+            HashMap::new()
+        } else {
+            decode(&data).unwrap_or_else(|| {
+                warn!(target: "hd_api::hashcache", "discarding undecodable cache {:?}", path);
+                HashMap::new()
+            })
+        };
+        info!(target: "hd_api::hashcache", "loaded {} entries from {:?}", entries.len(), path);
+        Ok(HashCache {
+            path: Some(path),
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The cached hashes of the file with metadata `md`, if they are still valid.
+    pub fn get(&self, md: &std::fs::Metadata) -> Option<CachedHashes> {
+        let (key, stamp) = identify(md)?;
+        let mut entries = self.entries.lock().unwrap();
+        let e = entries.get_mut(&key)?;
+        if e.stamp != stamp {
+            entries.remove(&key);
+            return None;
+        }
+        e.used = true;
+        Some(e.hashes.clone())
+    }
+
+    /// Record the hashes of the file with metadata `md`, which were computed at `hashed_at`.
+    /// Returns whether they were cached; see the module documentation for when they aren't.
+    pub fn put(&self, md: &std::fs::Metadata, hashed_at: SystemTime, hashes: CachedHashes) -> bool {
+        let hashed_at = hashed_at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        let Some((key, stamp)) = identify(md) else {
+            return false;
+        };
+        if !md.is_file() || stamp.mtime.0 > hashed_at - RACY_SECS {
+            return false;
+        }
+        self.entries.lock().unwrap().insert(
+            key,
+            Entry {
+                stamp,
+                hashes,
+                used: true,
+            },
+        );
+        true
+    }
+
+    /// Forget the file with metadata `md`.
+    pub fn invalidate(&self, md: &std::fs::Metadata) {
+        if let Some((key, _)) = identify(md) {
+            self.entries.lock().unwrap().remove(&key);
+        }
+    }
+
+    /// Drop all entries which haven't been looked up or stored since the cache was loaded, e.g.
+    /// those of deleted files after a complete scan.
+    pub fn prune(&self) {
+        self.entries.lock().unwrap().retain(|_, e| e.used);
+    }
+
+    /// Write the cache to its file, atomically replacing the previous version.
+    pub async fn save(&self) -> Result<()> {
+        let path = match self.path {
+            Some(ref p) => p,
+            None => return Ok(()),
+        };
+        let data = encode(&self.entries.lock().unwrap());
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut f = fs::File::create(&tmp)
+            .await
+            .context("HashCache: creating temporary file")?;
+        f.write_all(&data).await?;
+        f.sync_all().await?;
+        drop(f);
+        fs::rename(&tmp, path)
+            .await
+            .context("HashCache: replacing cache file")?;
+        Ok(())
+    }
+
+    /// The hashes of the file at `path` with metadata `md`, from the cache or by hashing it.
+    pub async fn hashes<P: AsRef<Path>>(
+        &self,
+        path: P,
+        md: &std::fs::Metadata,
+    ) -> Result<CachedHashes> {
+        if let Some(h) = self.get(md) {
+            return Ok(h);
+        }
+        let hashed_at = SystemTime::now();
+        let mut f = fs::File::open(path.as_ref()).await?;
+        let mut hasher = ChashHasher::new();
+        hasher.set_retained_level(1);
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = f.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        let tree = hasher.finalize();
+        let hashes = CachedHashes {
+            chash: tree.top_hash().clone(),
+            level1: tree.l.get(1).map(|l| l.h.clone()).unwrap_or_default(),
+        };
+        if identify(&f.metadata().await?) == identify(md) {
+            self.put(md, hashed_at, hashes.clone());
+        }
+        Ok(hashes)
+    }
+}
+
+fn encode(entries: &HashMap<(u64, u64), Entry>) -> Vec<u8> {
+    let mut data = Vec::with_capacity(8 + entries.len() * 72);
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    for ((dev, ino), e) in entries.iter() {
+        for n in [*dev, *ino, e.stamp.size] {
+            data.extend_from_slice(&n.to_le_bytes());
+        }
+        for (s, ns) in [e.stamp.mtime, e.stamp.ctime] {
+            data.extend_from_slice(&s.to_le_bytes());
+            data.extend_from_slice(&ns.to_le_bytes());
+        }
+        data.extend_from_slice(e.hashes.chash.as_bytes());
+        data.extend_from_slice(&(e.hashes.level1.len() as u32).to_le_bytes());
+        for h in e.hashes.level1.iter() {
+            data.extend_from_slice(h.as_bytes());
+        }
+    }
+    data
+}
+
+fn decode(data: &[u8]) -> Option<HashMap<(u64, u64), Entry>> {
+    let mut r = Reader(data);
+    if r.take(4)? != MAGIC || r.u32()? != VERSION {
+        return None;
+    }
+    let mut entries = HashMap::new();
+    while !r.0.is_empty() {
+        let key = (r.u64()?, r.u64()?);
+        let stamp = Stamp {
+            size: r.u64()?,
+            mtime: (r.u64()? as i64, r.u32()?),
+            ctime: (r.u64()? as i64, r.u32()?),
+        };
+        let chash = r.hash()?;
+        let n = r.u32()? as usize;
+        if n > r.0.len() / HASH_LEN {
+            return None;
+        }
+        let level1 = (0..n).map(|_| r.hash()).collect::<Option<Vec<_>>>()?;
+        entries.insert(
+            key,
+            Entry {
+                stamp,
+                hashes: CachedHashes { chash, level1 },
+                used: false,
+            },
+        );
+    }
+    Some(entries)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (a, b) = self.0.split_at(n);
+        self.0 = b;
+        Some(a)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn hash(&mut self) -> Option<Hash> {
+        Hash::from_bytes(self.take(HASH_LEN)?)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cache_roundtrip_and_invalidation() {
+        let dir = std::env::temp_dir().join(format!("hd_api_hashcache_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("data.bin");
+        let set_mtime =
+            |t| filetime::set_file_mtime(&file, filetime::FileTime::from_unix_time(t, 0)).unwrap();
+        std::fs::write(&file, vec![3; 600 * 4096]).unwrap();
+        set_mtime(1_600_000_000);
+        let expected = crate::hashing::chash_file(&file).await.unwrap();
+
+        let cache = HashCache::open(dir.join("cache")).await.unwrap();
+        let md = std::fs::metadata(&file).unwrap();
+        assert!(cache.get(&md).is_none());
+        let h = cache.hashes(&file, &md).await.unwrap();
+        assert_eq!(expected.top_hash(), &h.chash);
+        assert_eq!(3, h.level1.len());
+        cache.save().await.unwrap();
+        assert_eq!(
+            8 + 72 + 3 * 20,
+            std::fs::metadata(dir.join("cache")).unwrap().len()
+        );
+
+        let cache = HashCache::open(dir.join("cache")).await.unwrap();
+        assert_eq!(Some(h.clone()), cache.get(&md));
+
+        // Restoring the mtime after a write doesn't restore the ctime.
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        std::fs::write(&file, vec![4; 600 * 4096]).unwrap();
+        set_mtime(1_600_000_000);
+        let md = std::fs::metadata(&file).unwrap();
+        assert!(cache.get(&md).is_none());
+        assert!(cache.is_empty());
+
+        // Recently modified files aren't cached.
+        std::fs::write(&file, b"new").unwrap();
+        let md = std::fs::metadata(&file).unwrap();
+        let h = cache.hashes(&file, &md).await.unwrap();
+        assert_eq!(
+            crate::hashing::chash_file(&file).await.unwrap().top_hash(),
+            &h.chash
+        );
+        assert!(h.level1.is_empty());
+        assert!(cache.is_empty());
+
+        // Unused entries are pruned, and garbage is discarded.
+        set_mtime(1_600_000_000);
+        let md = std::fs::metadata(&file).unwrap();
+        cache.hashes(&file, &md).await.unwrap();
+        cache.save().await.unwrap();
+        let cache = HashCache::open(dir.join("cache")).await.unwrap();
+        assert_eq!(1, cache.len());
+        cache.prune();
+        assert!(cache.is_empty());
+        std::fs::write(dir.join("cache"), b"HDHC\x01\x00\x00\x00garbage").unwrap();
+        assert!(HashCache::open(dir.join("cache")).await.unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,15 +1,23 @@
+use crate::error::{Error, Result};
+use crate::hashcache::{self, HashCache};
 use crate::types;
 
 use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
 use std::fmt::{self, Display, Formatter};
-use std::path::Path;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time;
 
 #[cfg(target_family = "unix")]
 use std::os::unix::ffi::OsStrExt;
+#[cfg(target_family = "unix")]
+use std::os::unix::fs::MetadataExt;
 
-use anyhow::{self, Result};
 use digest;
+use futures_util::future::{BoxFuture, FutureExt};
+use log::{info, warn};
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use sha1::{Digest, Sha1};
 use tokio::fs;
@@ -17,13 +25,16 @@ use tokio::io::{AsyncRead, AsyncReadExt};
 
 // We are using SHA-1 everywhere, thus 20 bytes = 160 bits.
 const HASH_BYTES: usize = 20;
-const BLOCK_SIZE: usize = 4096;
+pub(crate) const BLOCK_SIZE: usize = 4096;
 const LEVEL_GROUP: usize = 256;
 
 /// A SHA1 hash.
-#[derive(Clone, Default)]
+#[derive(Clone, Default, PartialEq, Eq)]
 pub struct Hash([u8; HASH_BYTES]);
 
+/// The hash of a block consisting of zeros, or of a group of such blocks.
+static ZERO_HASH: Hash = Hash([0; HASH_BYTES]);
+
 impl Hash {
     fn new() -> Hash {
         Hash([0; HASH_BYTES])
@@ -38,13 +49,14 @@ impl Hash {
     pub fn parse<S: AsRef<str>>(sha1: S) -> Result<Hash> {
         let sha1 = sha1.as_ref();
         if sha1.len() != 2 * HASH_BYTES {
-            return Err(anyhow::Error::msg(
+            return Err(Error::msg(
                 "Hash::parse: SHA-1 string must have 40 characters",
             ));
         }
         let mut h = Hash::new();
         for i in 0..HASH_BYTES {
-            h.0[i] = u8::from_str_radix(&sha1[2 * i..2 * i + 2], 16)?;
+            h.0[i] = u8::from_str_radix(&sha1[2 * i..2 * i + 2], 16)
+                .map_err(|e| Error::msg(format!("Hash::parse: {}", e)))?;
         }
         Ok(h)
     }
@@ -55,7 +67,15 @@ impl Hash {
         Hash::new_from_sha1(h.finalize())
     }
 
-    fn is_zero_hash(&self) -> bool {
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub(crate) fn from_bytes(b: &[u8]) -> Option<Hash> {
+        Some(Hash(b.try_into().ok()?))
+    }
+
+    pub(crate) fn is_zero_hash(&self) -> bool {
         !self.0.iter().any(|e| *e != 0)
     }
 }
@@ -124,7 +144,9 @@ impl fmt::Debug for Hash {
 /// A Hash level (see HiDrive documentation). Contains one hash per block.
 #[derive(Debug)]
 pub struct HashLevel {
-    h: Vec<Hash>,
+    pub(crate) h: Vec<Hash>,
+    // Ranges of blocks whose hashes are known, in a partial tree. `None` if all are known.
+    known: Option<Vec<Range<usize>>>,
 }
 
 // See uint_macros module in std.
@@ -149,38 +171,22 @@ fn add_hashes(h1: Hash, h2: &[u8]) -> Hash {
 }
 
 impl HashLevel {
-    fn new(cap: usize) -> HashLevel {
-        HashLevel {
-            h: Vec::with_capacity(cap),
-        }
-    }
-
-    fn collapse(&self) -> HashLevel {
-        let mut nhl = HashLevel::new(self.h.len() / LEVEL_GROUP + 1);
-        let mut current_sum = Hash::new();
-        for i in 0..self.h.len() {
-            if i % LEVEL_GROUP == 0 && i > 0 {
-                nhl.h.push(current_sum);
-                current_sum = Hash::new();
-            }
-            if self.h[i].is_zero_hash() {
-                continue;
-            }
-            let mut h = Sha1::new();
-            h.update(self.h[i].0);
-            h.update([i as u8]);
-            let hash = h.finalize();
-            current_sum = add_hashes(current_sum, hash.as_slice());
+    /// The hash of `block`, if known. Blocks beyond the end of a complete level are zero.
+    fn get(&self, block: usize) -> Option<&Hash> {
+        match self.known {
+            Some(ref known) if !known.iter().any(|r| r.contains(&block)) => None,
+            _ => Some(self.h.get(block).unwrap_or(&ZERO_HASH)),
         }
-        nhl.h.push(current_sum);
-        nhl
     }
 }
 
 /// A HiDrive hashing tree. See "HiDrive_Synchronization-v3.3-rev28.pdf".
+///
+/// A tree is either computed from a file's content (`chash`), or partial: built from the hashes
+/// of selected blocks returned by `/file/hash`, as `Hashes::new_partial` and `add_api_hashes` do.
 #[derive(Debug)]
 pub struct Hashes {
-    l: Vec<HashLevel>,
+    pub(crate) l: Vec<HashLevel>,
 }
 
 impl Display for Hashes {
@@ -208,26 +214,156 @@ impl Hashes {
         let mut hash_levels = vec![];
         for i in 0..max_level + 1 {
             if let Some(mut hashes) = by_level.remove(&i) {
-                hashes.sort_by(|(ref k, ref _v), (ref kk, ref _vv)| k.cmp(kk));
+                hashes.sort_by_key(|(k, _)| *k);
                 hash_levels.push(HashLevel {
                     h: hashes.into_iter().map(|(_, v)| v).collect(),
+                    known: None,
                 });
             } else {
-                return Err(anyhow::Error::msg(
+                return Err(Error::msg(
                     "Missing hash level in API response: this is an API error",
                 ));
             }
         }
         Ok(Hashes { l: hash_levels })
     }
+
+    /// An empty partial tree for a file of `size` bytes. No hashes are known until they are added
+    /// using `add_api_hashes`.
+    pub fn new_partial(size: u64) -> Hashes {
+        let mut count = (size as usize).div_ceil(BLOCK_SIZE);
+        let mut l = vec![];
+        loop {
+            l.push(HashLevel {
+                h: vec![Hash::new(); count],
+                known: Some(vec![]),
+            });
+            if count <= 1 {
+                return Hashes { l };
+            }
+            count = count.div_ceil(LEVEL_GROUP);
+        }
+    }
+
+    /// Add the hashes returned by `/file/hash` (`HiDriveFiles::hash`) for the block ranges
+    /// `ranges` (inclusive, as passed to `hash`). Blocks in these ranges which are missing from
+    /// the response consist of zeros.
+    pub fn add_api_hashes(&mut self, fh: &types::FileHash, ranges: &[(usize, usize)]) {
+        while self.l.len() <= fh.level {
+            self.l.push(HashLevel {
+                h: vec![],
+                known: Some(vec![]),
+            });
+        }
+        let level = &mut self.l[fh.level];
+        let known = level.known.get_or_insert_with(Vec::new);
+        if ranges.is_empty() {
+            // The API returns at most the first level group.
+            known.push(0..LEVEL_GROUP);
+        }
+        known.extend(ranges.iter().map(|(a, b)| *a..*b + 1));
+        if let Some(end) = known.iter().map(|r| r.end).max() {
+            if level.h.len() < end {
+                level.h.resize(end, Hash::new());
+            }
+        }
+        for hb in fh.list.iter().flatten() {
+            if hb.block >= level.h.len() {
+                level.h.resize(hb.block + 1, Hash::new());
+            }
+            level.h[hb.block] = hb.hash.clone();
+        }
+    }
+
+    /// Whether all hashes are known.
+    pub fn is_complete(&self) -> bool {
+        self.l.iter().all(|l| l.known.is_none())
+    }
+
+    /// Compare two trees from the top level down, skipping equal subtrees, and return the ranges
+    /// of level-0 blocks which differ. Either tree may be partial: blocks whose hashes are not
+    /// known on both sides at any level count as differing, except for blocks beyond the end of
+    /// both trees.
+    pub fn diff(&self, other: &Hashes) -> Vec<Range<usize>> {
+        let levels = usize::max(self.l.len(), other.l.len());
+        if levels == 0 {
+            return vec![];
+        }
+        // The top level consists of a single block.
+        let mut candidates: Vec<Range<usize>> = std::iter::once(0..1).collect();
+        for level in (1..levels).rev() {
+            let differing = self.differing(other, level, &candidates);
+            candidates = self.children(other, level, &differing);
+        }
+        let mut ranges: Vec<Range<usize>> = vec![];
+        for b in self.differing(other, 0, &candidates) {
+            match ranges.last_mut() {
+                Some(r) if r.end == b => r.end = b + 1,
+                _ => ranges.push(b..b + 1),
+            }
+        }
+        ranges
+    }
+
+    fn hash_at(&self, level: usize, block: usize) -> Option<&Hash> {
+        self.l.get(level)?.get(block)
+    }
+
+    /// The blocks among `candidates` at `level` which aren't known to be equal.
+    pub(crate) fn differing(
+        &self,
+        other: &Hashes,
+        level: usize,
+        candidates: &[Range<usize>],
+    ) -> Vec<usize> {
+        candidates
+            .iter()
+            .flat_map(|r| r.clone())
+            .filter(
+                |b| match (self.hash_at(level, *b), other.hash_at(level, *b)) {
+                    (Some(x), Some(y)) => x != y,
+                    _ => true,
+                },
+            )
+            .collect()
+    }
+
+    /// The blocks at `level - 1` covered by `blocks` at `level`, up to the end of the longer tree.
+    pub(crate) fn children(
+        &self,
+        other: &Hashes,
+        level: usize,
+        blocks: &[usize],
+    ) -> Vec<Range<usize>> {
+        let len = |t: &Hashes| t.l.get(level - 1).map(|l| l.h.len()).unwrap_or(0);
+        let end = usize::max(len(self), len(other));
+        blocks
+            .iter()
+            .map(|b| b * LEVEL_GROUP..usize::min((b + 1) * LEVEL_GROUP, end))
+            .filter(|r| !r.is_empty())
+            .collect()
+    }
 }
 
-/// Calculate `nhash`, `mhash`, `chash` at once and return them.
+/// Calculate `nhash`, `mhash`, `chash` at once and return them. The `chash` is taken from the
+/// global `HashCache`, if one is installed and the file is unchanged.
 pub async fn file_hashes<S: AsRef<Path>>(path: S) -> Result<(Hash, Hash, Hash)> {
+    let md = fs::metadata(&path).await?;
     let nh = nhash(&path);
-    let mh = mhash_file(&path).await?;
-    let ch = chash_file(&path).await?;
-    Ok((nh, mh, ch.top_hash().clone()))
+    let mh = mhash_for_nhash(&nh, mtime_secs(&md)?, Some(md.len()));
+    let ch = file_chash(hashcache::global().as_deref(), path.as_ref(), &md).await?;
+    Ok((nh, mh, ch))
+}
+
+async fn file_chash(
+    cache: Option<&HashCache>,
+    path: &Path,
+    md: &std::fs::Metadata,
+) -> Result<Hash> {
+    match cache {
+        Some(c) => Ok(c.hashes(path, md).await?.chash),
+        None => Ok(chash_file(path).await?.top_hash().clone()),
+    }
 }
 
 /// Calculate nhash for file name.
@@ -238,8 +374,11 @@ pub fn nhash<S: AsRef<Path>>(filename: S) -> Hash {
 
 /// Calculate mhash for a given filename and access time (in seconds since epoch).
 pub fn mhash<S: AsRef<Path>>(filename: S, mtime: i64, size: Option<u64>) -> Hash {
+    mhash_for_nhash(&nhash(filename), mtime, size)
+}
+
+fn mhash_for_nhash(nh: &Hash, mtime: i64, size: Option<u64>) -> Hash {
     let mut h = Sha1::new();
-    let nh = nhash(filename);
     h.update(nh.0);
     if let Some(s) = size {
         h.update(s.to_le_bytes());
@@ -252,12 +391,18 @@ pub fn mhash<S: AsRef<Path>>(filename: S, mtime: i64, size: Option<u64>) -> Hash
 /// file size, and mtime.
 pub async fn mhash_file<S: AsRef<Path>>(path: S) -> Result<Hash> {
     let md = fs::metadata(&path).await?;
+    let mtime_s = mtime_secs(&md)?;
+    let fsize = md.len();
+    Ok(mhash(path, mtime_s, Some(fsize)))
+}
+
+/// The mtime of a file in seconds since epoch.
+pub(crate) fn mtime_secs(md: &std::fs::Metadata) -> Result<i64> {
     let mtime = md
         .modified()?
-        .duration_since(time::SystemTime::UNIX_EPOCH)?;
-    let mtime_s = mtime.as_secs();
-    let fsize = md.len();
-    Ok(mhash(path, mtime_s as i64, Some(fsize)))
+        .duration_since(time::SystemTime::UNIX_EPOCH)
+        .map_err(|e| Error::msg(format!("mtime before epoch: {}", e)))?;
+    Ok(mtime.as_secs() as i64)
 }
 
 /// Calculate content hash for file at path. A shortcut for opening a file and using `chash`.
@@ -266,35 +411,334 @@ pub async fn chash_file<S: AsRef<Path>>(path: S) -> Result<Hashes> {
     chash(f).await
 }
 
+/// Like `chash_file`, but for large files: the file is mapped into memory, and its groups of 256
+/// blocks are hashed in parallel on the blocking thread pool, leaving the async threads free.
+///
+/// The file must not be truncated while it is hashed; accessing the unmapped pages would
+/// terminate the process (`SIGBUS`). Therefore callers have to opt in: `file_hashes`,
+/// `DirHasher` and `transfer::download_resumable` use `chash_file`, as they can't rule out that a
+/// file is written concurrently; `transfer::DeltaUpload` documents the requirement.
+pub async fn chash_file_parallel<S: AsRef<Path>>(path: S) -> Result<Hashes> {
+    let path = path.as_ref().to_owned();
+    let map = tokio::task::spawn_blocking(move || -> Result<Option<memmap2::Mmap>> {
+        let f = std::fs::File::open(path)?;
+        if f.metadata()?.len() == 0 {
+            return Ok(None);
+        }
+        // SAFETY: see the function documentation; the mapping is only read.
+        Ok(Some(unsafe { memmap2::Mmap::map(&f)? }))
+    })
+    .await
+    .map_err(|e| Error::msg(format!("chash_file_parallel: {}", e)))??;
+    let map = match map {
+        Some(map) => std::sync::Arc::new(map),
+        None => return chash(&[][..]).await,
+    };
+    let len = map.len();
+    let blocks = len.div_ceil(BLOCK_SIZE);
+    let groups = blocks.div_ceil(LEVEL_GROUP);
+    let workers = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(groups);
+    let per_worker = groups.div_ceil(workers);
+
+    let tasks: Vec<_> = (0..groups)
+        .step_by(per_worker)
+        .map(|first| {
+            let map = map.clone();
+            let end = usize::min(first + per_worker, groups);
+            tokio::task::spawn_blocking(move || hash_groups(&map, first..end))
+        })
+        .collect();
+    let mut l0 = Vec::with_capacity(blocks);
+    let mut l1 = Vec::with_capacity(groups);
+    for t in tasks {
+        let (hashes, sums) = t
+            .await
+            .map_err(|e| Error::msg(format!("chash_file_parallel: {}", e)))?;
+        l0.extend(hashes);
+        l1.extend(sums);
+    }
+
+    let mut l = vec![HashLevel { h: l0, known: None }];
+    if blocks > 1 {
+        l.push(HashLevel { h: l1, known: None });
+    }
+    while l[l.len() - 1].h.len() > 1 {
+        let h = l[l.len() - 1]
+            .h
+            .chunks(LEVEL_GROUP)
+            .map(group_sum)
+            .collect();
+        l.push(HashLevel { h, known: None });
+    }
+    Ok(Hashes { l })
+}
+
+/// Hash the blocks of the level-0 groups `groups` of `data`, returning the block hashes and the
+/// group sums.
+fn hash_groups(data: &[u8], groups: Range<usize>) -> (Vec<Hash>, Vec<Hash>) {
+    let start = groups.start * LEVEL_GROUP * BLOCK_SIZE;
+    let end = usize::min(groups.end * LEVEL_GROUP * BLOCK_SIZE, data.len());
+    let hashes: Vec<Hash> = data[start..end]
+        .chunks(BLOCK_SIZE)
+        .map(block_hash)
+        .collect();
+    let sums = hashes.chunks(LEVEL_GROUP).map(group_sum).collect();
+    (hashes, sums)
+}
+
+/// The hash of the next level for a group of at most 256 hashes.
+fn group_sum(group: &[Hash]) -> Hash {
+    let mut sum = Hash::new();
+    for (i, h) in group.iter().enumerate() {
+        if !h.is_zero_hash() {
+            sum = add_hashes(sum, indexed_hash(h, i).as_slice());
+        }
+    }
+    sum
+}
+
+/// The summand of a hash at position `i` of its group.
+fn indexed_hash(h: &Hash, i: usize) -> digest::Output<Sha1> {
+    let mut sha = Sha1::new();
+    sha.update(h.0);
+    sha.update([i as u8]);
+    sha.finalize()
+}
+
 /// Hashes a file's content.
 pub async fn chash<R: AsyncRead + Unpin>(mut r: R) -> Result<Hashes> {
-    let mut l0 = HashLevel { h: vec![] };
+    let mut hasher = ChashHasher::new();
+    hasher.set_retained_level(0);
+    let mut buf = vec![0; 16 * BLOCK_SIZE];
     loop {
-        let mut buf = [0_u8; BLOCK_SIZE];
         let n = r.read(&mut buf).await?;
         if n == 0 {
             break;
         }
-        let mut hash_arr = Hash::new();
-        // Only hash a block if it has non-zero bytes in it.
-        if buf.iter().any(|e| *e != 0) {
-            let mut h = Sha1::new();
-            h.update(buf);
-            let hash = h.finalize();
-            hash_arr.0.copy_from_slice(hash.as_slice());
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize())
+}
+
+/// Computes the hash tree of data supplied piecewise, e.g. while it is being uploaded.
+///
+/// Each level only keeps the running sum of its current group of 256 blocks, so that memory use
+/// doesn't depend on the amount of data. The levels whose hashes are needed in the result can be
+/// retained with `set_retained_level`; by default, the result only contains the top hash.
+///
+/// ```ignore
+/// let mut hasher = ChashHasher::new();
+/// while let Some(chunk) = next_chunk().await? {
+///     hasher.update(&chunk);
+///     send(chunk).await?;
+/// }
+/// let chash = hasher.finalize().top_hash().clone();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ChashHasher {
+    // The incomplete block at the end of the data.
+    buf: Vec<u8>,
+    len: u64,
+    levels: Vec<HasherLevel>,
+    retain: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct HasherLevel {
+    // Blocks of this level so far.
+    count: u64,
+    // Sum over the current group.
+    sum: Hash,
+    last: Hash,
+    // All hashes, if the level is retained.
+    hashes: Vec<Hash>,
+}
+
+impl ChashHasher {
+    pub fn new() -> ChashHasher {
+        ChashHasher::default()
+    }
+
+    /// Keep the hashes of `level` and all levels above in the result of `finalize`. Level 0
+    /// takes 20 bytes per 4 KiB of data, each further level 256 times less.
+    pub fn set_retained_level(&mut self, level: usize) -> &mut Self {
+        self.retain = Some(level);
+        self
+    }
+
+    /// Number of bytes hashed so far, including gaps skipped by `update_at`.
+    pub fn position(&self) -> u64 {
+        self.len
+    }
+
+    /// Append `data`.
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        if !self.buf.is_empty() {
+            let n = usize::min(BLOCK_SIZE - self.buf.len(), data.len());
+            self.buf.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.buf.len() == BLOCK_SIZE {
+                let h = block_hash(&self.buf);
+                self.buf.clear();
+                self.push(0, h);
+            }
+        }
+        while data.len() >= BLOCK_SIZE {
+            self.push(0, block_hash(&data[..BLOCK_SIZE]));
+            data = &data[BLOCK_SIZE..];
         }
-        l0.h.push(hash_arr);
+        self.buf.extend_from_slice(data);
     }
 
-    let mut hashes = Hashes { l: vec![l0] };
-    loop {
-        if hashes.l[hashes.l.len() - 1].h.len() == 1 {
-            break;
+    /// Add `data` at `offset`, which must not be before the end of the data hashed so far. A gap
+    /// is hashed as zeros, without processing every zero block of it.
+    pub fn update_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        if offset < self.len {
+            return Err(Error::msg(format!(
+                "ChashHasher::update_at: offset {} is before the end of the data at {}",
+                offset, self.len
+            )));
+        }
+        self.skip(offset - self.len);
+        self.update(data);
+        Ok(())
+    }
+
+    /// Complete the tree. It has the same shape as the one computed by `chash`; levels which
+    /// haven't been retained contain no known hashes, except for the top level.
+    pub fn finalize(mut self) -> Hashes {
+        if !self.buf.is_empty() {
+            let h = block_hash(&self.buf);
+            self.push(0, h);
+        }
+        let mut top = 0;
+        loop {
+            self.level(top);
+            let st = &mut self.levels[top];
+            if st.count == 1 {
+                break;
+            }
+            // A complete last group has been passed on already.
+            if st.count % LEVEL_GROUP as u64 != 0 || st.count == 0 {
+                let sum = std::mem::take(&mut st.sum);
+                self.push(top + 1, sum);
+            }
+            top += 1;
+        }
+        self.levels.truncate(top + 1);
+        let retain = self.retain.unwrap_or(usize::MAX);
+        let l = self
+            .levels
+            .into_iter()
+            .enumerate()
+            .map(|(i, st)| match i {
+                i if i >= retain => HashLevel {
+                    h: st.hashes,
+                    known: None,
+                },
+                i if i == top => HashLevel {
+                    h: vec![st.last],
+                    known: None,
+                },
+                _ => HashLevel {
+                    h: vec![],
+                    known: Some(vec![]),
+                },
+            })
+            .collect();
+        Hashes { l }
+    }
+
+    fn level(&mut self, level: usize) -> &mut HasherLevel {
+        while self.levels.len() <= level {
+            self.levels.push(HasherLevel::default());
+        }
+        &mut self.levels[level]
+    }
+
+    fn retained(&self, level: usize) -> bool {
+        self.retain.map(|r| level >= r).unwrap_or(false)
+    }
+
+    /// Add the hash of the next block of `level`, passing on the sum of each completed group.
+    fn push(&mut self, level: usize, h: Hash) {
+        let retained = self.retained(level);
+        let st = self.level(level);
+        if !h.is_zero_hash() {
+            let summand = indexed_hash(&h, (st.count % LEVEL_GROUP as u64) as usize);
+            st.sum = add_hashes(std::mem::take(&mut st.sum), summand.as_slice());
+        }
+        if retained {
+            st.hashes.push(h.clone());
+        }
+        st.last = h;
+        st.count += 1;
+        if st.count % LEVEL_GROUP as u64 == 0 {
+            let sum = std::mem::take(&mut st.sum);
+            self.push(level + 1, sum);
+        }
+    }
+
+    /// Add `n` zero blocks to `level`. Whole groups of them are passed on as zero blocks of the
+    /// next level.
+    fn push_zeros(&mut self, level: usize, mut n: u64) {
+        let group = LEVEL_GROUP as u64;
+        while n > 0 && self.level(level).count % group != 0 {
+            self.push(level, Hash::new());
+            n -= 1;
+        }
+        let groups = n / group;
+        if groups > 0 {
+            let retained = self.retained(level);
+            let st = self.level(level);
+            if retained {
+                st.hashes
+                    .resize(st.hashes.len() + (groups * group) as usize, Hash::new());
+            }
+            st.count += groups * group;
+            st.last = Hash::new();
+            self.push_zeros(level + 1, groups);
+        }
+        for _ in 0..n % group {
+            self.push(level, Hash::new());
+        }
+    }
+
+    /// Hash `n` zero bytes.
+    fn skip(&mut self, mut n: u64) {
+        self.len += n;
+        if !self.buf.is_empty() {
+            let k = u64::min(n, (BLOCK_SIZE - self.buf.len()) as u64);
+            self.buf.resize(self.buf.len() + k as usize, 0);
+            n -= k;
+            if self.buf.len() == BLOCK_SIZE {
+                let h = block_hash(&self.buf);
+                self.buf.clear();
+                self.push(0, h);
+            }
+        }
+        // The buffer is empty unless all of `n` went into it.
+        if n > 0 {
+            self.push_zeros(0, n / BLOCK_SIZE as u64);
+            self.buf.resize((n % BLOCK_SIZE as u64) as usize, 0);
         }
-        let level = hashes.l[hashes.l.len() - 1].collapse();
-        hashes.l.push(level);
     }
-    Ok(hashes)
+}
+
+/// Hash a block of at most `BLOCK_SIZE` bytes, padded with zeros. Blocks consisting of zeros have
+/// the zero hash.
+fn block_hash(data: &[u8]) -> Hash {
+    if !data.iter().any(|b| *b != 0) {
+        return Hash::new();
+    }
+    let mut h = Sha1::new();
+    h.update(data);
+    h.update(&[0; BLOCK_SIZE][..BLOCK_SIZE - data.len()]);
+    Hash::new_from_sha1(h.finalize())
 }
 
 /// Calculate a `chash` for a directory.
@@ -317,6 +761,204 @@ pub fn mohash_dir(mhashes: &[Hash]) -> Hash {
     h
 }
 
+/// Hashes of a local file or directory, computed the way the HiDrive server computes the `Item`
+/// fields of the same names.
+#[derive(Debug, Clone)]
+pub struct LocalHashes {
+    pub name: OsString,
+    /// For directories, the total size of the files below.
+    pub size: u64,
+    pub mtime: i64,
+    pub nhash: Hash,
+    pub mhash: Hash,
+    pub chash: Hash,
+    /// Only set for directories.
+    pub mohash: Option<Hash>,
+    /// Only set for directories; sorted by name.
+    pub members: Option<Vec<LocalHashes>>,
+}
+
+impl LocalHashes {
+    pub fn is_dir(&self) -> bool {
+        self.members.is_some()
+    }
+
+    /// The member called `name`.
+    pub fn member<S: AsRef<OsStr>>(&self, name: S) -> Option<&LocalHashes> {
+        self.members
+            .as_ref()?
+            .iter()
+            .find(|m| m.name == name.as_ref())
+    }
+}
+
+/// Calculate `LocalHashes` for a directory tree, see `DirHasher`.
+pub async fn dir_hashes<S: AsRef<Path>>(path: S) -> Result<LocalHashes> {
+    DirHasher::new().hash(path).await
+}
+
+/// Hashes local directory trees recursively.
+///
+/// HiDrive has neither symbolic links nor names which aren't valid UTF-8, so by default, both are
+/// skipped, as they won't have a remote counterpart. FIFOs, sockets and device nodes are always
+/// skipped.
+#[derive(Debug, Clone)]
+pub struct DirHasher {
+    follow_symlinks: bool,
+    skip_non_utf8: bool,
+    cache: Option<Arc<HashCache>>,
+}
+
+impl Default for DirHasher {
+    fn default() -> DirHasher {
+        DirHasher {
+            follow_symlinks: false,
+            skip_non_utf8: true,
+            cache: None,
+        }
+    }
+}
+
+impl DirHasher {
+    pub fn new() -> DirHasher {
+        DirHasher::default()
+    }
+
+    /// Hash the targets of symbolic links as if they were located at the link. Links to an
+    /// ancestor directory and dangling links are skipped.
+    pub fn set_follow_symlinks(&mut self, follow: bool) -> &mut Self {
+        self.follow_symlinks = follow;
+        self
+    }
+
+    /// Whether to skip names which aren't valid UTF-8. If they are included, their `nhash` is
+    /// computed over the raw bytes of the name.
+    pub fn set_skip_non_utf8(&mut self, skip: bool) -> &mut Self {
+        self.skip_non_utf8 = skip;
+        self
+    }
+
+    /// Take unchanged files' hashes from `cache` instead of the global `HashCache`.
+    pub fn set_cache(&mut self, cache: Arc<HashCache>) -> &mut Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Hash the file or directory at `path`.
+    pub async fn hash<S: AsRef<Path>>(&self, path: S) -> Result<LocalHashes> {
+        // The name of the top directory is part of its `nhash` and `mhash`.
+        let path = fs::canonicalize(path.as_ref()).await?;
+        let name = path.file_name().unwrap_or_default().to_owned();
+        let md = fs::metadata(&path).await?;
+        if !md.is_dir() && !md.is_file() {
+            return Err(Error::msg(format!(
+                "DirHasher: {:?} is neither a file nor a directory",
+                path
+            )));
+        }
+        let cache = self.cache.clone().or_else(hashcache::global);
+        self.hash_entry(cache.as_deref(), path, name, md, vec![])
+            .await
+    }
+
+    fn hash_entry<'a>(
+        &'a self,
+        cache: Option<&'a HashCache>,
+        path: PathBuf,
+        name: OsString,
+        md: std::fs::Metadata,
+        mut ancestors: Vec<DirId>,
+    ) -> BoxFuture<'a, Result<LocalHashes>> {
+        async move {
+            let mtime = mtime_secs(&md)?;
+            let nh = Hash::for_string(name.as_bytes());
+            if md.is_file() {
+                let chash = file_chash(cache, &path, &md).await?;
+                return Ok(LocalHashes {
+                    mhash: mhash_for_nhash(&nh, mtime, Some(md.len())),
+                    name,
+                    size: md.len(),
+                    mtime,
+                    nhash: nh,
+                    chash,
+                    mohash: None,
+                    members: None,
+                });
+            }
+            ancestors.push(dir_id(&path, &md).await);
+            let mut members = vec![];
+            let mut rd = fs::read_dir(&path).await?;
+            while let Some(e) = rd.next_entry().await? {
+                let name = e.file_name();
+                if name.to_str().is_none() && self.skip_non_utf8 {
+                    warn!(target: "hd_api::hashing", "skipping {:?}: name is not valid UTF-8", e.path());
+                    continue;
+                }
+                let md = if e.file_type().await?.is_symlink() {
+                    if !self.follow_symlinks {
+                        info!(target: "hd_api::hashing", "skipping symbolic link {:?}", e.path());
+                        continue;
+                    }
+                    match fs::metadata(e.path()).await {
+                        Ok(md) if ancestors.contains(&dir_id(&e.path(), &md).await) => {
+                            warn!(target: "hd_api::hashing", "skipping symbolic link {:?}: it points to an ancestor", e.path());
+                            continue;
+                        }
+                        Ok(md) => md,
+                        Err(err) => {
+                            warn!(target: "hd_api::hashing", "skipping symbolic link {:?}: {}", e.path(), err);
+                            continue;
+                        }
+                    }
+                } else {
+                    e.metadata().await?
+                };
+                if !md.is_dir() && !md.is_file() {
+                    warn!(target: "hd_api::hashing", "skipping {:?}: not a regular file", e.path());
+                    continue;
+                }
+                members.push(
+                    self.hash_entry(cache, e.path(), name, md, ancestors.clone())
+                        .await?,
+                );
+            }
+            members.sort_by(|a, b| a.name.cmp(&b.name));
+            let mhashes: Vec<Hash> = members.iter().map(|m| m.mhash.clone()).collect();
+            let chashes: Vec<Hash> = members.iter().map(|m| m.chash.clone()).collect();
+            Ok(LocalHashes {
+                mhash: mhash_for_nhash(&nh, mtime, None),
+                name,
+                size: members.iter().map(|m| m.size).sum(),
+                mtime,
+                nhash: nh,
+                chash: chash_dir(&mhashes, &chashes),
+                mohash: Some(mohash_dir(&mhashes)),
+                members: Some(members),
+            })
+        }
+        .boxed()
+    }
+}
+
+/// Identifies a directory independent of the path leading to it, to detect symbolic links to
+/// ancestors.
+#[cfg(target_family = "unix")]
+type DirId = (u64, u64);
+#[cfg(not(target_family = "unix"))]
+type DirId = PathBuf;
+
+#[cfg(target_family = "unix")]
+async fn dir_id(_path: &Path, md: &std::fs::Metadata) -> DirId {
+    (md.dev(), md.ino())
+}
+
+#[cfg(not(target_family = "unix"))]
+async fn dir_id(path: &Path, _md: &std::fs::Metadata) -> DirId {
+    fs::canonicalize(path)
+        .await
+        .unwrap_or_else(|_| path.to_owned())
+}
+
 #[cfg(test)]
 mod tests {
     use sha1::{Digest, Sha1};
@@ -374,6 +1016,30 @@ mod tests {
         assert_eq!("fd0da83a93d57dd4e514c8641088ba1322aa6947", h.to_string());
     }
 
+    #[tokio::test]
+    async fn test_hash_tree_parallel() {
+        for (file, hash) in [
+            (
+                "testdata/test_hashes.txt",
+                "09f077820a8a41f34a639f2172f1133b1eafe4e6",
+            ),
+            (
+                "testdata/test_hashes_1M.txt",
+                "75a9f88fb219ef1dd31adf41c93e2efaac8d0245",
+            ),
+            (
+                "testdata/test_hashes_2M.txt",
+                "fd0da83a93d57dd4e514c8641088ba1322aa6947",
+            ),
+        ] {
+            let h = super::chash_file_parallel(file).await.unwrap();
+            assert_eq!(hash, h.to_string(), "{}", file);
+            let expected = super::chash_file(file).await.unwrap();
+            assert_eq!(expected.l.len(), h.l.len());
+            assert!(h.diff(&expected).is_empty());
+        }
+    }
+
     #[tokio::test]
     async fn test_top_hash_2m() {
         let f = fs::OpenOptions::new()
@@ -512,4 +1178,252 @@ mod tests {
         assert_eq!(1, hashes.l.len());
         assert_eq!(4, hashes.l[0].h.len());
     }
+
+    fn blocks(n: usize, changed: &[usize]) -> Vec<u8> {
+        let mut data: Vec<u8> = (0..n * 4096).map(|i| (i % 251) as u8 | 1).collect();
+        for b in changed {
+            data[b * 4096] ^= 0xff;
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn test_diff() {
+        let a = super::chash(&blocks(600, &[])[..]).await.unwrap();
+        let b = super::chash(&blocks(600, &[3, 4, 5, 300, 599])[..])
+            .await
+            .unwrap();
+        assert!(a.diff(&a).is_empty());
+        assert_eq!(vec![3..6, 300..301, 599..600], a.diff(&b));
+        // A longer file differs in its additional blocks.
+        let c = super::chash(&blocks(700, &[300])[..]).await.unwrap();
+        assert_eq!(vec![300..301, 600..700], a.diff(&c));
+        assert_eq!(vec![300..301, 600..700], c.diff(&a));
+    }
+
+    #[tokio::test]
+    async fn test_incremental_hasher() {
+        let data = std::fs::read("testdata/test_hashes_2M.txt").unwrap();
+        let expected = super::chash(&data[..]).await.unwrap();
+        assert_eq!(
+            "fd0da83a93d57dd4e514c8641088ba1322aa6947",
+            expected.to_string()
+        );
+
+        // Arbitrary chunk sizes.
+        let mut hasher = super::ChashHasher::new();
+        for chunk in data.chunks(1000) {
+            hasher.update(chunk);
+        }
+        assert_eq!(data.len() as u64, hasher.position());
+        let h = hasher.finalize();
+        assert_eq!(expected.top_hash(), h.top_hash());
+        assert_eq!(expected.l.len(), h.l.len());
+        // Lower levels aren't kept by default.
+        assert!(!h.is_complete());
+
+        let mut hasher = super::ChashHasher::new();
+        hasher.set_retained_level(0);
+        for chunk in data.chunks(5000) {
+            hasher.update(chunk);
+        }
+        let h = hasher.finalize();
+        assert!(h.is_complete());
+        assert!(h.diff(&expected).is_empty());
+
+        for len in [0, 1, 4096, 4097, 256 * 4096, 256 * 4096 + 1] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 253) as u8 | 1).collect();
+            let mut hasher = super::ChashHasher::new();
+            hasher.update(&data);
+            assert_eq!(
+                super::chash(&data[..]).await.unwrap().top_hash(),
+                hasher.finalize().top_hash(),
+                "length {}",
+                len
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_incremental_hasher_sparse() {
+        let mut data = vec![0_u8; 3 * 256 * 4096 + 5000];
+        data[10..20].copy_from_slice(b"0123456789");
+        let tail = data.len() - 100;
+        data[tail..].fill(7);
+        let expected = super::chash(&data[..]).await.unwrap();
+
+        let mut hasher = super::ChashHasher::new();
+        hasher.set_retained_level(1);
+        hasher.update_at(10, &data[10..20]).unwrap();
+        hasher.update_at(tail as u64, &data[tail..]).unwrap();
+        assert!(hasher.update_at(5, b"x").is_err());
+        let h = hasher.finalize();
+        assert_eq!(expected.top_hash(), h.top_hash());
+        assert_eq!(expected.l[1].h, h.l[1].h);
+    }
+
+    #[tokio::test]
+    async fn test_diff_partial() {
+        let local = super::chash(&blocks(600, &[])[..]).await.unwrap();
+        let remote = super::chash(&blocks(600, &[10, 520])[..]).await.unwrap();
+        // Emulate `/file/hash` responses, which omit zero hashes.
+        let response = |level: usize, ranges: &[(usize, usize)]| crate::types::FileHash {
+            level,
+            chash: remote.top_hash().clone(),
+            list: ranges
+                .iter()
+                .map(|(a, b)| {
+                    (*a..=*b)
+                        .filter(|i| *i < remote.l[level].h.len())
+                        .map(|i| crate::types::HashedBlock {
+                            hash: remote.l[level].h[i].clone(),
+                            level,
+                            block: i,
+                        })
+                        .collect()
+                })
+                .collect(),
+        };
+        let mut partial = super::Hashes::new_partial(600 * 4096);
+        assert!(!partial.is_complete());
+        // Nothing known: everything may differ.
+        assert_eq!(vec![0..600], local.diff(&partial));
+
+        partial.add_api_hashes(&response(1, &[(0, 2)]), &[(0, 2)]);
+        assert_eq!(vec![0..256, 512..600], local.diff(&partial));
+        partial.add_api_hashes(&response(0, &[(0, 255)]), &[(0, 255)]);
+        assert_eq!(vec![10..11, 512..600], local.diff(&partial));
+        partial.add_api_hashes(&response(0, &[(512, 599)]), &[(512, 599)]);
+        assert_eq!(vec![10..11, 520..521], partial.diff(&local));
+    }
+
+    fn compare_remote(srv: &crate::testing::MockServer, local: &super::LocalHashes, path: &str) {
+        let it = srv.item(path).unwrap();
+        assert_eq!(it.size, Some(local.size as usize), "{}", path);
+        assert_eq!(it.chash.as_ref(), Some(&local.chash), "{}", path);
+        assert_eq!(it.mohash, local.mohash, "{}", path);
+        assert_eq!(it.nhash.as_ref(), Some(&local.nhash), "{}", path);
+        assert_eq!(it.mhash.as_ref(), Some(&local.mhash), "{}", path);
+        for m in local.members.iter().flatten() {
+            compare_remote(srv, m, &format!("{}/{}", path, m.name.to_str().unwrap()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dir_hashes() {
+        use crate::sync::{DirSync, Direction};
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = std::env::temp_dir().join(format!("hd_api_dirhash_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let local = root.join("tree");
+        for (p, data, mtime) in [
+            ("a.txt", &b"aaa"[..], 1_600_000_000),
+            ("sub/b.txt", b"bbb", 1_600_000_100),
+            ("sub/deeper/c.bin", &[7; 10000], 1_600_000_200),
+        ] {
+            let p = local.join(p);
+            std::fs::create_dir_all(p.parent().unwrap()).unwrap();
+            std::fs::write(&p, data).unwrap();
+            filetime::set_file_mtime(&p, filetime::FileTime::from_unix_time(mtime, 0)).unwrap();
+        }
+        std::fs::create_dir_all(local.join("empty")).unwrap();
+        std::fs::write(local.join(std::ffi::OsStr::from_bytes(b"n\xe4me")), b"x").unwrap();
+        std::os::unix::fs::symlink(local.join("sub"), local.join("link")).unwrap();
+        std::os::unix::fs::symlink(&local, local.join("sub/loop")).unwrap();
+
+        let srv = crate::testing::MockServer::start().await;
+        let hd = srv.hidrive();
+        let mut sync = DirSync::new(&local, "/users/test/tree");
+        sync.set_direction(Direction::Upload);
+        sync.run(&hd.files()).await.unwrap();
+        // `DirSync` doesn't transfer the mtime of the root itself, so the remote one is that of
+        // the last upload into it; adopt it locally to compare the root's `mhash` too.
+        let mtime = srv.item("/users/test/tree").unwrap().mtime.unwrap();
+        filetime::set_file_mtime(
+            &local,
+            filetime::FileTime::from_unix_time(mtime.unix_timestamp(), 0),
+        )
+        .unwrap();
+
+        let hashes = super::dir_hashes(&local).await.unwrap();
+        assert_eq!("tree", hashes.name);
+        assert_eq!(
+            vec!["a.txt", "empty", "sub"],
+            hashes
+                .members
+                .iter()
+                .flatten()
+                .map(|m| m.name.to_str().unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some(super::ZERO_HASH.clone()),
+            hashes.member("empty").unwrap().mohash
+        );
+        compare_remote(&srv, &hashes, "/users/test/tree");
+
+        let mut hasher = super::DirHasher::new();
+        let cache = std::sync::Arc::new(super::HashCache::in_memory());
+        hasher
+            .set_follow_symlinks(true)
+            .set_skip_non_utf8(false)
+            .set_cache(cache.clone());
+        let all = hasher.hash(&local).await.unwrap();
+        // The recently written file isn't cached; `link/b.txt` is `sub/b.txt`.
+        assert_eq!(3, cache.len());
+        assert_eq!(all.chash, hasher.hash(&local).await.unwrap().chash);
+        let link = all.member("link").unwrap();
+        assert!(link.is_dir());
+        // The link to the root is skipped.
+        assert!(link.member("loop").is_none());
+        assert_eq!(hashes.member("sub").unwrap().chash, link.chash);
+        let odd = all.member(std::ffi::OsStr::from_bytes(b"n\xe4me")).unwrap();
+        assert_eq!(super::Hash::for_string(b"n\xe4me"), odd.nhash);
+        assert_eq!(hashes.size + 1 + link.size, all.size);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_dir_hashes_special_files() {
+        use crate::sync::DirSync;
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = std::env::temp_dir().join(format!("hd_api_special_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.txt"), b"aaa").unwrap();
+        // Opening a FIFO for reading blocks until there is a writer, a socket can't be opened.
+        let fifo = std::ffi::CString::new(root.join("fifo").as_os_str().as_bytes()).unwrap();
+        assert_eq!(0, unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) });
+        let _socket = std::os::unix::net::UnixListener::bind(root.join("socket")).unwrap();
+
+        let timeout = std::time::Duration::from_secs(5);
+        let hashes = tokio::time::timeout(timeout, super::dir_hashes(&root))
+            .await
+            .expect("hashing blocked")
+            .unwrap();
+        assert_eq!(
+            vec!["a.txt"],
+            hashes
+                .members
+                .iter()
+                .flatten()
+                .map(|m| m.name.to_str().unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert!(super::dir_hashes(root.join("fifo")).await.is_err());
+
+        let srv = crate::testing::MockServer::start().await;
+        let hd = srv.hidrive();
+        let sync = DirSync::new(&root, "/users/test/special");
+        let files = hd.files();
+        let plan = tokio::time::timeout(timeout, sync.plan(&files))
+            .await
+            .expect("planning blocked")
+            .unwrap();
+        assert_eq!(2, plan.actions.len(), "{:?}", plan.actions);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
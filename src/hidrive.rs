@@ -5,12 +5,17 @@
 //! of pairs, such as `&[(T0, T1)]` or `BTreeMap<T0, T1>`.
 //!
 
-use crate::http::Client;
+use crate::error::{Context, Error, Result};
+use crate::http::{Client, RetryPolicy};
 use crate::oauth2;
+use crate::transfer::TransferControl;
 use crate::types::*;
 
-use anyhow::{self, Context, Result};
-use futures_util::StreamExt;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use futures_util::stream::BoxStream;
+use futures_util::{SinkExt, StreamExt};
 use hyper::Method;
 use log::info;
 use reqwest;
@@ -25,6 +30,10 @@ pub const NO_PARAMS: Option<&Params> = None;
 const DEFAULT_API_BASE_URL: &str = "https://api.hidrive.strato.com/2.1";
 const DEFAULT_WS_BASE_URL: &str = "wss://api.hidrive.strato.com/2.1/subscribe";
 
+/// Number of directory members requested at once by `HiDriveFiles::list_dir`, `get_dir_paged` and
+/// `walk`.
+pub const DIR_PAGE_SIZE: usize = 1000;
+
 /// The HiDrive API hub.
 ///
 /// API documentation can be found at
@@ -32,9 +41,14 @@ const DEFAULT_WS_BASE_URL: &str = "wss://api.hidrive.strato.com/2.1/subscribe";
 ///
 /// All calls are "dynamically typed", taking a collection of parameters varying by call. Check the
 /// documentation for which parameters are required for any given call.
+///
+/// `HiDrive` is cheap to clone, and all clones share one `Authorizer`. Calls only need `&self`, so
+/// a single instance can be used by many tasks concurrently.
+#[derive(Clone)]
 pub struct HiDrive {
     client: Client,
     base_url: String,
+    ws_url: String,
 }
 
 impl HiDrive {
@@ -42,65 +56,424 @@ impl HiDrive {
         HiDrive {
             client: Client::new(c, a),
             base_url: DEFAULT_API_BASE_URL.into(),
+            ws_url: DEFAULT_WS_BASE_URL.into(),
         }
     }
 
-    pub fn user(&mut self) -> HiDriveUser<'_> {
+    /// Configure endpoints and the HTTP client before creating a `HiDrive`.
+    pub fn builder() -> HiDriveBuilder {
+        HiDriveBuilder::new()
+    }
+
+    /// Send API requests to a different base URL (default: `https://api.hidrive.strato.com/2.1`),
+    /// e.g. a `testing::MockServer`.
+    pub fn set_base_url<S: Into<String>>(&mut self, base_url: S) {
+        self.base_url = base_url.into();
+    }
+
+    /// Set the policy for repeating requests which failed transiently. By default, idempotent
+    /// requests are attempted up to four times.
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.client.set_retry_policy(retry);
+    }
+
+    pub fn user(&self) -> HiDriveUser<'_> {
         HiDriveUser { hd: self }
     }
 
-    pub fn permissions(&mut self) -> HiDrivePermission<'_> {
+    pub fn permissions(&self) -> HiDrivePermission<'_> {
         HiDrivePermission { hd: self }
     }
 
-    pub fn files(&mut self) -> HiDriveFiles<'_> {
+    pub fn files(&self) -> HiDriveFiles<'_> {
         HiDriveFiles { hd: self }
     }
 
-    pub async fn notifications(&mut self) -> Result<HiDriveNotifications<'_, SecureWSStream>> {
-        HiDriveNotifications::new(self, DEFAULT_WS_BASE_URL).await
+    pub fn sharelinks(&self) -> HiDriveShareLinks<'_> {
+        HiDriveShareLinks { hd: self }
+    }
+
+    pub async fn notifications(&self) -> Result<HiDriveNotifications<'_, SecureWSStream>> {
+        HiDriveNotifications::new(self, &self.ws_url).await
+    }
+
+    /// A notification stream which reconnects as needed, with default settings; see
+    /// `notify::NotificationStream`. Must be called within a Tokio runtime.
+    pub fn notification_stream(&self) -> crate::notify::NotificationStream {
+        crate::notify::NotificationStream::new(self, Default::default())
+    }
+
+    pub(crate) async fn invalidate_token(&self) {
+        self.client.invalidate_token().await
     }
 }
 
+/// Configuration shared by `HiDrive`, its `Authorizer`, and the `LogInFlow` obtaining credentials.
+///
+/// ```ignore
+/// let mut b = HiDrive::builder();
+/// b.base_url("https://staging.example.com/2.1")
+///     .token_url("https://staging.example.com/oauth2/token")
+///     .proxy(reqwest::Proxy::all("http://localhost:8080")?)
+///     .connect_timeout(Duration::from_secs(10));
+/// let authz = b.authorizer(credentials, client_secret)?;
+/// let hd = b.build(authz)?;
+/// ```
+///
+/// The `user_agent`, `timeout`, `connect_timeout` and `proxy` settings apply to the HTTP client
+/// created by the builder; they are ignored if a custom client is supplied with `client()`.
+#[derive(Debug, Clone)]
+pub struct HiDriveBuilder {
+    base_url: String,
+    ws_url: String,
+    token_url: String,
+    authorize_url: String,
+
+    user_agent: Option<String>,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    proxy: Option<reqwest::Proxy>,
+    client: Option<reqwest::Client>,
+    // The client built from the settings above, shared by everything the builder creates.
+    built: BuiltClient,
+
+    retry: RetryPolicy,
+}
+
+/// A lazily built HTTP client, reset whenever a setting changes.
+#[derive(Debug, Default)]
+struct BuiltClient(std::sync::Mutex<Option<reqwest::Client>>);
+
+impl Clone for BuiltClient {
+    fn clone(&self) -> BuiltClient {
+        BuiltClient(std::sync::Mutex::new(self.0.lock().unwrap().clone()))
+    }
+}
+
+impl Default for HiDriveBuilder {
+    fn default() -> HiDriveBuilder {
+        HiDriveBuilder {
+            base_url: DEFAULT_API_BASE_URL.into(),
+            ws_url: DEFAULT_WS_BASE_URL.into(),
+            token_url: oauth2::DEFAULT_TOKEN_URL.into(),
+            authorize_url: oauth2::DEFAULT_AUTHORIZATION_URL.into(),
+            user_agent: None,
+            timeout: None,
+            connect_timeout: None,
+            proxy: None,
+            client: None,
+            built: BuiltClient::default(),
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+impl HiDriveBuilder {
+    /// A builder with the production endpoints.
+    pub fn new() -> HiDriveBuilder {
+        Default::default()
+    }
+
+    /// Base URL of the HTTP API (default: `https://api.hidrive.strato.com/2.1`).
+    pub fn base_url<S: Into<String>>(&mut self, url: S) -> &mut Self {
+        self.base_url = url.into();
+        self
+    }
+
+    /// URL of the notification WebSocket (default: `wss://api.hidrive.strato.com/2.1/subscribe`).
+    pub fn ws_url<S: Into<String>>(&mut self, url: S) -> &mut Self {
+        self.ws_url = url.into();
+        self
+    }
+
+    /// OAuth2 token endpoint (default: `https://my.hidrive.com/oauth2/token`).
+    pub fn token_url<S: Into<String>>(&mut self, url: S) -> &mut Self {
+        self.token_url = url.into();
+        self
+    }
+
+    /// OAuth2 authorization page (default: `https://my.hidrive.com/oauth2/authorize`).
+    pub fn authorize_url<S: Into<String>>(&mut self, url: S) -> &mut Self {
+        self.authorize_url = url.into();
+        self
+    }
+
+    /// `User-Agent` header sent with every request.
+    pub fn user_agent<S: Into<String>>(&mut self, user_agent: S) -> &mut Self {
+        self.user_agent = Some(user_agent.into());
+        self.built = BuiltClient::default();
+        self
+    }
+
+    /// Total time allowed per request, including the transfer of the body. Keep this in mind when
+    /// transferring large files.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self.built = BuiltClient::default();
+        self
+    }
+
+    /// Time allowed for establishing a connection.
+    pub fn connect_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.connect_timeout = Some(timeout);
+        self.built = BuiltClient::default();
+        self
+    }
+
+    /// Send all requests through `proxy`.
+    pub fn proxy(&mut self, proxy: reqwest::Proxy) -> &mut Self {
+        self.proxy = Some(proxy);
+        self.built = BuiltClient::default();
+        self
+    }
+
+    /// Use a preconfigured HTTP client.
+    pub fn client(&mut self, client: reqwest::Client) -> &mut Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Policy for repeating requests which failed transiently.
+    pub fn retry_policy(&mut self, retry: RetryPolicy) -> &mut Self {
+        self.retry = retry;
+        self
+    }
+
+    /// The HTTP client used for API and token requests: either the one given to `client()`, or
+    /// one with the configured user agent, timeouts and proxy. The latter is built once, so that
+    /// the authorizer, the login flow and the hub share its connection pool.
+    pub fn http_client(&self) -> Result<reqwest::Client> {
+        if let Some(ref cl) = self.client {
+            return Ok(cl.clone());
+        }
+        let mut built = self.built.0.lock().unwrap();
+        if let Some(ref cl) = *built {
+            return Ok(cl.clone());
+        }
+        let mut b = reqwest::Client::builder();
+        if let Some(ref ua) = self.user_agent {
+            b = b.user_agent(ua);
+        }
+        if let Some(t) = self.timeout {
+            b = b.timeout(t);
+        }
+        if let Some(t) = self.connect_timeout {
+            b = b.connect_timeout(t);
+        }
+        if let Some(ref p) = self.proxy {
+            b = b.proxy(p.clone());
+        }
+        let cl = b.build().context("HiDriveBuilder: building HTTP client")?;
+        *built = Some(cl.clone());
+        Ok(cl)
+    }
+
+    /// An `Authorizer` using the configured token endpoint and HTTP client.
+    pub fn authorizer(
+        &self,
+        cred: oauth2::Credentials,
+        cs: oauth2::ClientSecret,
+    ) -> Result<oauth2::Authorizer> {
+        let mut authz = oauth2::Authorizer::new_with_client(cred, cs, self.http_client()?);
+        authz.set_token_url(&self.token_url);
+        Ok(authz)
+    }
+
+    /// An `Authorizer` with credentials loaded from `store`, using the configured token endpoint
+    /// and HTTP client.
+    pub async fn authorizer_with_store<T: oauth2::TokenStore + 'static>(
+        &self,
+        store: T,
+        cs: oauth2::ClientSecret,
+    ) -> Result<oauth2::Authorizer> {
+        let mut authz = oauth2::Authorizer::new_with_store(store, cs, self.http_client()?).await?;
+        authz.set_token_url(&self.token_url);
+        Ok(authz)
+    }
+
+    /// A `LogInFlow` using the configured authorization page, token endpoint and HTTP client.
+    pub fn login_flow(&self, cs: oauth2::ClientSecret) -> Result<oauth2::LogInFlow> {
+        let mut flow =
+            oauth2::LogInFlow::new(cs, self.authorize_url.clone(), self.token_url.clone());
+        flow.set_http_client(self.http_client()?);
+        Ok(flow)
+    }
+
+    /// Create the `HiDrive` hub. `authz` is typically obtained from `authorizer()`.
+    pub fn build(&self, authz: oauth2::Authorizer) -> Result<HiDrive> {
+        let mut client = Client::new(self.http_client()?, authz);
+        client.set_retry_policy(self.retry.clone());
+        Ok(HiDrive {
+            client,
+            base_url: self.base_url.clone(),
+            ws_url: self.ws_url.clone(),
+        })
+    }
+}
+
+/// The notification WebSocket.
+///
+/// After subscribing to directories with `subscribe`, `next` returns a `Notification::Change`
+/// for every change below them. Requests carry an `id` chosen by the client, which the server
+/// repeats in its reply; notifications arriving while waiting for a reply are queued.
 pub struct HiDriveNotifications<'a, S> {
-    hd: &'a mut HiDrive,
+    // Keeps the hub borrowed for as long as the notification stream is open.
+    #[allow(dead_code)]
+    hd: &'a HiDrive,
     stream: tokio_tungstenite::WebSocketStream<S>,
+    last_id: usize,
+    pending: VecDeque<Notification>,
+    last_message: Instant,
 }
 
 type SecureWSStream = tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>;
 impl HiDriveNotifications<'_, SecureWSStream> {
     async fn new(
-        hd: &mut HiDrive,
+        hd: &HiDrive,
         url: impl AsRef<str>,
     ) -> Result<HiDriveNotifications<'_, SecureWSStream>> {
-        let url = format!("{}?access_token={}", url.as_ref(), hd.client.access_token().await?);
+        let url = format!(
+            "{}?access_token={}",
+            url.as_ref(),
+            hd.client.access_token().await?
+        );
         info!(target: "hd_api::hidrive", "requesting WSS connection to {}", url);
         tokio_tungstenite::connect_async(url)
             .await
             .map_err(|e| e.into())
-            .map(|(stream, _resp)| HiDriveNotifications { hd, stream })
+            .map(|(stream, _resp)| HiDriveNotifications::from_stream(hd, stream))
     }
 }
 
-impl<S: AsyncRead + AsyncWrite + Unpin> HiDriveNotifications<'_, S> {
-    pub async fn next(&mut self) -> Result<Option<WebsocketNotification>> {
+impl<'a, S: AsyncRead + AsyncWrite + Unpin> HiDriveNotifications<'a, S> {
+    fn from_stream(
+        hd: &'a HiDrive,
+        stream: tokio_tungstenite::WebSocketStream<S>,
+    ) -> HiDriveNotifications<'a, S> {
+        HiDriveNotifications {
+            hd,
+            stream,
+            last_id: 0,
+            pending: VecDeque::new(),
+            last_message: Instant::now(),
+        }
+    }
+
+    /// The next message, or `None` once the connection is closed.
+    pub async fn next(&mut self) -> Result<Option<Notification>> {
+        match self.pending.pop_front() {
+            Some(n) => Ok(Some(n)),
+            None => self.receive().await,
+        }
+    }
+
+    /// Subscribe to changes of the directory with ID `pid`, and also of all directories below it
+    /// if `recursive`. Returns the subscription ID, which is included in the `ChangeEvent`s.
+    pub async fn subscribe(&mut self, pid: &str, recursive: bool) -> Result<usize> {
+        let args = WebsocketArgs {
+            pid: Some(pid.into()),
+            recursive: Some(recursive),
+            ..Default::default()
+        };
+        self.request("subscribe", args)
+            .await?
+            .ok_or_else(|| Error::msg("subscribe: reply lacks subs_id"))
+    }
+
+    /// Send a ping. The server's pong isn't returned by `next`, but counts as activity.
+    pub async fn ping(&mut self) -> Result<()> {
+        self.stream.send(Message::Ping(vec![])).await?;
+        Ok(())
+    }
+
+    /// Time since the last message of any kind was received.
+    pub fn idle(&self) -> Duration {
+        self.last_message.elapsed()
+    }
+
+    /// Cancel the subscription `subs_id`.
+    pub async fn unsubscribe(&mut self, subs_id: usize) -> Result<()> {
+        let args = WebsocketArgs {
+            subs_id: Some(subs_id),
+            ..Default::default()
+        };
+        self.request("unsubscribe", args).await?;
+        Ok(())
+    }
+
+    /// Send a request and wait for its reply, returning the `subs_id` of the reply.
+    async fn request(&mut self, name: &str, mut args: WebsocketArgs) -> Result<Option<usize>> {
+        self.last_id += 1;
+        let id = self.last_id;
+        args.id = Some(id);
+        let msg = serde_json::to_string(&WebsocketNotification {
+            name: name.into(),
+            args,
+        })?;
+        self.stream
+            .send(Message::Text(msg))
+            .await
+            .with_context(|| format!("{}: sending request", name))?;
         loop {
-            if let Some(message) = self.stream.next().await {
-                match message? {
-                    Message::Text(s) => return Ok(serde_json::from_str(s.as_str())?),
-                    Message::Close(_) => return Ok(None),
-                    _ => continue,
+            match self.receive().await? {
+                Some(Notification::Reply {
+                    id: reply_id,
+                    subs_id,
+                    code,
+                }) if reply_id == id => {
+                    return match code {
+                        Some(c) if !is_success_code(&c) => {
+                            Err(Error::msg(format!("{}: error code {}", name, c)))
+                        }
+                        _ => Ok(subs_id),
+                    }
+                }
+                Some(n) => self.pending.push_back(n),
+                None => return Err(Error::msg(format!("{}: connection closed", name))),
+            }
+        }
+    }
+
+    async fn receive(&mut self) -> Result<Option<Notification>> {
+        while let Some(message) = self.stream.next().await {
+            self.last_message = Instant::now();
+            match message? {
+                Message::Text(s) => {
+                    let n: WebsocketNotification = serde_json::from_str(s.as_str())?;
+                    return Ok(Some(n.into()));
                 }
-            } else {
-                return Ok(None);
+                Message::Close(_) => return Ok(None),
+                _ => continue,
             }
         }
+        Ok(None)
     }
 }
 
+/// Replies carry an HTTP-like status code.
+fn is_success_code(code: &str) -> bool {
+    code.eq_ignore_ascii_case("ok") || code.starts_with('2')
+}
+
+/// `fields` as fields of the members of a directory.
+fn member_fields(fields: &str) -> String {
+    fields
+        .split(',')
+        .map(str::trim)
+        .filter(|f| !f.is_empty())
+        .map(|f| format!("members.{}", f))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The `limit` parameter of GET /dir, selecting `count` members starting at `offset`.
+pub(crate) fn dir_limit(offset: usize, count: usize) -> String {
+    format!("{},{}", offset, count)
+}
+
 /// Interact with user information.
 pub struct HiDriveUser<'a> {
-    hd: &'a mut HiDrive,
+    hd: &'a HiDrive,
 }
 
 /// The /user/ API.
@@ -108,7 +481,7 @@ pub struct HiDriveUser<'a> {
 /// This will be extended in future to allow for administration. For now, it only contains
 /// bare-bones features.
 impl<'a> HiDriveUser<'a> {
-    pub async fn me(&mut self, params: Option<&Params>) -> Result<User> {
+    pub async fn me(&self, params: Option<&Params>) -> Result<User> {
         let u = format!("{}/user/me", self.hd.base_url);
         self.hd
             .client
@@ -122,18 +495,14 @@ impl<'a> HiDriveUser<'a> {
 
 /// Interact with object permissions.
 pub struct HiDrivePermission<'a> {
-    hd: &'a mut HiDrive,
+    hd: &'a HiDrive,
 }
 
 impl<'a> HiDrivePermission<'a> {
     /// GET /permission
     ///
     /// Optional parameters: `pid, account, fields`.
-    pub async fn get_permission(
-        &mut self,
-        id: Identifier,
-        p: Option<&Params>,
-    ) -> Result<Permissions> {
+    pub async fn get_permission(&self, id: Identifier, p: Option<&Params>) -> Result<Permissions> {
         let u = format!("{}/permission", self.hd.base_url);
         let mut rqp = Params::new();
         id.to_params(&mut rqp, "pid", "path");
@@ -149,11 +518,7 @@ impl<'a> HiDrivePermission<'a> {
     /// PUT /permission
     ///
     /// Optional parameters: `pid, account, invite_id, readable, writable` for P.
-    pub async fn set_permission(
-        &mut self,
-        id: Identifier,
-        p: Option<&Params>,
-    ) -> Result<Permissions> {
+    pub async fn set_permission(&self, id: Identifier, p: Option<&Params>) -> Result<Permissions> {
         let u = format!("{}/permission", self.hd.base_url);
         let mut rqp = Params::new();
         id.to_params(&mut rqp, "pid", "path");
@@ -167,6 +532,101 @@ impl<'a> HiDrivePermission<'a> {
     }
 }
 
+/// Manage share links, i.e. public URLs of a file or directory.
+///
+/// Share links are identified by their own `id`, as returned by `create` and `list`.
+pub struct HiDriveShareLinks<'a> {
+    hd: &'a HiDrive,
+}
+
+impl<'a> HiDriveShareLinks<'a> {
+    /// POST /sharelink: share a file or directory.
+    ///
+    /// Optional parameters: `type, ttl, maxcount, password, valid_until`.
+    pub async fn create(&self, id: Identifier, p: Option<&Params>) -> Result<Share> {
+        let u = format!("{}/sharelink", self.hd.base_url);
+        let mut rqp = Params::new();
+        id.to_params(&mut rqp, "pid", "path");
+        self.hd
+            .client
+            .request(Method::POST, u, &rqp, p)
+            .await?
+            .go()
+            .await
+            .context("POST /sharelink")
+    }
+
+    /// GET /sharelink: all share links, or only those of `id`.
+    ///
+    /// Optional parameters: `fields`.
+    pub async fn list(&self, id: Option<Identifier>, p: Option<&Params>) -> Result<Vec<Share>> {
+        let u = format!("{}/sharelink", self.hd.base_url);
+        let mut rqp = Params::new();
+        if let Some(id) = id {
+            id.to_params(&mut rqp, "pid", "path");
+        }
+        self.hd
+            .client
+            .request(Method::GET, u, &rqp, p)
+            .await?
+            .go()
+            .await
+            .context("GET /sharelink")
+    }
+
+    /// GET /sharelink: the share link `share_id`.
+    ///
+    /// Optional parameters: `fields`.
+    pub async fn get(&self, share_id: &str, p: Option<&Params>) -> Result<Share> {
+        let u = format!("{}/sharelink", self.hd.base_url);
+        let mut rqp = Params::new();
+        rqp.add_str("id", share_id);
+        let shares: Vec<Share> = self
+            .hd
+            .client
+            .request(Method::GET, u, &rqp, p)
+            .await?
+            .go()
+            .await
+            .context("GET /sharelink")?;
+        shares
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::msg(format!("GET /sharelink: no share link {}", share_id)))
+    }
+
+    /// PUT /sharelink: change the share link `share_id`.
+    ///
+    /// Optional parameters: `ttl, maxcount, password, valid_until`. An empty `password` removes
+    /// the password.
+    pub async fn update(&self, share_id: &str, p: Option<&Params>) -> Result<Share> {
+        let u = format!("{}/sharelink", self.hd.base_url);
+        let mut rqp = Params::new();
+        rqp.add_str("id", share_id);
+        self.hd
+            .client
+            .request(Method::PUT, u, &rqp, p)
+            .await?
+            .go()
+            .await
+            .context("PUT /sharelink")
+    }
+
+    /// DELETE /sharelink: remove the share link `share_id`.
+    pub async fn delete(&self, share_id: &str) -> Result<()> {
+        let u = format!("{}/sharelink", self.hd.base_url);
+        let mut rqp = Params::new();
+        rqp.add_str("id", share_id);
+        self.hd
+            .client
+            .request(Method::DELETE, u, &rqp, NO_PARAMS)
+            .await?
+            .go()
+            .await
+            .context("DELETE /sharelink")
+    }
+}
+
 /// Interact with files.
 ///
 /// Almost all calls identify files or directories by the parameters `pid` (object ID) and `path`
@@ -176,18 +636,34 @@ impl<'a> HiDrivePermission<'a> {
 /// * if only `path` is given, operate on this file or directory.
 /// * if both are given, `path` is taken to be relative to `pid`.
 ///
+#[derive(Clone)]
 pub struct HiDriveFiles<'a> {
-    hd: &'a mut HiDrive,
+    hd: &'a HiDrive,
 }
 
 impl<'a> HiDriveFiles<'a> {
     /// Download file.
     ///
     /// Parameters: `pid, path, snapshot, snaptime`.
+    ///
+    /// See `get_range` for partial downloads, and `transfer::download_resumable` for continuing
+    /// interrupted downloads.
     pub async fn get<D: AsyncWrite + Unpin>(
-        &mut self,
+        &self,
+        id: Identifier,
+        out: D,
+        p: Option<&Params>,
+    ) -> Result<usize> {
+        self.get_with_control(id, out, &TransferControl::default(), p)
+            .await
+    }
+
+    /// Download file, reporting progress to and allowing cancellation through `ctl`.
+    pub async fn get_with_control<D: AsyncWrite + Unpin>(
+        &self,
         id: Identifier,
         out: D,
+        ctl: &TransferControl,
         p: Option<&Params>,
     ) -> Result<usize> {
         let u = format!("{}/file", self.hd.base_url);
@@ -197,14 +673,59 @@ impl<'a> HiDriveFiles<'a> {
             .client
             .request(Method::GET, u, &rqp, p)
             .await?
-            .download_file(out)
+            .download_file(out, ctl)
             .await
             .context("GET /file")
     }
 
+    /// Download part of a file, starting at byte `offset`. If `len` is `None`, the rest of the
+    /// file is downloaded.
+    ///
+    /// Parameters: `pid, path, snapshot, snaptime`.
+    pub async fn get_range<D: AsyncWrite + Unpin>(
+        &self,
+        id: Identifier,
+        out: D,
+        offset: usize,
+        len: Option<usize>,
+        p: Option<&Params>,
+    ) -> Result<usize> {
+        self.get_range_with_control(id, out, offset, len, &TransferControl::default(), p)
+            .await
+    }
+
+    /// Like `get_range`, reporting progress to and allowing cancellation through `ctl`. Progress
+    /// is counted from `offset`.
+    pub async fn get_range_with_control<D: AsyncWrite + Unpin>(
+        &self,
+        id: Identifier,
+        out: D,
+        offset: usize,
+        len: Option<usize>,
+        ctl: &TransferControl,
+        p: Option<&Params>,
+    ) -> Result<usize> {
+        let range = match len {
+            Some(0) => return Ok(0),
+            Some(l) => format!("bytes={}-{}", offset, offset + l - 1),
+            None => format!("bytes={}-", offset),
+        };
+        let u = format!("{}/file", self.hd.base_url);
+        let mut rqp = Params::new();
+        id.to_params(&mut rqp, "pid", "path");
+        self.hd
+            .client
+            .request(Method::GET, u, &rqp, p)
+            .await?
+            .set_header(reqwest::header::RANGE, range)
+            .download_range(out, offset as u64, ctl)
+            .await
+            .context("GET /file (range)")
+    }
+
     /// Obtain a public URL valid for 6 hours.
     ///
-    pub async fn url(&mut self, id: Identifier, p: Option<&Params>) -> Result<Url> {
+    pub async fn url(&self, id: Identifier, p: Option<&Params>) -> Result<Url> {
         let u = format!("{}/file/url", self.hd.base_url);
         let mut rqp = Params::new();
         id.to_params(&mut rqp, "pid", "path");
@@ -218,16 +739,16 @@ impl<'a> HiDriveFiles<'a> {
     }
 
     /// Upload a file (max. 2 gigabytes). Specify either `dir_id`, `dir`, or both; in the latter
-    /// case, `dir` is relative to `dir_id`.
+    /// case, `dir` is relative to `dir_id`. Use `transfer::ChunkedUpload` for larger files.
     ///
     /// Parameter `name` specifies the file name to be acted on. `dir` or `dir_id` specify the
     /// directory where to create the file. Also available: `mtime, parent_mtime, on_exist`.
     ///
     /// File will not be overwritten if it exists (in that case, code 409 is returned).
     ///
-    /// TODO: provide callback for upload status.
+    /// See `upload_with_control` for progress reporting.
     pub async fn upload_no_overwrite<S: AsRef<str>, R: Into<reqwest::Body>>(
-        &mut self,
+        &self,
         dir: Identifier,
         name: S,
         src: R,
@@ -241,7 +762,7 @@ impl<'a> HiDriveFiles<'a> {
     ///
     /// Parameter `name` specifies the file name to be acted on.
     pub async fn upload<S: AsRef<str>, R: Into<reqwest::Body>>(
-        &mut self,
+        &self,
         dir: Identifier,
         name: S,
         src: R,
@@ -251,7 +772,7 @@ impl<'a> HiDriveFiles<'a> {
     }
 
     async fn upload_(
-        &mut self,
+        &self,
         id: Identifier,
         name: impl AsRef<str>,
         src: impl Into<reqwest::Body>,
@@ -274,14 +795,66 @@ impl<'a> HiDriveFiles<'a> {
             .with_context(ctx)
     }
 
-    /// Truncate a file to the specified size. If `size` is greater than the current size, a sparse
-    /// file is created.
-    pub async fn truncate(
-        &mut self,
+    /// Upload a file (max. 2 gigabytes) streamed from `src`, reporting progress to and allowing
+    /// cancellation through `ctl`. `size` is only used as total for progress reports.
+    ///
+    /// Like `upload_no_overwrite`, an existing file is not overwritten unless `on_exist` is given.
+    /// As the data is streamed, the request is not repeated on failure.
+    pub async fn upload_with_control<S: AsRef<str>, R: AsyncRead + Send + Sync + 'static>(
+        &self,
+        dir: Identifier,
+        name: S,
+        src: R,
+        size: Option<u64>,
+        ctl: &TransferControl,
+        p: Option<&Params>,
+    ) -> Result<Item> {
+        let u = format!("{}/file", self.hd.base_url);
+        let mut rqp = Params::new();
+        dir.to_params(&mut rqp, "dir_id", "dir");
+        rqp.add_str("name", name.as_ref());
+        self.hd
+            .client
+            .request(Method::POST, u, &rqp, p)
+            .await?
+            .set_attachment_stream(src, ctl.tracker(0, size))
+            .go_with_control(ctl)
+            .await
+            .context("POST /file")
+    }
+
+    /// Write `src` into an existing file, starting at byte `offset` (PATCH /file).
+    ///
+    /// This is used to upload files larger than 2 gigabytes in several parts; see
+    /// `transfer::ChunkedUpload`. Writing beyond the current end of the file extends it.
+    ///
+    /// Further parameters: `mtime, parent_mtime`.
+    pub async fn patch<R: Into<reqwest::Body>>(
+        &self,
         id: Identifier,
-        size: usize,
+        offset: usize,
+        src: R,
         p: Option<&Params>,
     ) -> Result<Item> {
+        let u = format!("{}/file", self.hd.base_url);
+        let mut rqp = Params::new();
+        id.to_params(&mut rqp, "pid", "path");
+        rqp.add_uint("offset", offset);
+        self.hd
+            .client
+            .request(Method::PATCH, u, &rqp, p)
+            .await?
+            // Writing the same data at the same offset again does no harm.
+            .set_idempotent(true)
+            .set_attachment(src)
+            .go()
+            .await
+            .context("PATCH /file")
+    }
+
+    /// Truncate a file to the specified size. If `size` is greater than the current size, a sparse
+    /// file is created.
+    pub async fn truncate(&self, id: Identifier, size: usize, p: Option<&Params>) -> Result<Item> {
         let u = format!("{}/file/truncate", self.hd.base_url);
         let mut rqp = Params::new();
         rqp.add_uint("size", size);
@@ -300,12 +873,7 @@ impl<'a> HiDriveFiles<'a> {
     /// Copy from `src` to `dst`. `dst` must be `Path` or `Relative`.
     ///
     /// Also available: `snapshot, snaptime, dst_parent_mtime, preserve_mtime`.
-    pub async fn copy(
-        &mut self,
-        from: Identifier,
-        to: Identifier,
-        p: Option<&Params>,
-    ) -> Result<Item> {
+    pub async fn copy(&self, from: Identifier, to: Identifier, p: Option<&Params>) -> Result<Item> {
         let u = format!("{}/file/copy", self.hd.base_url);
         let mut rqp = Params::new();
         from.to_params(&mut rqp, "src_id", "src");
@@ -322,12 +890,7 @@ impl<'a> HiDriveFiles<'a> {
     /// Move file.
     ///
     /// `to` must be `Relative` or `Path`.
-    pub async fn mv(
-        &mut self,
-        from: Identifier,
-        to: Identifier,
-        p: Option<&Params>,
-    ) -> Result<Item> {
+    pub async fn mv(&self, from: Identifier, to: Identifier, p: Option<&Params>) -> Result<Item> {
         let u = format!("{}/file/move", self.hd.base_url);
         let mut rqp = Params::new();
         from.to_params(&mut rqp, "src_id", "src");
@@ -346,7 +909,7 @@ impl<'a> HiDriveFiles<'a> {
     /// Takes the new name as required parameter. Useful parameters: `path, pid, on_exist =
     /// {autoname, overwrite}, parent_mtime (int)'.
     pub async fn rename(
-        &mut self,
+        &self,
         id: Identifier,
         name: impl AsRef<str>,
         p: Option<&Params>,
@@ -365,7 +928,7 @@ impl<'a> HiDriveFiles<'a> {
     }
 
     /// Delete file.
-    pub async fn delete(&mut self, id: Identifier, p: Option<&Params>) -> Result<()> {
+    pub async fn delete(&self, id: Identifier, p: Option<&Params>) -> Result<()> {
         let u = format!("{}/file", self.hd.base_url);
         let mut rqp = Params::new();
         id.to_params(&mut rqp, "pid", "path");
@@ -382,7 +945,7 @@ impl<'a> HiDriveFiles<'a> {
     ///
     /// Optional parameters are `width, height, mode, snapshot, snaptime`.
     pub async fn thumbnail<D: AsyncWrite + Unpin>(
-        &mut self,
+        &self,
         id: Identifier,
         dst: D,
         p: Option<&Params>,
@@ -394,14 +957,14 @@ impl<'a> HiDriveFiles<'a> {
             .client
             .request(Method::GET, u, &rqp, p)
             .await?
-            .download_file(dst)
+            .download_file(dst, &TransferControl::default())
             .await
             .context("/file/thumbnail")
     }
 
     /// Return metadata. Specify fields to return.
     pub async fn metadata(
-        &mut self,
+        &self,
         id: Identifier,
         fields: impl AsRef<str>,
         p: Option<&Params>,
@@ -419,8 +982,24 @@ impl<'a> HiDriveFiles<'a> {
             .context("/meta")
     }
 
+    /// Modify metadata of a file or directory (PUT /meta).
+    ///
+    /// Parameters: `mtime`.
+    pub async fn set_metadata(&self, id: Identifier, p: Option<&Params>) -> Result<Item> {
+        let u = format!("{}/meta", self.hd.base_url);
+        let mut rqp = Params::new();
+        id.to_params(&mut rqp, "pid", "path");
+        self.hd
+            .client
+            .request(Method::PUT, u, &rqp, p)
+            .await?
+            .go()
+            .await
+            .context("PUT /meta")
+    }
+
     pub async fn search(
-        &mut self,
+        &self,
         root: Identifier,
         fields: impl AsRef<str>,
         p: Option<&Params>,
@@ -447,7 +1026,7 @@ impl<'a> HiDriveFiles<'a> {
     /// Specify either `pid` or `path`, or the request will fail.
     ///
     /// Further parameters: `members, limit, snapshot, snaptime, fields, sort`.
-    pub async fn get_dir(&mut self, id: Identifier, p: Option<&Params>) -> Result<Item> {
+    pub async fn get_dir(&self, id: Identifier, p: Option<&Params>) -> Result<Item> {
         let u = format!("{}/dir", self.hd.base_url);
         let mut rqp = Params::new();
         id.to_params(&mut rqp, "pid", "path");
@@ -460,10 +1039,82 @@ impl<'a> HiDriveFiles<'a> {
             .context("GET /dir")
     }
 
+    /// List the members of a directory page by page, requesting `page_size` members at a time
+    /// (GET /dir with `limit=offset,count`), so that only one page is held in memory. `fields`
+    /// are the fields of the members, such as `name,size`.
+    ///
+    /// Members added or removed while listing may be missed or returned twice.
+    pub fn list_dir(
+        &self,
+        id: Identifier,
+        fields: impl AsRef<str>,
+        page_size: usize,
+    ) -> BoxStream<'a, Result<Item>> {
+        let files = self.clone();
+        let fields = member_fields(fields.as_ref());
+        let page_size = page_size.max(1);
+        futures_util::stream::unfold(Some(0), move |offset| {
+            let (files, id, fields) = (files.clone(), id.clone(), fields.clone());
+            async move {
+                let offset = offset?;
+                let mut p = Params::new();
+                p.add_str("fields", fields)
+                    .add_str("limit", dir_limit(offset, page_size));
+                match files.get_dir(id, Some(&p)).await {
+                    Ok(dir) => {
+                        let next = (dir.members.len() == page_size).then_some(offset + page_size);
+                        Some((Ok(dir.members), next))
+                    }
+                    Err(e) => Some((Err(e), None)),
+                }
+            }
+        })
+        .flat_map(|page| {
+            let items: Vec<Result<Item>> = match page {
+                Ok(members) => members.into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(e)],
+            };
+            futures_util::stream::iter(items)
+        })
+        .boxed()
+    }
+
+    /// Like `get_dir`, but request the members in pages of `page_size` like `list_dir` does, and
+    /// collect all of them. `fields` include those of the members, e.g. `name,members.name`.
+    ///
+    /// Members added or removed while listing may be missed or returned twice.
+    pub async fn get_dir_paged(
+        &self,
+        id: Identifier,
+        fields: impl AsRef<str>,
+        page_size: usize,
+    ) -> Result<Item> {
+        let page_size = page_size.max(1);
+        let mut p = Params::new();
+        p.add_str("fields", fields.as_ref())
+            .add_str("limit", dir_limit(0, page_size));
+        let mut dir = self.get_dir(id.clone(), Some(&p)).await?;
+        let mut page = dir.members.len();
+        while page == page_size {
+            let mut p = Params::new();
+            p.add_str("fields", fields.as_ref())
+                .add_str("limit", dir_limit(dir.members.len(), page_size));
+            let more = self.get_dir(id.clone(), Some(&p)).await?.members;
+            page = more.len();
+            dir.members.extend(more);
+        }
+        Ok(dir)
+    }
+
+    /// Traverse the tree below `root`, yielding the `fields` of every item; see `walk::Walk`.
+    pub fn walk(&self, root: Identifier, fields: impl AsRef<str>) -> crate::walk::Walk<'a> {
+        crate::walk::Walk::new(self.clone(), root, fields.as_ref())
+    }
+
     /// Return metadata for home directory.
     ///
     /// Further parameters: `members, limit, snapshot, snaptime, fields, sort`.
-    pub async fn get_home_dir(&mut self, p: Option<&Params>) -> Result<Item> {
+    pub async fn get_home_dir(&self, p: Option<&Params>) -> Result<Item> {
         let u = format!("{}/dir/home", self.hd.base_url);
         self.hd
             .client
@@ -479,7 +1130,7 @@ impl<'a> HiDriveFiles<'a> {
     /// `id` must be `Path` or `Relative`.
     ///
     /// Further parameters: `pid, on_exist, mtime, parent_mtime`.
-    pub async fn mkdir(&mut self, id: Identifier, p: Option<&Params>) -> Result<Item> {
+    pub async fn mkdir(&self, id: Identifier, p: Option<&Params>) -> Result<Item> {
         let u = format!("{}/dir", self.hd.base_url);
         let mut rqp = Params::new();
         id.to_params(&mut rqp, "pid", "path");
@@ -495,7 +1146,7 @@ impl<'a> HiDriveFiles<'a> {
     /// Remove directory.
     ///
     /// Further parameters: `path, pid, recursive, parent_mtime`.
-    pub async fn delete_dir(&mut self, id: Identifier, p: Option<&Params>) -> Result<Item> {
+    pub async fn delete_dir(&self, id: Identifier, p: Option<&Params>) -> Result<Item> {
         let u = format!("{}/dir", self.hd.base_url);
         let mut rqp = Params::new();
         id.to_params(&mut rqp, "pid", "path");
@@ -513,7 +1164,7 @@ impl<'a> HiDriveFiles<'a> {
     /// Further parameters: `on_exist, snapshot, snaptime, dst_parent_mtime,
     /// preserve_mtime`.
     pub async fn copy_dir(
-        &mut self,
+        &self,
         from: Identifier,
         to: Identifier,
         p: Option<&Params>,
@@ -536,7 +1187,7 @@ impl<'a> HiDriveFiles<'a> {
     /// Further parameters: `src, src_id, dst_id, on_exist, src_parent_mtime, dst_parent_mtime,
     /// preserve_mtime`.
     pub async fn mvdir(
-        &mut self,
+        &self,
         from: Identifier,
         to: Identifier,
         p: Option<&Params>,
@@ -559,7 +1210,7 @@ impl<'a> HiDriveFiles<'a> {
     /// Takes the new name as required parameter. Useful parameters: `path, pid, on_exist =
     /// {autoname, overwrite}, parent_mtime (int)'.
     pub async fn renamedir(
-        &mut self,
+        &self,
         dir: Identifier,
         name: impl AsRef<str>,
         p: Option<&Params>,
@@ -584,7 +1235,7 @@ impl<'a> HiDriveFiles<'a> {
     /// Get hash for given level and ranges. If ranges is empty, return hashes for entire file (but
     /// at most 256).
     pub async fn hash(
-        &mut self,
+        &self,
         id: Identifier,
         level: usize,
         ranges: &[(usize, usize)],
@@ -612,3 +1263,218 @@ impl<'a> HiDriveFiles<'a> {
             .context("/file/hash")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Only compiled, not run: a HiDrive handle and the futures of its calls must be usable from
+    // spawned tasks.
+    #[allow(dead_code)]
+    fn assert_spawnable(hd: HiDrive) {
+        fn send_sync<T: Send + Sync + Clone>(_: &T) {}
+        send_sync(&hd);
+        tokio::spawn(async move {
+            let files = hd.files();
+            let (a, b) = tokio::join!(
+                files.metadata(Identifier::Id("a".into()), "id", NO_PARAMS),
+                files.metadata(Identifier::Id("b".into()), "id", NO_PARAMS)
+            );
+            a.and(b)
+        });
+    }
+
+    #[tokio::test]
+    async fn test_builder_shares_endpoints_with_login_flow() {
+        let srv = crate::testing::MockServer::start().await;
+        let mut b = srv.builder();
+        b.user_agent("hd_api-test")
+            .connect_timeout(Duration::from_secs(5))
+            .retry_policy(RetryPolicy::none());
+
+        let mut flow = b.login_flow(srv.client_secret()).unwrap();
+        flow.supply_authorization_code("code".into());
+        let cred = flow.exchange_code().await.unwrap();
+
+        let hd = b
+            .build(b.authorizer(cred, srv.client_secret()).unwrap())
+            .unwrap();
+        let me = hd.user().me(None).await.unwrap();
+        assert_eq!(crate::testing::MockServer::HOME, me.home);
+        assert_eq!(1, srv.token_refreshes());
+        // The token and API requests went through the same client and connection.
+        assert_eq!(1, srv.connections());
+    }
+
+    #[tokio::test]
+    async fn test_list_dir_pages() {
+        let srv = crate::testing::MockServer::start().await;
+        let hd = srv.hidrive();
+        for i in 0..7 {
+            srv.put_file(&format!("/users/test/many/{}.txt", i), b"x", 1);
+        }
+        let list = |page_size| {
+            hd.files()
+                .list_dir(
+                    Identifier::Path("/users/test/many".into()),
+                    "name",
+                    page_size,
+                )
+                .map(|it| it.unwrap().name.unwrap())
+                .collect::<Vec<_>>()
+        };
+        let all: Vec<String> = (0..7).map(|i| format!("{}.txt", i)).collect();
+        for page_size in [1, 3, 7, 100] {
+            let requests = srv.requests();
+            assert_eq!(all, list(page_size).await);
+            assert_eq!(7 / page_size + 1, srv.requests() - requests);
+        }
+
+        let mut missing =
+            hd.files()
+                .list_dir(Identifier::Path("/users/test/none".into()), "name", 10);
+        assert!(missing.next().await.unwrap().unwrap_err().is_not_found());
+        assert!(missing.next().await.is_none());
+
+        for page_size in [1, 3, 7, 100] {
+            let requests = srv.requests();
+            let dir = hd
+                .files()
+                .get_dir_paged(
+                    Identifier::Path("/users/test/many".into()),
+                    "name,members.name",
+                    page_size,
+                )
+                .await
+                .unwrap();
+            assert_eq!(Some("many"), dir.name.as_deref());
+            let names: Vec<String> = dir.members.into_iter().map(|m| m.name.unwrap()).collect();
+            assert_eq!(all, names);
+            assert_eq!(7 / page_size + 1, srv.requests() - requests);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sharelinks() {
+        let srv = crate::testing::MockServer::start().await;
+        let hd = srv.hidrive();
+        srv.put_file("/users/test/s/a.txt", b"a", 1);
+        srv.mkdir_p("/users/test/s/d");
+        let links = hd.sharelinks();
+
+        let mut p = Params::new();
+        p.add_str("ttl", "3600");
+        p.add_str("maxcount", "5");
+        p.add_str("password", "secret");
+        let a = links
+            .create(Identifier::Path("/users/test/s/a.txt".into()), Some(&p))
+            .await
+            .unwrap();
+        assert_eq!(Some("file"), a.file_type.as_deref());
+        assert_eq!((Some(3600), Some(5)), (a.ttl, a.maxcount));
+        assert_eq!(Some(true), a.has_password);
+        assert!(a.uri.is_some() && a.valid_until.is_some());
+        let d = links
+            .create(Identifier::Path("/users/test/s/d".into()), NO_PARAMS)
+            .await
+            .unwrap();
+        assert_eq!(Some("dir"), d.file_type.as_deref());
+
+        let a_id = a.id.clone().unwrap();
+        assert_eq!(2, links.list(None, NO_PARAMS).await.unwrap().len());
+        let only = links
+            .list(Some(Identifier::Path("/users/test/s/d".into())), NO_PARAMS)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![d.id.clone()],
+            only.into_iter().map(|s| s.id).collect::<Vec<_>>()
+        );
+        assert_eq!(a.uri, links.get(&a_id, NO_PARAMS).await.unwrap().uri);
+
+        let mut p = Params::new();
+        p.add_str("maxcount", "1");
+        p.add_str("password", "");
+        let a = links.update(&a_id, Some(&p)).await.unwrap();
+        assert_eq!((Some(1), Some(false)), (a.maxcount, a.has_password));
+
+        links.delete(&a_id).await.unwrap();
+        assert!(links
+            .get(&a_id, NO_PARAMS)
+            .await
+            .unwrap_err()
+            .is_not_found());
+        assert!(links.delete(&a_id).await.unwrap_err().is_not_found());
+        assert_eq!(1, links.list(None, NO_PARAMS).await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_notifications_subscribe() {
+        use tokio_tungstenite::tungstenite::protocol::Role;
+        use tokio_tungstenite::WebSocketStream;
+
+        let srv = crate::testing::MockServer::start().await;
+        let hd = srv.hidrive();
+        let (a, b) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut ws = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+            let reply = |json: &str| Message::Text(json.into());
+            let req = ws.next().await.unwrap().unwrap().into_text().unwrap();
+            assert_eq!(
+                r#"{"name":"subscribe","args":{"id":1,"pid":"b123","recursive":true}}"#,
+                req
+            );
+            // A notification of an earlier subscription, before the reply.
+            ws.send(reply(
+                r#"{"name":"notify","args":{"subs_id":3,"event":"deleted","pid":"b9"}}"#,
+            ))
+            .await
+            .unwrap();
+            ws.send(reply(
+                r#"{"name":"subscribe","args":{"id":1,"subs_id":7,"code":"200"}}"#,
+            ))
+            .await
+            .unwrap();
+            ws.send(reply(
+                r#"{"name":"notify","args":{"subs_id":7,"event":"created","pid":"b124",
+                "tld_chash":"1f8ac10f23c5b5bc1167bda84b833e5c057a77d2"}}"#,
+            ))
+            .await
+            .unwrap();
+            let req = ws.next().await.unwrap().unwrap().into_text().unwrap();
+            assert_eq!(r#"{"name":"unsubscribe","args":{"id":2,"subs_id":7}}"#, req);
+            ws.send(reply(
+                r#"{"name":"unsubscribe","args":{"id":2,"code":"404"}}"#,
+            ))
+            .await
+            .unwrap();
+            ws.close(None).await.unwrap();
+        });
+
+        let ws = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+        let mut n = HiDriveNotifications::from_stream(&hd, ws);
+        assert_eq!(7, n.subscribe("b123", true).await.unwrap());
+        let change = |n: Option<Notification>| match n {
+            Some(Notification::Change(c)) => c,
+            n => panic!("unexpected {:?}", n),
+        };
+        let c = change(n.next().await.unwrap());
+        assert_eq!((Some(3), ChangeKind::Deleted), (c.subs_id, c.kind));
+        let c = change(n.next().await.unwrap());
+        assert_eq!(
+            r#"{"change":{"subs_id":7,"kind":"created","pid":"b124","tld_chash":"1f8ac10f23c5b5bc1167bda84b833e5c057a77d2"}}"#,
+            serde_json::to_string(&Notification::Change(c.clone())).unwrap()
+        );
+        assert_eq!(ChangeKind::Created, c.kind);
+        assert_eq!(Some("b124"), c.pid.as_deref());
+        assert_eq!(
+            "1f8ac10f23c5b5bc1167bda84b833e5c057a77d2",
+            c.tld_chash.unwrap().to_string()
+        );
+        assert!(c.tld_mhash.is_none());
+        let err = n.unsubscribe(7).await.unwrap_err();
+        assert_eq!("unsubscribe: error code 404", err.to_string());
+        assert!(n.next().await.unwrap().is_none());
+        server.await.unwrap();
+    }
+}
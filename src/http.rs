@@ -1,18 +1,109 @@
-use anyhow::{Context, Error, Result};
+use crate::error::{Context, Error, Result};
+use crate::oauth2::Authorizer;
+use crate::transfer::{ProgressTracker, TransferControl};
+use crate::types::*;
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
 use futures_util::StreamExt;
 use log::{error, info, warn};
-use reqwest::header::{HeaderName, HeaderValue};
-use reqwest::RequestBuilder;
+use reqwest::header::{HeaderName, HeaderValue, AUTHORIZATION};
+use reqwest::{RequestBuilder, StatusCode};
 use serde::{de::DeserializeOwned, Serialize};
-use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Mutex;
+use tokio_util::io::ReaderStream;
 
-use crate::oauth2::Authorizer;
-use crate::types::*;
+/// Controls how often and when failed requests are repeated.
+///
+/// A request is repeated if the connection failed, or the server responded with a transient
+/// error (429, 500, 502, 503, 504). Only idempotent requests (`GET, PUT, DELETE`, and partial
+/// writes) are repeated, unless `retry_non_idempotent` is set. Requests whose body can't be
+/// replayed, such as a streamed file, are never repeated.
+///
+/// Between attempts, the client waits for an exponentially growing, jittered delay, or as long as
+/// the server asks for in a `Retry-After` header. If the server asks for more than `max_backoff`,
+/// the error is returned instead.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first one. `1` disables retries.
+    pub max_attempts: usize,
+    /// Delay after the first failed attempt.
+    pub initial_backoff: Duration,
+    /// Upper bound for any delay.
+    pub max_backoff: Duration,
+    /// Also repeat `POST` and `PATCH` requests.
+    pub retry_non_idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            retry_non_idempotent: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy sending each request exactly once.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Delay before attempt `attempt + 1`, after `attempt` attempts have failed. A random value
+    /// between half and all of the exponential backoff.
+    pub(crate) fn backoff(&self, attempt: usize) -> Duration {
+        let exp = self
+            .initial_backoff
+            .saturating_mul(1 << (attempt - 1).min(16) as u32)
+            .min(self.max_backoff);
+        let half = exp / 2;
+        let jitter = RandomState::new().build_hasher().finish() % (half.as_millis() as u64 + 1);
+        half + Duration::from_millis(jitter)
+    }
+}
+
+/// Parse a `Retry-After` header, given either in seconds or as HTTP date. A date in the past
+/// means no delay.
+fn retry_after(resp: &reqwest::Response) -> Option<Duration> {
+    let v = resp
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    match v.parse() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => {
+            let at = httpdate::parse_http_date(v).ok()?;
+            Some(at.duration_since(SystemTime::now()).unwrap_or_default())
+        }
+    }
+}
+
+/// Replace the `Authorization` header of a request.
+fn with_token(rqb: RequestBuilder, token: &str) -> Result<RequestBuilder> {
+    let (cl, rq) = rqb.build_split();
+    let mut rq = rq?;
+    rq.headers_mut().insert(
+        AUTHORIZATION,
+        HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|e| Error::msg(e.to_string()))?,
+    );
+    Ok(RequestBuilder::from_parts(cl, rq))
+}
 
 /// This is a callback for gen_call_cb, deserializing the response to JSON.
-async fn read_body_to_json<RT: Default + DeserializeOwned + ?Sized>(
-    rp: reqwest::Response,
-) -> Result<RT> {
+async fn read_body_to_json<RT: Default + DeserializeOwned>(rp: reqwest::Response) -> Result<RT> {
     let status = rp.status();
     if status.is_success() {
         let body = rp.text().await?;
@@ -25,96 +116,269 @@ async fn read_body_to_json<RT: Default + DeserializeOwned + ?Sized>(
     } else {
         let body = rp.text().await?;
         warn!(target: "hd_api::http", "Received HTTP error {}: with body {}", status, body);
-        let e: ApiError = serde_json::from_reader(body.as_bytes())?;
+        let e = api_error(status, &body);
         error!(target: "hd_api::http", "ApiError is {:?}", e);
-        Err(Error::new(e))
+        Err(e)
     }
 }
 
+/// Decode an error response. Bodies which are not an API error object (e.g. from a proxy) are
+/// kept as message.
+fn api_error(status: reqwest::StatusCode, body: &str) -> Error {
+    let error = serde_json::from_str(body).unwrap_or_else(|_| ApiError {
+        msg: body.into(),
+        code: status.as_u16() as usize,
+        auth: None,
+    });
+    Error::Api { status, error }
+}
+
 /// A wrapped callback for writing an HTTP response body to a file.
 async fn write_response_to_file<D: AsyncWrite + Unpin>(
     rp: reqwest::Response,
     mut d: D,
+    mut progress: ProgressTracker,
 ) -> Result<usize> {
     if rp.status().is_success() {
+        if let (None, Some(len)) = (progress.total(), rp.content_length()) {
+            progress.set_total(len);
+        }
         let mut stream = rp.bytes_stream();
         let mut i = 0;
         while let Some(chunk) = stream.next().await {
             let chunk = chunk?;
             d.write_all(chunk.as_ref()).await?;
             i += chunk.len();
+            progress.add(chunk.len());
         }
         Ok(i)
     } else {
+        let status = rp.status();
         let body = rp.text().await?;
-        let e: ApiError = serde_json::from_reader(body.as_bytes())?;
-        Err(Error::new(e))
+        Err(api_error(status, &body))
     }
 }
 
+/// An authorizing HTTP client. Clones share the `Authorizer`, so that a token is only refreshed
+/// once, even if many requests are waiting for it.
+#[derive(Clone)]
 pub struct Client {
     cl: reqwest::Client,
-    authz: Authorizer,
+    authz: Arc<Mutex<Authorizer>>,
+    retry: RetryPolicy,
 }
 
 pub struct Request {
     rqb: RequestBuilder,
+    authz: Arc<Mutex<Authorizer>>,
+    // The access token the request was authorized with.
+    token: String,
+    retry: RetryPolicy,
+    idempotent: bool,
 }
 
 impl Client {
     pub fn new(cl: reqwest::Client, authz: Authorizer) -> Client {
-        Client { cl, authz }
+        Client {
+            cl,
+            authz: Arc::new(Mutex::new(authz)),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
     }
 
     /// Generic call to an API endpoint.
     pub async fn request<U: reqwest::IntoUrl, P: Serialize + ?Sized, RP: Serialize + ?Sized>(
-        &mut self,
+        &self,
         method: reqwest::Method,
         url: U,
         required: &RP,
         optional: Option<&P>,
     ) -> Result<Request> {
-        let rqb = self
-            .authz
-            .authorize(self.cl.request(method, url))
+        let idempotent = matches!(
+            method,
+            reqwest::Method::GET
+                | reqwest::Method::HEAD
+                | reqwest::Method::PUT
+                | reqwest::Method::DELETE
+        );
+        let token = self
+            .access_token()
             .await
             .context("HiDrive::new_request: Building authorized RequestBuilder")?;
+        let rqb = self
+            .cl
+            .request(method, url)
+            .header(AUTHORIZATION, format!("Bearer {}", token));
         let rqb = rqb.query(required);
         let rqb = if let Some(params) = optional {
             rqb.query(params)
         } else {
             rqb
         };
-        Ok(Request { rqb })
+        Ok(Request {
+            rqb,
+            authz: self.authz.clone(),
+            token,
+            retry: self.retry.clone(),
+            idempotent,
+        })
     }
 
-    pub async fn access_token(&mut self) -> Result<String> {
-        self.authz.token().await
+    pub async fn access_token(&self) -> Result<String> {
+        self.authz.lock().await.token().await
+    }
+
+    /// Make the next `access_token` call obtain a new token.
+    pub async fn invalidate_token(&self) {
+        self.authz.lock().await.invalidate_token();
     }
 }
 
 #[allow(unused)]
 impl Request {
-    pub async fn go<RT: Default + DeserializeOwned + ?Sized>(self) -> Result<RT> {
+    /// Send the request, repeating it according to the retry policy.
+    ///
+    /// If the API rejects the access token (401 with `auth` set in the error), a new token is
+    /// obtained and the request is sent once more, independently of the retry policy. A repeated
+    /// request uses the current token, which may have been refreshed during the backoff.
+    async fn send(self) -> Result<reqwest::Response> {
+        let may_retry = self.idempotent || self.retry.retry_non_idempotent;
+        let mut rqb = self.rqb;
+        let mut token = self.token;
+        let mut reauthorized = false;
+        let mut attempt = 1;
+        loop {
+            let can_repeat = may_retry && attempt < self.retry.max_attempts;
+            // try_clone() fails for streaming bodies, which can't be replayed.
+            let next = if can_repeat || !reauthorized {
+                rqb.try_clone()
+            } else {
+                None
+            };
+            let delay = match rqb.send().await {
+                Ok(resp) if resp.status() == StatusCode::UNAUTHORIZED && !reauthorized => {
+                    let status = resp.status();
+                    let body = resp.text().await?;
+                    let err = api_error(status, &body);
+                    let next = match next {
+                        Some(next) if err.api_error().and_then(|e| e.auth.as_ref()).is_some() => {
+                            next
+                        }
+                        _ => return Err(err),
+                    };
+                    warn!(target: "hd_api::http", "access token was rejected ({:#}): reauthorizing", err);
+                    token = {
+                        let mut authz = self.authz.lock().await;
+                        // Another request may have obtained a new token already.
+                        if authz.current_token() == Some(&token) {
+                            authz.invalidate_token();
+                        }
+                        authz.token().await?
+                    };
+                    rqb = with_token(next, &token)?;
+                    reauthorized = true;
+                    continue;
+                }
+                Ok(resp) if !crate::error::is_transient_status(resp.status()) => return Ok(resp),
+                Ok(resp) => {
+                    let delay = retry_after(&resp).unwrap_or_else(|| self.retry.backoff(attempt));
+                    if !can_repeat || next.is_none() || delay > self.retry.max_backoff {
+                        return Ok(resp);
+                    }
+                    warn!(target: "hd_api::http", "attempt {} received HTTP {}: retrying in {:?}", attempt, resp.status(), delay);
+                    delay
+                }
+                Err(e) => {
+                    let e = Error::from(e);
+                    if !can_repeat || next.is_none() || !e.is_retryable() {
+                        return Err(e);
+                    }
+                    let delay = self.retry.backoff(attempt);
+                    warn!(target: "hd_api::http", "attempt {} failed ({:#}): retrying in {:?}", attempt, e, delay);
+                    delay
+                }
+            };
+            tokio::time::sleep(delay).await;
+            rqb = next.unwrap();
+            // The token may have expired while waiting.
+            let current = self.authz.lock().await.token().await?;
+            if current != token {
+                rqb = with_token(rqb, &current)?;
+                token = current;
+            }
+            attempt += 1;
+        }
+    }
+
+    pub async fn go<RT: Default + DeserializeOwned>(self) -> Result<RT> {
         info!(target: "hd_api::http", "sending http request: {:?}", self.rqb);
-        let resp = self.rqb.send().await?;
-        read_body_to_json(resp).await
+        read_body_to_json(self.send().await?).await
     }
 
     pub async fn go_raw(self) -> Result<String> {
         info!(target: "hd_api::http", "sending http request: {:?}", self.rqb);
-        let resp = self.rqb.send().await?;
+        let resp = self.send().await?;
         Ok(resp.text().await?)
     }
 
-    pub async fn download_file<W: AsyncWrite + Unpin>(self, dst: W) -> Result<usize> {
+    pub async fn download_file<W: AsyncWrite + Unpin>(
+        self,
+        dst: W,
+        ctl: &TransferControl,
+    ) -> Result<usize> {
         info!(target: "hd_api::http", "sending http request for download: {:?}", self.rqb);
-        write_response_to_file(self.rqb.send().await?, dst).await
+        ctl.run(async {
+            write_response_to_file(self.send().await?, dst, ctl.tracker(0, None)).await
+        })
+        .await
+    }
+
+    /// Like `download_file`, but for requests carrying a `Range` header starting at `offset`.
+    /// Fails if the server ignores a range starting after 0 and answers with the entire file.
+    pub async fn download_range<W: AsyncWrite + Unpin>(
+        self,
+        dst: W,
+        offset: u64,
+        ctl: &TransferControl,
+    ) -> Result<usize> {
+        info!(target: "hd_api::http", "sending http request for ranged download: {:?}", self.rqb);
+        ctl.run(async {
+            let resp = self.send().await?;
+            if resp.status() == StatusCode::OK && offset > 0 {
+                return Err(Error::msg(
+                    "server ignored Range header and sent the entire file",
+                ));
+            }
+            let mut progress = ctl.tracker(offset, None);
+            if let Some(total) = content_range_total(&resp) {
+                progress.set_total(total);
+            }
+            write_response_to_file(resp, dst, progress).await
+        })
+        .await
+    }
+
+    /// Send the request and decode the response, unless `ctl` is cancelled first.
+    pub async fn go_with_control<RT: Default + DeserializeOwned>(
+        self,
+        ctl: &TransferControl,
+    ) -> Result<RT> {
+        ctl.run(self.go()).await
+    }
+
+    /// Mark the request as safe to repeat, regardless of its method.
+    pub fn set_idempotent(self, idempotent: bool) -> Self {
+        Self { idempotent, ..self }
     }
 
     pub fn set_body<B: Into<reqwest::Body>>(self, b: B) -> Self {
         Self {
             rqb: self.rqb.body(b),
+            ..self
         }
     }
 
@@ -123,6 +387,7 @@ impl Request {
             rqb: self
                 .rqb
                 .header(k, HeaderValue::from_str(v.as_ref()).unwrap()),
+            ..self
         }
     }
 
@@ -130,4 +395,202 @@ impl Request {
         self.set_header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
             .set_body(b)
     }
+
+    /// Stream the attachment from `src`, reporting each chunk read from it to `progress`. As the
+    /// body can't be replayed, the request is not repeated.
+    pub fn set_attachment_stream<R: AsyncRead + Send + Sync + 'static>(
+        self,
+        src: R,
+        mut progress: ProgressTracker,
+    ) -> Self {
+        let stream = ReaderStream::new(src).inspect(move |chunk| {
+            if let Ok(ref c) = chunk {
+                progress.add(c.len());
+            }
+        });
+        self.set_attachment(reqwest::Body::wrap_stream(stream))
+    }
+}
+
+/// Parse the total size from a `Content-Range: bytes a-b/total` header.
+fn content_range_total(resp: &reqwest::Response) -> Option<u64> {
+    resp.headers()
+        .get(reqwest::header::CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .rsplit_once('/')?
+        .1
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    /// Serve the given (status, Retry-After) responses in order, and count requests. The `msg`
+    /// of the response is the request's `Authorization` header.
+    fn serve(responses: Vec<(u16, Option<&'static str>)>) -> (String, Arc<Mutex<usize>>) {
+        let count = Arc::new(Mutex::new(0));
+        let (c, responses) = (count.clone(), Arc::new(responses));
+        let mk = hyper::service::make_service_fn(move |_| {
+            let (c, responses) = (c.clone(), responses.clone());
+            async move {
+                Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |rq| {
+                    let mut n = c.lock().unwrap();
+                    let (status, ra) = responses[(*n).min(responses.len() - 1)];
+                    *n += 1;
+                    let mut rp = hyper::Response::builder().status(status);
+                    if let Some(ra) = ra {
+                        rp = rp.header("Retry-After", ra);
+                    }
+                    let auth = rq.headers().get(AUTHORIZATION).unwrap().to_str().unwrap();
+                    let rp = rp.body(hyper::Body::from(format!(
+                        r#"{{"code": {}, "msg": "{}"}}"#,
+                        status, auth
+                    )));
+                    async move { rp }
+                }))
+            }
+        });
+        let srv = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(mk);
+        let url = format!("http://{}/", srv.local_addr());
+        tokio::spawn(srv);
+        (url, count)
+    }
+
+    fn authorizer() -> Authorizer {
+        let cred = serde_json::from_str(&format!(
+            r#"{{"refresh_token": "rt", "expires_in": 3600, "userid": "u", "access_token": "at",
+                "alias": "a", "token_type": "Bearer", "issued_at": {}}}"#,
+            time::OffsetDateTime::now_utc().unix_timestamp()
+        ))
+        .unwrap();
+        Authorizer::new(cred, Default::default())
+    }
+
+    fn request(
+        rqb: RequestBuilder,
+        authz: &Arc<tokio::sync::Mutex<Authorizer>>,
+        idempotent: bool,
+    ) -> Request {
+        Request {
+            rqb: rqb.header(AUTHORIZATION, "Bearer at"),
+            authz: authz.clone(),
+            token: "at".into(),
+            retry: RetryPolicy {
+                initial_backoff: Duration::from_millis(1),
+                ..Default::default()
+            },
+            idempotent,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_errors() {
+        let (url, count) = serve(vec![(503, None), (429, Some("0")), (200, None)]);
+        let cl = reqwest::Client::new();
+        let authz = Arc::new(tokio::sync::Mutex::new(authorizer()));
+        let r: ApiError = request(cl.get(&url), &authz, true).go().await.unwrap();
+        assert_eq!(200, r.code);
+        assert_eq!(3, *count.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_retry_gives_up() {
+        let (url, count) = serve(vec![(502, None)]);
+        let cl = reqwest::Client::new();
+        let authz = Arc::new(tokio::sync::Mutex::new(authorizer()));
+        let r = request(cl.get(&url), &authz, true).go::<ApiError>().await;
+        assert_eq!(Some(StatusCode::BAD_GATEWAY), r.unwrap_err().status());
+        assert_eq!(4, *count.lock().unwrap());
+
+        // Retry-After exceeding max_backoff is not waited for.
+        for ra in ["3600", "Fri, 01 Jan 2100 00:00:00 GMT"] {
+            let (url, count) = serve(vec![(503, Some(ra))]);
+            assert!(request(cl.get(&url), &authz, true)
+                .go::<ApiError>()
+                .await
+                .is_err());
+            assert_eq!(1, *count.lock().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_date_and_token_renewal() {
+        // A date in the past asks for no delay.
+        let (url, count) = serve(vec![
+            (503, Some("Sun, 06 Nov 1994 08:49:37 GMT")),
+            (200, None),
+        ]);
+        let cl = reqwest::Client::new();
+        let authz = Arc::new(tokio::sync::Mutex::new(authorizer()));
+        // The request was built with a token that has since been replaced.
+        let mut rq = request(cl.get(&url), &authz, true);
+        rq.token = "old".into();
+        rq.rqb = with_token(rq.rqb, "old").unwrap();
+        let r: ApiError = rq.go().await.unwrap();
+        assert_eq!((200, "Bearer at"), (r.code, r.msg.as_str()));
+        assert_eq!(2, *count.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_no_retry_for_non_idempotent_or_permanent() {
+        let (url, count) = serve(vec![(503, None), (200, None)]);
+        let cl = reqwest::Client::new();
+        let authz = Arc::new(tokio::sync::Mutex::new(authorizer()));
+        assert!(request(cl.post(&url), &authz, false)
+            .go::<ApiError>()
+            .await
+            .is_err());
+        assert_eq!(1, *count.lock().unwrap());
+
+        let (url, count) = serve(vec![(404, None), (200, None)]);
+        assert!(request(cl.get(&url), &authz, true)
+            .go::<ApiError>()
+            .await
+            .unwrap_err()
+            .is_not_found());
+        assert_eq!(1, *count.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized_without_auth_field_is_returned() {
+        let (url, count) = serve(vec![(401, None), (200, None)]);
+        let cl = reqwest::Client::new();
+        let authz = Arc::new(tokio::sync::Mutex::new(authorizer()));
+        let r = request(cl.get(&url), &authz, true).go::<ApiError>().await;
+        assert!(r.unwrap_err().is_auth());
+        assert_eq!(1, *count.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_download_range_full_reply() {
+        let (url, _) = serve(vec![(200, None)]);
+        let cl = reqwest::Client::new();
+        let authz = Arc::new(tokio::sync::Mutex::new(authorizer()));
+        let ctl = TransferControl::default();
+        let mut out = vec![];
+        let n = request(cl.get(&url), &authz, true)
+            .download_range(&mut out, 0, &ctl)
+            .await
+            .unwrap();
+        assert_eq!(out.len(), n);
+        assert!(request(cl.get(&url), &authz, true)
+            .download_range(&mut vec![], 5, &ctl)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let p = RetryPolicy::default();
+        for attempt in 1..20 {
+            let d = p.backoff(attempt);
+            assert!(d <= p.max_backoff);
+            assert!(d >= p.initial_backoff.min(p.max_backoff) / 2);
+        }
+    }
 }
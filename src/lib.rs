@@ -3,12 +3,24 @@
 mod chunking;
 mod http;
 
+pub mod error;
+pub mod hashcache;
 pub mod hashing;
 pub mod hidrive;
+pub mod notify;
 pub mod oauth2;
+pub mod sync;
+pub mod sync_state;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod transfer;
 pub mod types;
+pub mod walk;
+pub mod watch;
 
-pub use hidrive::HiDrive;
+pub use error::{Error, Result};
+pub use hidrive::{HiDrive, HiDriveBuilder};
+pub use http::RetryPolicy;
 
 pub use oauth2::{Authorizer, ClientSecret, Credentials};
 pub use types::{Identifier, Params};
@@ -0,0 +1,414 @@
+//! A notification stream for long-running programs.
+//!
+//! `NotificationStream` owns a clone of the `HiDrive` hub and keeps a `HiDriveNotifications`
+//! connection open in a background task: it pings the server regularly, reconnects with backoff
+//! (and with a fresh access token, which is part of the WebSocket URL) when the connection fails
+//! or goes silent, and then renews all subscriptions. After a reconnection, it yields
+//! `Notification::Gap`, as changes may have been missed in between.
+//!
+//! ```ignore
+//! let mut events = hd.notification_stream();
+//! let sub = events.subscribe(&home_id, true).await?;
+//! while let Some(n) = events.next().await {
+//!     match n? {
+//!         Notification::Change(c) => println!("{:?} in {:?}", c.kind, c.pid),
+//!         Notification::Gap => rescan().await?,
+//!         _ => (),
+//!     }
+//! }
+//! ```
+//!
+//! Subscription IDs returned by `NotificationStream::subscribe` stay valid across reconnections;
+//! the `subs_id` of `ChangeEvent`s is translated accordingly.
+
+use crate::error::{Error, Result};
+use crate::hidrive::{HiDrive, HiDriveNotifications};
+use crate::http::RetryPolicy;
+use crate::types::Notification;
+
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::Stream;
+use log::{info, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite;
+
+/// Settings of a `NotificationStream`.
+#[derive(Debug, Clone)]
+pub struct NotifyOptions {
+    /// Interval between pings. A connection without any message for twice as long is considered
+    /// dead.
+    pub keepalive: Duration,
+    /// Backoff between connection attempts. A connection lost within `keepalive` counts as a
+    /// failure, too. After `max_attempts` consecutive failures, the error is yielded and the stream
+    /// ends.
+    pub reconnect: RetryPolicy,
+}
+
+impl Default for NotifyOptions {
+    fn default() -> NotifyOptions {
+        NotifyOptions {
+            keepalive: Duration::from_secs(30),
+            reconnect: RetryPolicy {
+                max_attempts: usize::MAX,
+                initial_backoff: Duration::from_secs(1),
+                max_backoff: Duration::from_secs(60),
+                retry_non_idempotent: true,
+            },
+        }
+    }
+}
+
+enum Command {
+    Subscribe {
+        pid: String,
+        recursive: bool,
+        reply: oneshot::Sender<Result<usize>>,
+    },
+    Unsubscribe(usize),
+}
+
+/// Self-reconnecting stream of notifications. Dropping it closes the connection.
+pub struct NotificationStream {
+    events: mpsc::UnboundedReceiver<Result<Notification>>,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl NotificationStream {
+    /// Start connecting in a background task. Must be called within a Tokio runtime.
+    pub fn new(hd: &HiDrive, opts: NotifyOptions) -> NotificationStream {
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let task = Task {
+            hd: hd.clone(),
+            opts,
+            events: events_tx,
+            commands: commands_rx,
+            subs: BTreeMap::new(),
+            last_sub: 0,
+        };
+        tokio::spawn(task.run());
+        NotificationStream { events, commands }
+    }
+
+    /// Subscribe to changes of the directory with ID `pid`, see
+    /// `HiDriveNotifications::subscribe`. While disconnected, the subscription is only recorded,
+    /// and made once connected.
+    pub async fn subscribe(&self, pid: &str, recursive: bool) -> Result<usize> {
+        let (reply, rx) = oneshot::channel();
+        self.commands
+            .send(Command::Subscribe {
+                pid: pid.into(),
+                recursive,
+                reply,
+            })
+            .map_err(|_| Error::msg("NotificationStream: stream has ended"))?;
+        rx.await
+            .map_err(|_| Error::msg("NotificationStream: stream has ended"))?
+    }
+
+    /// Cancel the subscription `id`, as returned by `subscribe`.
+    pub fn unsubscribe(&self, id: usize) {
+        let _ = self.commands.send(Command::Unsubscribe(id));
+    }
+}
+
+impl Stream for NotificationStream {
+    type Item = Result<Notification>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+struct Subscription {
+    pid: String,
+    recursive: bool,
+    // ID assigned by the server on the current connection.
+    subs_id: Option<usize>,
+}
+
+struct Task {
+    hd: HiDrive,
+    opts: NotifyOptions,
+    events: mpsc::UnboundedSender<Result<Notification>>,
+    commands: mpsc::UnboundedReceiver<Command>,
+    subs: BTreeMap<usize, Subscription>,
+    last_sub: usize,
+}
+
+/// Why a connection ended.
+enum End {
+    /// The `NotificationStream` was dropped.
+    Dropped,
+    Lost(Error),
+}
+
+impl Task {
+    async fn run(mut self) {
+        let hd = self.hd.clone();
+        let mut failures = 0;
+        let mut connected_before = false;
+        loop {
+            let err = match hd.notifications().await {
+                Ok(mut n) => match self.resubscribe(&mut n).await {
+                    Ok(()) => {
+                        if connected_before && self.events.send(Ok(Notification::Gap)).is_err() {
+                            return;
+                        }
+                        connected_before = true;
+                        let up = Instant::now();
+                        match self.serve(&mut n).await {
+                            End::Dropped => return,
+                            End::Lost(e) => {
+                                // A connection which is dropped right away counts as a failed
+                                // attempt; one which lasted a while starts a new series.
+                                if up.elapsed() >= self.opts.keepalive {
+                                    failures = 0;
+                                }
+                                e
+                            }
+                        }
+                    }
+                    Err(e) => e,
+                },
+                Err(e) => e,
+            };
+            failures += 1;
+            if is_unauthorized(&err) {
+                hd.invalidate_token().await;
+            }
+            if failures >= self.opts.reconnect.max_attempts {
+                let _ = self.events.send(Err(err));
+                return;
+            }
+            let delay = self.opts.reconnect.backoff(failures);
+            warn!(target: "hd_api::notify", "{:#}, reconnecting in {:?}", err, delay);
+            if !self.wait(delay).await {
+                return;
+            }
+        }
+    }
+
+    /// Wait for `delay` while disconnected, handling commands. Returns false if the stream was
+    /// dropped.
+    async fn wait(&mut self, delay: Duration) -> bool {
+        let sleep = tokio::time::sleep(delay);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                _ = &mut sleep => return true,
+                c = self.commands.recv() => match c {
+                    Some(Command::Subscribe { pid, recursive, reply }) => {
+                        let _ = reply.send(Ok(self.add(pid, recursive, None)));
+                    }
+                    Some(Command::Unsubscribe(id)) => {
+                        self.subs.remove(&id);
+                    }
+                    None => return false,
+                },
+            }
+        }
+    }
+
+    fn add(&mut self, pid: String, recursive: bool, subs_id: Option<usize>) -> usize {
+        self.last_sub += 1;
+        self.subs.insert(
+            self.last_sub,
+            Subscription {
+                pid,
+                recursive,
+                subs_id,
+            },
+        );
+        self.last_sub
+    }
+
+    async fn resubscribe<S: AsyncRead + AsyncWrite + Unpin>(
+        &mut self,
+        n: &mut HiDriveNotifications<'_, S>,
+    ) -> Result<()> {
+        for s in self.subs.values_mut() {
+            s.subs_id = Some(n.subscribe(&s.pid, s.recursive).await?);
+        }
+        info!(target: "hd_api::notify", "connected, {} subscriptions", self.subs.len());
+        Ok(())
+    }
+
+    async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+        &mut self,
+        n: &mut HiDriveNotifications<'_, S>,
+    ) -> End {
+        let mut ticker = tokio::time::interval(self.opts.keepalive);
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                m = n.next() => {
+                    let m = match m {
+                        Ok(Some(m)) => m,
+                        Ok(None) => return End::Lost(Error::msg("connection closed")),
+                        Err(e) => return End::Lost(e),
+                    };
+                    if self.events.send(Ok(self.translate(m))).is_err() {
+                        return End::Dropped;
+                    }
+                }
+                _ = ticker.tick() => {
+                    if n.idle() > 2 * self.opts.keepalive {
+                        return End::Lost(Error::msg("no response to pings"));
+                    }
+                    if let Err(e) = n.ping().await {
+                        return End::Lost(e);
+                    }
+                }
+                c = self.commands.recv() => match c {
+                    Some(Command::Subscribe { pid, recursive, reply }) => {
+                        let r = n.subscribe(&pid, recursive).await;
+                        let _ = reply.send(r.map(|s| self.add(pid, recursive, Some(s))));
+                    }
+                    Some(Command::Unsubscribe(id)) => {
+                        if let Some(s) = self.subs.remove(&id).and_then(|s| s.subs_id) {
+                            if let Err(e) = n.unsubscribe(s).await {
+                                warn!(target: "hd_api::notify", "unsubscribing {}: {:#}", id, e);
+                            }
+                        }
+                    }
+                    None => return End::Dropped,
+                },
+            }
+        }
+    }
+
+    /// Replace the server's subscription ID of a change by ours.
+    fn translate(&self, m: Notification) -> Notification {
+        match m {
+            Notification::Change(mut c) => {
+                c.subs_id = self
+                    .subs
+                    .iter()
+                    .find(|(_, s)| s.subs_id.is_some() && s.subs_id == c.subs_id)
+                    .map(|(id, _)| *id);
+                Notification::Change(c)
+            }
+            m => m,
+        }
+    }
+}
+
+/// The WebSocket handshake was rejected with 401, e.g. because the token has expired.
+fn is_unauthorized(e: &Error) -> bool {
+    match e.root() {
+        Error::WebSocket(w) => matches!(
+            **w,
+            tungstenite::Error::Http(ref r) if r.status() == tungstenite::http::StatusCode::UNAUTHORIZED
+        ),
+        e => e.is_auth(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ChangeKind;
+
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tungstenite::handshake::server::{Request, Response};
+    use tungstenite::Message;
+
+    #[tokio::test]
+    async fn test_reconnect_and_resubscribe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            for subs_id in [5, 9] {
+                let (tcp, _) = listener.accept().await.unwrap();
+                #[allow(clippy::result_large_err)]
+                let check = |req: &Request, resp: Response| {
+                    assert!(req.uri().query().unwrap().starts_with("access_token="));
+                    Ok(resp)
+                };
+                let mut ws = tokio_tungstenite::accept_hdr_async(tcp, check)
+                    .await
+                    .unwrap();
+                let req = ws.next().await.unwrap().unwrap().into_text().unwrap();
+                assert!(req.contains(r#""pid":"b1","recursive":true"#), "{}", req);
+                let reply = format!(
+                    r#"{{"name":"subscribe","args":{{"id":1,"subs_id":{}}}}}"#,
+                    subs_id
+                );
+                ws.send(Message::Text(reply)).await.unwrap();
+                let change = format!(
+                    r#"{{"name":"notify","args":{{"subs_id":{},"event":"modified","pid":"b2"}}}}"#,
+                    subs_id
+                );
+                ws.send(Message::Text(change)).await.unwrap();
+                if subs_id == 9 {
+                    // Wait for the client to go away.
+                    while let Some(Ok(_)) = ws.next().await {}
+                }
+                // Dropping the first connection without a close handshake.
+            }
+        });
+
+        let srv = crate::testing::MockServer::start().await;
+        let mut b = srv.builder();
+        b.ws_url(format!("ws://127.0.0.1:{}/", port));
+        let hd = b.build(srv.authorizer()).unwrap();
+        let mut opts = NotifyOptions::default();
+        opts.reconnect.initial_backoff = Duration::from_millis(10);
+        let mut stream = NotificationStream::new(&hd, opts);
+        let id = stream.subscribe("b1", true).await.unwrap();
+
+        let change = |n: Option<Result<Notification>>| match n {
+            Some(Ok(Notification::Change(c))) => c,
+            n => panic!("unexpected {:?}", n),
+        };
+        let c = change(stream.next().await);
+        assert_eq!((Some(id), ChangeKind::Modified), (c.subs_id, c.kind));
+        assert!(matches!(stream.next().await, Some(Ok(Notification::Gap))));
+        let c = change(stream.next().await);
+        assert_eq!(Some(id), c.subs_id);
+        assert_eq!(Some("b2"), c.pid.as_deref());
+        drop(stream);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dropped_connections_count_as_failures() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let mut accepted = 0;
+            while let Ok(Ok((tcp, _))) =
+                tokio::time::timeout(Duration::from_millis(500), listener.accept()).await
+            {
+                accepted += 1;
+                // Accept the handshake, then go away.
+                let ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+                drop(ws);
+            }
+            accepted
+        });
+
+        let srv = crate::testing::MockServer::start().await;
+        let mut b = srv.builder();
+        b.ws_url(format!("ws://127.0.0.1:{}/", port));
+        let hd = b.build(srv.authorizer()).unwrap();
+        let mut opts = NotifyOptions::default();
+        opts.reconnect.initial_backoff = Duration::from_millis(10);
+        opts.reconnect.max_attempts = 3;
+        let mut stream = NotificationStream::new(&hd, opts);
+
+        assert!(matches!(stream.next().await, Some(Ok(Notification::Gap))));
+        assert!(matches!(stream.next().await, Some(Ok(Notification::Gap))));
+        assert!(matches!(stream.next().await, Some(Err(_))));
+        assert!(stream.next().await.is_none());
+        assert_eq!(3, server.await.unwrap());
+    }
+}
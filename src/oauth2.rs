@@ -4,17 +4,19 @@
 // Implement revocation
 
 use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
 use std::pin::pin;
-use std::time::Duration;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::{Context, Error, Result};
 
-use anyhow::{self, Context, Result};
 use log::{self, error, info};
 
 use futures_util::future::{select, FutureExt};
 use hyper::{server, service};
 use serde::{Deserialize, Serialize};
 use serde_json::{from_str, to_string_pretty};
-use time::ext::NumericalDuration;
 use tokio::fs;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::mpsc;
@@ -29,7 +31,7 @@ pub struct ClientSecret {
 impl ClientSecret {
     /// Returns a client secret. The file must contain a JSON object
     /// with at least the fields `client_id` and `client_secret` of type string.
-    pub async fn load(p: impl AsRef<std::path::Path>) -> anyhow::Result<ClientSecret> {
+    pub async fn load(p: impl AsRef<std::path::Path>) -> Result<ClientSecret> {
         let mut s = String::new();
         fs::OpenOptions::new()
             .read(true)
@@ -53,6 +55,9 @@ impl ClientSecret {
 ///   "scope": "ro,user"
 /// }
 /// ```
+///
+/// When obtained by this crate, the time of issuance is recorded in `issued_at` (seconds since
+/// epoch), so that a saved access token can be reused until it expires.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Credentials {
     refresh_token: String,
@@ -62,26 +67,39 @@ pub struct Credentials {
     alias: String,
     token_type: String,
     scope: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    issued_at: Option<i64>,
 }
 
 impl Credentials {
-    /// Save credentials to file.
-    pub async fn save(&self, f: impl AsRef<std::path::Path>) -> anyhow::Result<()> {
+    /// Save credentials to file. The file is replaced atomically by writing a temporary file
+    /// first and renaming it, so that a crash never leaves truncated credentials behind.
+    pub async fn save(&self, f: impl AsRef<std::path::Path>) -> Result<()> {
         let s = to_string_pretty(self)?;
-        info!(target: "hd_api::oauth2", "Saving credentials to {:?}", f.as_ref());
-        fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(f)
-            .await?
-            .write_all(s.as_bytes())
+        let f = f.as_ref();
+        info!(target: "hd_api::oauth2", "Saving credentials to {:?}", f);
+        let mut tmp = f.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut opts = fs::OpenOptions::new();
+        opts.write(true).truncate(true).create(true);
+        #[cfg(target_family = "unix")]
+        opts.mode(0o600);
+        let mut file = opts
+            .open(&tmp)
+            .await
+            .context("Credentials::save: error creating temporary file")?;
+        file.write_all(s.as_bytes())
+            .await
+            .context("Credentials::save: error writing to file")?;
+        file.sync_all().await?;
+        fs::rename(&tmp, f)
             .await
-            .context("Credentials::save: error writing to file")
+            .context("Credentials::save: error renaming temporary file")
     }
 
     /// Load credentials from file.
-    pub async fn load(f: impl AsRef<std::path::Path>) -> anyhow::Result<Credentials> {
+    pub async fn load(f: impl AsRef<std::path::Path>) -> Result<Credentials> {
         let mut s = String::new();
         info!(target: "hd_api::oauth2", "Loading credentials from {:?}", f.as_ref());
         fs::OpenOptions::new()
@@ -94,6 +112,79 @@ impl Credentials {
     }
 }
 
+/// A place where credentials are kept between runs of an application.
+///
+/// An `Authorizer` with a token store writes the new credentials back after every refresh. This
+/// keeps a rotated refresh token from going stale, and allows the next process to reuse the
+/// access token until it expires. Implement this trait to keep credentials e.g. in an OS keyring.
+#[async_trait::async_trait]
+pub trait TokenStore: Send + Sync {
+    /// Return the stored credentials, or `None` if there are none yet.
+    async fn load(&self) -> Result<Option<Credentials>>;
+    /// Replace the stored credentials.
+    async fn store(&self, cred: &Credentials) -> Result<()>;
+}
+
+/// Keeps credentials in a JSON file, as written by `Credentials::save`.
+#[derive(Debug, Clone)]
+pub struct FileTokenStore {
+    path: PathBuf,
+}
+
+impl FileTokenStore {
+    pub fn new(path: impl AsRef<Path>) -> FileTokenStore {
+        FileTokenStore {
+            path: path.as_ref().into(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenStore for FileTokenStore {
+    async fn load(&self) -> Result<Option<Credentials>> {
+        match fs::metadata(&self.path).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            _ => Credentials::load(&self.path).await.map(Some),
+        }
+    }
+
+    async fn store(&self, cred: &Credentials) -> Result<()> {
+        cred.save(&self.path).await
+    }
+}
+
+/// Keeps credentials in memory only; useful for tests, or to hand credentials to another
+/// component of the application.
+#[derive(Debug, Default)]
+pub struct MemoryTokenStore {
+    cred: Mutex<Option<Credentials>>,
+}
+
+impl MemoryTokenStore {
+    pub fn new(cred: Option<Credentials>) -> MemoryTokenStore {
+        MemoryTokenStore {
+            cred: Mutex::new(cred),
+        }
+    }
+
+    /// Return the currently stored credentials.
+    pub fn get(&self) -> Option<Credentials> {
+        self.cred.lock().unwrap().clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenStore for MemoryTokenStore {
+    async fn load(&self) -> Result<Option<Credentials>> {
+        Ok(self.get())
+    }
+
+    async fn store(&self, cred: &Credentials) -> Result<()> {
+        *self.cred.lock().unwrap() = Some(cred.clone());
+        Ok(())
+    }
+}
+
 /// Authorizer is responsible for issuing Bearer tokens to HTTP requests, refreshing the access
 /// token when necessary.
 pub struct Authorizer {
@@ -103,19 +194,15 @@ pub struct Authorizer {
     http_cl: reqwest::Client,
 
     token_url: String,
-    current_token: Option<(String, time::Instant)>,
+    current_token: Option<(String, Instant)>,
+
+    store: Option<Box<dyn TokenStore>>,
 }
 
 impl Authorizer {
     /// Create a new Authorizer instance.
     pub fn new(cred: Credentials, cs: ClientSecret) -> Authorizer {
-        Authorizer {
-            cred,
-            cs,
-            http_cl: reqwest::Client::new(),
-            token_url: DEFAULT_TOKEN_URL.into(),
-            current_token: None,
-        }
+        Self::new_with_client(cred, cs, reqwest::Client::new())
     }
 
     pub fn new_with_client(
@@ -123,27 +210,54 @@ impl Authorizer {
         cs: ClientSecret,
         http_cl: reqwest::Client,
     ) -> Authorizer {
+        let current_token = unexpired_token(&cred);
         Authorizer {
             cred,
             cs,
             http_cl,
             token_url: DEFAULT_TOKEN_URL.into(),
-            current_token: None,
+            current_token,
+            store: None,
         }
     }
 
+    /// Create an Authorizer with credentials loaded from `store`. Refreshed credentials are
+    /// written back to it.
+    pub async fn new_with_store<T: TokenStore + 'static>(
+        store: T,
+        cs: ClientSecret,
+        http_cl: reqwest::Client,
+    ) -> Result<Authorizer> {
+        let cred = store
+            .load()
+            .await?
+            .ok_or_else(|| Error::msg("Authorizer: token store contains no credentials"))?;
+        let mut authz = Self::new_with_client(cred, cs, http_cl);
+        authz.store = Some(Box::new(store));
+        Ok(authz)
+    }
+
+    /// Use a different OAuth2 token endpoint, e.g. a test server.
+    pub fn set_token_url<S: Into<String>>(&mut self, token_url: S) {
+        self.token_url = token_url.into();
+    }
+
+    /// Write credentials to `store` after every refresh.
+    pub fn set_token_store<T: TokenStore + 'static>(&mut self, store: T) {
+        self.store = Some(Box::new(store));
+    }
+
     /// Returns a Bearer token for subsequent use.
-    pub async fn token(&mut self) -> anyhow::Result<String> {
-        // TODO: cache current token on disk and use it if not elapsed yet. This saves one oauth
-        // roundtrip.
+    pub async fn token(&mut self) -> Result<String> {
         match self.current_token {
-            None => (),
-            Some((ref t, ref c)) => {
-                // Token available and not expired
-                if c.elapsed() < ((self.cred.expires_in - 30) as f64).seconds() {
-                    return Ok(t.clone());
-                }
+            // Token available and not expired
+            Some((ref t, ref c))
+                if c.elapsed()
+                    < Duration::from_secs(self.cred.expires_in.saturating_sub(30) as u64) =>
+            {
+                return Ok(t.clone());
             }
+            _ => (),
         };
 
         info!(target: "hd_api::oauth2", "no current token available: refreshing from OAuth2 provider");
@@ -152,36 +266,84 @@ impl Authorizer {
         Ok(self.current_token.as_ref().unwrap().0.clone())
     }
 
-    async fn refresh(&mut self) -> anyhow::Result<(String, time::Instant)> {
-        let t = time::Instant::now();
+    async fn refresh(&mut self) -> Result<(String, Instant)> {
+        let t = Instant::now();
         let url = format!(
             "{}?client_id={}&client_secret={}&grant_type=refresh_token&refresh_token={}",
             self.token_url, self.cs.client_id, self.cs.client_secret, self.cred.refresh_token
         );
-        let req =
-            self.http_cl.post(url).build().map_err(|e| {
-                anyhow::Error::new(e).context("Couldn't build token exchange request.")
-            })?;
+        let req = self
+            .http_cl
+            .post(url)
+            .build()
+            .context("Couldn't build token exchange request.")?;
         info!(target: "hd_api::oauth2", "Refreshing OAuth2 access: {:?}", req);
-        let resp = match self.http_cl.execute(req).await {
-            Err(e) => return Err(anyhow::Error::new(e).context("Couldn't exchange code for token")),
-            Ok(resp) => resp,
-        };
+        let resp = self
+            .http_cl
+            .execute(req)
+            .await
+            .context("Couldn't exchange code for token")?;
         info!(target: "hd_api::oauth2", "Refresh request got response: {:?}", resp);
-        let body = String::from_utf8(resp.bytes().await?.into_iter().collect())?;
-        self.cred = from_str(&body)?;
+        self.cred = read_token_response(resp).await?;
+        if let Some(ref store) = self.store {
+            // The new token is usable even if it couldn't be persisted.
+            if let Err(e) = store.store(&self.cred).await {
+                error!(target: "hd_api::oauth2", "Couldn't persist refreshed credentials: {:#}", e);
+            }
+        }
         Ok((self.cred.access_token.clone(), t))
     }
 
+    /// The access token currently in use, if any.
+    pub fn current_token(&self) -> Option<&String> {
+        self.current_token.as_ref().map(|(t, _)| t)
+    }
+
+    /// Discard the current access token, so that the next call to `token()` obtains a new one
+    /// from the OAuth2 provider. Used when the API rejects a token before its expiry.
+    pub fn invalidate_token(&mut self) {
+        info!(target: "hd_api::oauth2", "invalidating current access token");
+        self.current_token = None;
+    }
+
     /// Set authorization headers on a request builder.
     pub async fn authorize(
         &mut self,
         rqb: reqwest::RequestBuilder,
-    ) -> anyhow::Result<reqwest::RequestBuilder> {
+    ) -> Result<reqwest::RequestBuilder> {
         Ok(rqb.header("Authorization", format!("Bearer {}", self.token().await?)))
     }
 }
 
+/// If the credentials contain an access token which is still valid, return it together with the
+/// instant it was issued at.
+fn unexpired_token(cred: &Credentials) -> Option<(String, Instant)> {
+    let age = time::OffsetDateTime::now_utc().unix_timestamp() - cred.issued_at?;
+    if age < 0 || age as usize + 30 >= cred.expires_in {
+        return None;
+    }
+    let issued = Instant::now().checked_sub(Duration::from_secs(age as u64))?;
+    Some((cred.access_token.clone(), issued))
+}
+
+/// Decode the response of the token endpoint: either credentials, or an `OAuthError`.
+async fn read_token_response(resp: reqwest::Response) -> Result<Credentials> {
+    let status = resp.status();
+    let body = resp.text().await?;
+    if status.is_success() {
+        let mut cred: Credentials = from_str(&body)?;
+        cred.issued_at = Some(time::OffsetDateTime::now_utc().unix_timestamp());
+        Ok(cred)
+    } else {
+        let err = from_str(&body).unwrap_or_else(|_| OAuthError {
+            error: status.to_string(),
+            error_description: body,
+        });
+        error!(target: "hd_api::oauth2", "Token request failed: {}", err);
+        Err(Error::Auth(err))
+    }
+}
+
 #[derive(Debug, Default, Clone, Eq, PartialEq)]
 pub enum LogInState {
     #[default]
@@ -209,6 +371,8 @@ pub struct LogInFlow {
     ok_body: String,
     err_body: String,
 
+    http_cl: reqwest::Client,
+
     state: LogInState,
     authz_code: Option<String>,
 }
@@ -285,8 +449,8 @@ impl Display for Lang {
 }
 
 // TODO: These could be read from the client secret file.
-const DEFAULT_AUTHORIZATION_URL: &str = "https://my.hidrive.com/oauth2/authorize";
-const DEFAULT_TOKEN_URL: &str = "https://my.hidrive.com/oauth2/token";
+pub(crate) const DEFAULT_AUTHORIZATION_URL: &str = "https://my.hidrive.com/oauth2/authorize";
+pub(crate) const DEFAULT_TOKEN_URL: &str = "https://my.hidrive.com/oauth2/token";
 const DEFAULT_BODY_RESPONSE: &str = r"
 <html>
 <head><title>Authorization complete</title></head>
@@ -337,6 +501,11 @@ impl LogInFlow {
         self.err_body = err_body;
     }
 
+    /// Use `http_cl` for exchanging the code, e.g. to go through a proxy.
+    pub fn set_http_client(&mut self, http_cl: reqwest::Client) {
+        self.http_cl = http_cl;
+    }
+
     /// Obtain URL for user to navigate to in order to authorize us.
     pub fn get_authorization_url(&self, scope: Scope) -> String {
         format!(
@@ -355,7 +524,7 @@ impl LogInFlow {
     /// If your application is configured with a redirect-to-localhost scheme, this will
     /// start a web server on port 8087 (TO DO: make this adjustable) and wait for the redirect
     /// request.
-    pub async fn wait_for_redirect(&mut self, abort_p: impl Fn() -> bool) -> anyhow::Result<()> {
+    pub async fn wait_for_redirect(&mut self, abort_p: impl Fn() -> bool) -> Result<()> {
         let rdr = RedirectHandlingServer::new(self.ok_body.clone(), self.err_body.clone());
         match rdr.start_and_wait_for_code(abort_p).await {
             LogInResult::Ok { code } => {
@@ -367,7 +536,7 @@ impl LogInFlow {
                 self.state = LogInState::Error;
                 info!(target: "hd_api::oauth2", "LogInFlow: Error (failed to receive code from internal server)");
                 return Err(
-                    anyhow::Error::msg(err).context("Received error from redirect catching server")
+                    Error::Auth(err).context("Received error from redirect catching server")
                 );
             }
         }
@@ -376,16 +545,16 @@ impl LogInFlow {
 
     /// Call this to exchange the received code for access tokens.
     /// Save the returned credentials somewhere for use in `Authorizer`.
-    pub async fn exchange_code(&mut self) -> anyhow::Result<Credentials> {
+    pub async fn exchange_code(&mut self) -> Result<Credentials> {
         info!(target: "hd_api::oauth2", "oauth2: Exchanging code");
         if self.state != LogInState::ReceivedCode {
-            return Err(anyhow::Error::msg(format!(
+            return Err(Error::msg(format!(
                 "LogInFlow: wrong state {:?}: no code obtained yet!",
                 self.state
             )));
         }
         let code = match self.authz_code {
-            None => return Err(anyhow::Error::msg("No code obtained yet!")),
+            None => return Err(Error::msg("No code obtained yet!")),
             Some(ref c) => c,
         };
         let url = format!(
@@ -394,17 +563,22 @@ impl LogInFlow {
         );
         self.state = LogInState::ExchangingCode;
         info!(target: "hd_api::oauth2", "LogInFlow: ExchangingCode");
-        let cl = reqwest::Client::new();
+        let cl = &self.http_cl;
         let req = cl
             .post(url)
             .build()
-            .map_err(|e| anyhow::Error::new(e).context("Couldn't build token exchange request."))?;
-        let resp = match cl.execute(req).await {
-            Err(e) => return Err(anyhow::Error::new(e).context("Couldn't exchange code for token")),
-            Ok(resp) => resp,
+            .context("Couldn't build token exchange request.")?;
+        let resp = cl
+            .execute(req)
+            .await
+            .context("Couldn't exchange code for token")?;
+        let token = match read_token_response(resp).await {
+            Ok(token) => token,
+            Err(e) => {
+                self.state = LogInState::Error;
+                return Err(e);
+            }
         };
-        let body = String::from_utf8(resp.bytes().await?.into_iter().collect())?;
-        let token = from_str(&body)?;
         self.state = LogInState::Complete;
         info!(target: "hd_api::oauth2", "LogInFlow: Complete");
         Ok(token)
@@ -449,7 +623,16 @@ pub async fn authorize_user(
     client_secret: ClientSecret,
     scope: Scope,
 ) -> Result<Credentials> {
-    let mut flow = LogInFlow::default_instance(client_secret);
+    authorize_user_with_flow(handler, LogInFlow::default_instance(client_secret), scope).await
+}
+
+/// Like `authorize_user`, but with a preconfigured `LogInFlow`, e.g. from
+/// `HiDriveBuilder::login_flow`.
+pub async fn authorize_user_with_flow(
+    handler: &mut dyn AuthorizationHandler,
+    mut flow: LogInFlow,
+    scope: Scope,
+) -> Result<Credentials> {
     let auth_url = flow.get_authorization_url(scope);
     handler.display_authorization_url(auth_url).await?;
     let abort_wait = || handler.abort_wait_for_redirect();
@@ -459,13 +642,12 @@ pub async fn authorize_user(
     Ok(credentials)
 }
 
-// The following ones are only pub for debugging.
-
+/// An error reported by the OAuth2 provider or the redirect server, contained in `Error::Auth`.
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(default)]
 pub struct OAuthError {
-    error: String,
-    error_description: String,
+    pub error: String,
+    pub error_description: String,
 }
 
 impl std::error::Error for OAuthError {}
@@ -570,7 +752,7 @@ impl RedirectHandlingServer {
         shutdown: mpsc::Sender<()>,
         ok_body: String,
         err_body: String,
-    ) -> anyhow::Result<hyper::Response<hyper::Body>> {
+    ) -> Result<hyper::Response<hyper::Body>> {
         shutdown.send(()).await.expect("shutdown: mpsc error");
         info!(target: "hd_api::oauth2", "Received OAuth callback");
         let response_builder = hyper::Response::builder().status(hyper::StatusCode::OK);
@@ -589,7 +771,7 @@ impl RedirectHandlingServer {
                     .expect("result: mpsc error");
                 return response_builder
                     .body(err_body.into())
-                    .map_err(anyhow::Error::new)
+                    .map_err(|e| Error::msg(e.to_string()))
                     .context("Couldn't create response to callback request");
             }
             Some(q) => q,
@@ -621,19 +803,19 @@ impl RedirectHandlingServer {
                 .expect("mpsc send error");
             return response_builder
                 .body(err_body.into())
-                .map_err(anyhow::Error::new)
+                .map_err(|e| Error::msg(e.to_string()))
                 .context("couldn't create response to callback request");
         }
         response_builder
             .body(ok_body.into())
-            .map_err(anyhow::Error::new)
+            .map_err(|e| Error::msg(e.to_string()))
             .context("couldn't create response to callback request")
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::oauth2;
+    use crate::oauth2::{self, TokenStore};
 
     #[tokio::test]
     async fn test_code_flow() {
@@ -669,10 +851,55 @@ mod tests {
         }
     }
 
+    fn test_credentials(issued_at: Option<i64>) -> oauth2::Credentials {
+        oauth2::Credentials {
+            refresh_token: "rt-test".into(),
+            expires_in: 3600,
+            userid: "1.2.3".into(),
+            access_token: "at-test".into(),
+            alias: "test".into(),
+            token_type: "Bearer".into(),
+            scope: None,
+            issued_at,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_file_token_store() {
+        let path = std::env::temp_dir().join(format!("hd_api_creds_{}.json", std::process::id()));
+        let store = oauth2::FileTokenStore::new(&path);
+        assert!(store.load().await.unwrap().is_none());
+
+        store.store(&test_credentials(Some(1234))).await.unwrap();
+        let cred = store.load().await.unwrap().unwrap();
+        assert_eq!("rt-test", cred.refresh_token);
+        assert_eq!(Some(1234), cred.issued_at);
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        assert!(!std::path::Path::new(&tmp).exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stored_token_is_reused() {
+        // A token issued a minute ago is still valid, and no refresh is necessary.
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        let store = oauth2::MemoryTokenStore::new(Some(test_credentials(Some(now - 60))));
+        let mut authz =
+            oauth2::Authorizer::new_with_store(store, Default::default(), reqwest::Client::new())
+                .await
+                .unwrap();
+        assert_eq!("at-test", authz.token().await.unwrap());
+
+        // Without issuance time or after expiry, the token is not trusted.
+        assert!(oauth2::unexpired_token(&test_credentials(None)).is_none());
+        assert!(oauth2::unexpired_token(&test_credentials(Some(now - 3590))).is_none());
+    }
+
+    // Run this with `--ignored` to check out the returned page manually.
     #[tokio::test]
+    #[ignore]
     async fn manual_test() {
-        // Enable this to check out the returned page manually.
-        return;
         let rdr = oauth2::RedirectHandlingServer::new(
             oauth2::DEFAULT_BODY_RESPONSE.into(),
             oauth2::DEFAULT_ERROR_RESPONSE.into(),
@@ -681,8 +908,8 @@ mod tests {
     }
 
     #[tokio::test]
+    #[ignore]
     async fn manual_exchange_test() {
-        return;
         let cs = oauth2::ClientSecret::load("clientsecret.json")
             .await
             .unwrap();
@@ -701,8 +928,8 @@ mod tests {
     }
 
     #[tokio::test]
+    #[ignore]
     async fn manual_refresh_test() {
-        return;
         let cs = oauth2::ClientSecret::load("clientsecret.json")
             .await
             .unwrap();
@@ -0,0 +1,1298 @@
+//! Synchronization of a local directory tree with a remote directory.
+//!
+//! `DirSync` compares both trees top-down using the HiDrive hashes: if the `chash` of a local
+//! directory equals the remote one, the entire subtree is identical and is skipped without listing
+//! it. Files are considered unchanged if their `mhash` (name, size, mtime) or `chash` (content)
+//! agree. The result is a `Plan`, which can be inspected before it is executed.
+//!
+//! ```ignore
+//! let mut sync = DirSync::new("/home/me/Documents", "/users/me/Documents");
+//! sync.set_direction(Direction::Upload);
+//! let plan = sync.plan(&hd.files()).await?;
+//! println!("{:?}", plan.actions);
+//! sync.execute(&hd.files(), &plan).await?;
+//! ```
+//!
+//! Without further information, a file existing on only one side is copied to the other side. A
+//! `SyncState` remembers the state of each path as of the last sync; with it, `Direction::Both`
+//! compares each side to that state and tells deletions from creations, and changes on one side
+//! from changes on both sides:
+//!
+//! ```ignore
+//! sync.set_state(SyncState::open("/home/me/.documents.hdsync").await?);
+//! ```
+//!
+//! A file changed on both sides since the last sync (without a `SyncState`: any file differing
+//! between both sides) is a conflict, which is settled according to the `ConflictPolicy`. Remote
+//! writes carry the mtime of the remote directory as observed while planning as `parent_mtime`
+//! precondition. If a directory is changed concurrently, the write fails with a conflict error
+//! (`Error::is_conflict`) instead of overwriting the change; planning again takes it into account.
+//! After each write, the next precondition is the directory's mtime as set by that write (the
+//! `ctime` of the entry written). A deletion returns no entry, so the directory's mtime is read
+//! again; if it stems from an entry created by someone else, execution fails with a conflict.
+//!
+//! File mtimes are transferred along with the content. As the `mhash` of a directory includes its
+//! mtime, the plan ends with setting the mtimes of changed directories on the non-authoritative
+//! side (the remote one, unless downloading), so that the directory hashes of both sides agree
+//! and the next comparison can skip them.
+
+use crate::error::{Context, Error, Result};
+use crate::hashing::{self, Hash};
+use crate::hidrive::{HiDriveFiles, DIR_PAGE_SIZE};
+use crate::sync_state::{EntryState, SyncState};
+use crate::transfer::{ChunkedUpload, DEFAULT_CHUNK_SIZE};
+use crate::types::*;
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use futures_util::future::{BoxFuture, FutureExt};
+use futures_util::StreamExt;
+use log::info;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+/// Fields requested for remote directories and their members.
+const DIR_FIELDS: &str = "id,name,type,mtime,size,chash,mhash,members.id,members.name,members.type,members.mtime,members.size,members.chash,members.mhash";
+/// Fields requested for files after transferring them.
+const FILE_FIELDS: &str = "id,name,type,ctime,mtime,size,chash,mhash";
+
+/// Which side is authoritative.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Direction {
+    /// Make the remote tree a copy of the local one. Remote files missing locally are deleted.
+    Upload,
+    /// Make the local tree a copy of the remote one. Local files missing remotely are deleted.
+    Download,
+    /// Copy new and changed files in both directions. Without a `SyncState`, the newer version
+    /// of a file wins and nothing is deleted. With it, deletions are propagated, and the newer
+    /// version only wins if a file has been changed on both sides.
+    #[default]
+    Both,
+}
+
+/// A file which has been changed on both sides.
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    /// Relative path, as in `Action`.
+    pub path: String,
+    pub local_size: u64,
+    pub local_mtime: i64,
+    /// The remote file, with `id, name, mtime, size, chash, mhash`.
+    pub remote: Item,
+}
+
+/// How a `Conflict` is settled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Upload the local version under a new name, and download the remote one.
+    KeepBoth,
+    /// Replace the remote file with the local one.
+    KeepLocal,
+    /// Replace the local file with the remote one.
+    KeepRemote,
+}
+
+/// Decides conflicts interactively, e.g. by asking the user.
+#[async_trait::async_trait]
+pub trait ConflictHandler: Send + Sync {
+    /// Returning an error aborts planning.
+    async fn resolve(&self, conflict: &Conflict) -> Result<Resolution>;
+}
+
+/// How conflicts are settled. Only used with `Direction::Both`.
+#[derive(Clone, Default)]
+pub enum ConflictPolicy {
+    /// Always `Resolution::KeepBoth`. The server picks the new name (`on_exist=autoname`),
+    /// e.g. `report (1).txt`.
+    KeepBoth,
+    PreferLocal,
+    PreferRemote,
+    /// Keep the version with the newer mtime; the local one if both are equal.
+    #[default]
+    PreferNewer,
+    /// Ask the handler for every conflict.
+    Ask(Arc<dyn ConflictHandler>),
+}
+
+impl fmt::Debug for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictPolicy::KeepBoth => f.write_str("KeepBoth"),
+            ConflictPolicy::PreferLocal => f.write_str("PreferLocal"),
+            ConflictPolicy::PreferRemote => f.write_str("PreferRemote"),
+            ConflictPolicy::PreferNewer => f.write_str("PreferNewer"),
+            ConflictPolicy::Ask(_) => f.write_str("Ask(..)"),
+        }
+    }
+}
+
+/// A step of a `Plan`. Paths are relative to the synchronized roots, separated by `/`; the empty
+/// path denotes the roots themselves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Create a remote directory.
+    MkdirRemote(String),
+    /// Create a local directory.
+    MkdirLocal(String),
+    /// Upload a local file, replacing the remote file.
+    Upload(String),
+    /// Download a remote file, replacing the local file.
+    Download(String),
+    /// Delete a remote file.
+    DeleteRemote(String),
+    /// Delete a remote directory recursively.
+    DeleteRemoteDir(String),
+    /// Delete a local file or directory (recursively).
+    DeleteLocal(String),
+    /// Set the mtime of a remote directory to that of the local directory.
+    SetRemoteMtime(String),
+    /// Set the mtime of a local directory to that of the remote directory.
+    SetLocalMtime(String),
+    /// Upload a local file under a new name, and replace it with the remote file.
+    KeepBoth(String),
+}
+
+/// Actions bringing both trees in sync, in the order they are executed.
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    pub actions: Vec<Action>,
+    /// Conflicts found, and how they are settled by `actions`.
+    pub conflicts: Vec<(Conflict, Resolution)>,
+    // Unchanged entries whose state is to be recorded.
+    settled: Vec<(String, EntryState)>,
+    // Mtimes of the remote directories listed, as preconditions for writes into them.
+    remote_mtimes: BTreeMap<String, i64>,
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+}
+
+/// A local file or directory with the hashes HiDrive computes for it.
+#[derive(Debug, Clone)]
+struct LocalEntry {
+    size: u64,
+    mtime: i64,
+    mhash: Hash,
+    chash: Hash,
+    // Only set for directories.
+    children: Option<BTreeMap<String, LocalEntry>>,
+}
+
+/// Scan `path` recursively. Symbolic links and names which aren't valid UTF-8 are skipped.
+async fn scan_local(path: PathBuf) -> Result<LocalEntry> {
+    Ok(LocalEntry::from_hashes(
+        hashing::DirHasher::new().hash(path).await?,
+    ))
+}
+
+fn join(rel: &str, name: &str) -> String {
+    if rel.is_empty() {
+        name.into()
+    } else {
+        format!("{}/{}", rel, name)
+    }
+}
+
+fn is_dir(it: &Item) -> bool {
+    it.typ.as_deref() == Some("dir")
+}
+
+fn mtime(it: &Item) -> i64 {
+    it.mtime.map(|t| t.unix_timestamp()).unwrap_or(0)
+}
+
+impl LocalEntry {
+    fn from_hashes(h: hashing::LocalHashes) -> LocalEntry {
+        // Names which aren't valid UTF-8 are skipped by the `DirHasher`.
+        let children = h.members.map(|ms| {
+            ms.into_iter()
+                .filter_map(|m| Some((m.name.to_str()?.to_owned(), LocalEntry::from_hashes(m))))
+                .collect()
+        });
+        LocalEntry {
+            size: if children.is_some() { 0 } else { h.size },
+            mtime: h.mtime,
+            mhash: h.mhash,
+            chash: h.chash,
+            children,
+        }
+    }
+
+    fn state(&self) -> EntryState {
+        match self.children {
+            Some(_) => EntryState::dir(None),
+            None => EntryState {
+                id: None,
+                dir: false,
+                size: self.size,
+                mtime: self.mtime,
+                chash: Some(self.chash.clone()),
+                mhash: Some(self.mhash.clone()),
+            },
+        }
+    }
+}
+
+/// How an executed action changes the recorded state of its path.
+enum StateChange {
+    Put(EntryState),
+    Remove,
+    Keep,
+    // The local version has been kept as `path`.
+    Copy {
+        path: String,
+        copy: EntryState,
+        state: EntryState,
+    },
+}
+
+/// Synchronizes a local directory with a remote directory.
+#[derive(Debug, Clone)]
+pub struct DirSync {
+    local: PathBuf,
+    remote: String,
+    direction: Direction,
+    state: Option<Arc<Mutex<SyncState>>>,
+    conflicts: ConflictPolicy,
+}
+
+impl DirSync {
+    /// Synchronize the local directory `local` with the remote directory at path `remote`.
+    pub fn new<P: AsRef<Path>, S: AsRef<str>>(local: P, remote: S) -> DirSync {
+        DirSync {
+            local: local.as_ref().into(),
+            remote: remote.as_ref().trim_end_matches('/').into(),
+            direction: Direction::default(),
+            state: None,
+            conflicts: ConflictPolicy::default(),
+        }
+    }
+
+    pub fn set_direction(&mut self, direction: Direction) -> &mut Self {
+        self.direction = direction;
+        self
+    }
+
+    /// Use and update `state`, the state of the trees as of the last sync. It must belong to this
+    /// pair of directories.
+    pub fn set_state(&mut self, state: SyncState) -> &mut Self {
+        self.state = Some(Arc::new(Mutex::new(state)));
+        self
+    }
+
+    pub fn set_conflict_policy(&mut self, policy: ConflictPolicy) -> &mut Self {
+        self.conflicts = policy;
+        self
+    }
+
+    /// The sync state given to `set_state`.
+    pub fn state(&self) -> Option<Arc<Mutex<SyncState>>> {
+        self.state.clone()
+    }
+
+    fn local_path(&self, rel: &str) -> PathBuf {
+        if rel.is_empty() {
+            self.local.clone()
+        } else {
+            self.local.join(rel)
+        }
+    }
+
+    fn remote_id(&self, rel: &str) -> Identifier {
+        if rel.is_empty() {
+            Identifier::Path(self.remote.clone())
+        } else {
+            Identifier::Path(format!("{}/{}", self.remote, rel))
+        }
+    }
+
+    /// Compare both trees and return the actions needed to synchronize them.
+    pub async fn plan(&self, files: &HiDriveFiles<'_>) -> Result<Plan> {
+        let local = if fs::try_exists(&self.local).await? {
+            Some(
+                scan_local(self.local.clone())
+                    .await
+                    .context("scanning local tree")?,
+            )
+        } else {
+            None
+        };
+        let remote = match self.list_remote(files, "").await {
+            Ok(it) => Some(it),
+            Err(e) if e.is_not_found() => None,
+            Err(e) => return Err(e),
+        };
+        let base = match self.state {
+            Some(ref st) => Some(st.lock().await),
+            None => None,
+        };
+        let mut planner = Planner {
+            sync: self,
+            files,
+            base: base.as_deref(),
+            actions: vec![],
+            conflicts: vec![],
+            settled: vec![],
+            remote_mtimes: BTreeMap::new(),
+            dirs: BTreeSet::new(),
+        };
+        match (local, remote) {
+            (Some(l), Some(r)) => planner.compare_dirs("", &l, r).await?,
+            (Some(l), None) if self.direction != Direction::Download => {
+                planner.actions.push(Action::MkdirRemote(String::new()));
+                planner.add_local_children("", &l);
+            }
+            (None, Some(r)) if self.direction != Direction::Upload => {
+                planner.actions.push(Action::MkdirLocal(String::new()));
+                planner.add_remote_children("", r).await?;
+            }
+            _ => (),
+        }
+        Ok(planner.finish())
+    }
+
+    async fn list_remote(&self, files: &HiDriveFiles<'_>, rel: &str) -> Result<Item> {
+        files
+            .get_dir_paged(self.remote_id(rel), DIR_FIELDS, DIR_PAGE_SIZE)
+            .await
+    }
+
+    /// Execute `plan`. Execution stops at the first failing action, which is a conflict error if
+    /// a remote directory has been changed since planning. The sync state, if any, is updated
+    /// after every action.
+    pub async fn execute(&self, files: &HiDriveFiles<'_>, plan: &Plan) -> Result<()> {
+        let mut state = match self.state {
+            Some(ref st) => Some(st.lock().await),
+            None => None,
+        };
+        if let Some(ref mut st) = state {
+            for (rel, es) in plan.settled.iter() {
+                st.put(rel, es.clone()).await?;
+            }
+        }
+        let mut parents = Guards {
+            mtimes: plan.remote_mtimes.clone(),
+            written: HashSet::new(),
+        };
+        for a in plan.actions.iter() {
+            info!(target: "hd_api::sync", "{:?}", a);
+            let change = self
+                .execute_action(files, a, &mut parents)
+                .await
+                .with_context(|| format!("sync: {:?}", a))?;
+            if let Some(ref mut st) = state {
+                match change {
+                    StateChange::Put(es) => st.put(action_path(a), es).await?,
+                    StateChange::Remove => st.remove(action_path(a)).await?,
+                    StateChange::Keep => (),
+                    StateChange::Copy { path, copy, state } => {
+                        st.put(&path, copy).await?;
+                        st.put(action_path(a), state).await?;
+                    }
+                }
+            }
+        }
+        if let Some(ref mut st) = state {
+            st.compact().await?;
+        }
+        Ok(())
+    }
+
+    /// Compare both trees and execute the resulting plan, which is returned.
+    pub async fn run(&self, files: &HiDriveFiles<'_>) -> Result<Plan> {
+        let plan = self.plan(files).await?;
+        self.execute(files, &plan).await?;
+        Ok(plan)
+    }
+
+    async fn execute_action(
+        &self,
+        files: &HiDriveFiles<'_>,
+        a: &Action,
+        parents: &mut Guards,
+    ) -> Result<StateChange> {
+        match a {
+            Action::MkdirRemote(rel) => {
+                let mut p = parents.params(rel);
+                p.add_int("mtime", local_mtime(&self.local_path(rel)).await? as isize);
+                p.add_str("fields", "id,ctime,mtime");
+                let it = files.mkdir(self.remote_id(rel), Some(&p)).await?;
+                parents.written(rel, &it)?;
+                parents.mtimes.insert(rel.clone(), mtime(&it));
+                return Ok(StateChange::Put(EntryState::dir(it.id)));
+            }
+            Action::MkdirLocal(rel) => {
+                fs::create_dir_all(self.local_path(rel)).await?;
+                return Ok(StateChange::Put(EntryState::dir(None)));
+            }
+            Action::Upload(rel) => {
+                let it = self.upload(files, rel, parents.params(rel), true).await?;
+                parents.written(rel, &it)?;
+                return Ok(StateChange::Put(EntryState::from_item(&it)));
+            }
+            Action::Download(rel) => {
+                let it = self.download(files, rel).await?;
+                return Ok(StateChange::Put(EntryState::from_item(&it)));
+            }
+            Action::DeleteRemote(rel) => {
+                let p = parents.params(rel);
+                files.delete(self.remote_id(rel), Some(&p)).await?;
+                self.deleted(files, parents, rel).await?;
+                return Ok(StateChange::Remove);
+            }
+            Action::DeleteRemoteDir(rel) => {
+                let mut p = parents.params(rel);
+                p.add_bool("recursive", true);
+                files.delete_dir(self.remote_id(rel), Some(&p)).await?;
+                self.deleted(files, parents, rel).await?;
+                return Ok(StateChange::Remove);
+            }
+            Action::DeleteLocal(rel) => {
+                let path = self.local_path(rel);
+                if fs::symlink_metadata(&path).await?.is_dir() {
+                    fs::remove_dir_all(path).await?;
+                } else {
+                    fs::remove_file(path).await?;
+                }
+                return Ok(StateChange::Remove);
+            }
+            Action::SetRemoteMtime(rel) => {
+                let mut p = parents.params(rel);
+                p.add_int("mtime", local_mtime(&self.local_path(rel)).await? as isize);
+                files.set_metadata(self.remote_id(rel), Some(&p)).await?;
+                // Its mtime is ours now, not one observed on the server; writes into the
+                // directory after this would go unguarded.
+                parents.mtimes.remove(rel);
+            }
+            Action::SetLocalMtime(rel) => {
+                let md = files.metadata(self.remote_id(rel), "mtime", None).await?;
+                filetime::set_file_mtime(
+                    self.local_path(rel),
+                    filetime::FileTime::from_unix_time(mtime(&md), 0),
+                )?;
+            }
+            Action::KeepBoth(rel) => {
+                let mut p = parents.params(rel);
+                p.add_str("on_exist", "autoname");
+                let copy = self.upload(files, rel, p, false).await?;
+                parents.written(rel, &copy)?;
+                let name = copy
+                    .name
+                    .as_deref()
+                    .ok_or_else(|| Error::msg("uploaded copy has no name"))?;
+                let path = join(parent(rel).unwrap_or_default(), name);
+                let dst = self.local_path(&path);
+                if fs::try_exists(&dst).await? {
+                    return Err(Error::msg(format!("{:?} exists already", dst)));
+                }
+                fs::rename(self.local_path(rel), dst).await?;
+                let it = self.download(files, rel).await?;
+                return Ok(StateChange::Copy {
+                    path,
+                    copy: EntryState::from_item(&copy),
+                    state: EntryState::from_item(&it),
+                });
+            }
+        }
+        Ok(StateChange::Keep)
+    }
+
+    /// Read the new mtime of the guarded remote parent of `rel` after deleting `rel`. It must have
+    /// been set by the deletion, not by the creation of an entry that we didn't write. Like the
+    /// `parent_mtime` precondition itself, this can't tell changes within the same second.
+    async fn deleted(
+        &self,
+        files: &HiDriveFiles<'_>,
+        parents: &mut Guards,
+        rel: &str,
+    ) -> Result<()> {
+        let (dir, before) = match parent(rel).and_then(|d| Some((d, *parents.mtimes.get(d)?))) {
+            Some(d) => d,
+            None => return Ok(()),
+        };
+        let m = mtime(&files.metadata(self.remote_id(dir), "mtime", None).await?);
+        let mut members = files.list_dir(self.remote_id(dir), "id,ctime", DIR_PAGE_SIZE);
+        while let Some(it) = members.next().await {
+            let it = it?;
+            let ours = it.id.as_ref().map(|id| parents.written.contains(id));
+            let ctime = it.ctime.map(|c| c.unix_timestamp());
+            if ctime == Some(m) && m > before && ours != Some(true) {
+                return Err(concurrent_change(dir, it.id.as_deref().unwrap_or_default()));
+            }
+        }
+        parents.mtimes.insert(dir.into(), m);
+        Ok(())
+    }
+
+    /// Upload the local file `rel`, passing `p` on to the request creating the remote file.
+    async fn upload(
+        &self,
+        files: &HiDriveFiles<'_>,
+        rel: &str,
+        mut p: Params,
+        overwrite: bool,
+    ) -> Result<Item> {
+        let path = self.local_path(rel);
+        let f = fs::File::open(&path).await?;
+        let md = f.metadata().await?;
+        let mtime = hashing::mtime_secs(&md)?;
+        let (dir, name) = match rel.rsplit_once('/') {
+            Some((d, n)) => (d, n),
+            None => ("", rel),
+        };
+        if md.len() as usize > DEFAULT_CHUNK_SIZE {
+            let mut up = ChunkedUpload::new(self.remote_id(dir), name);
+            up.set_mtime(mtime)
+                .set_overwrite(overwrite)
+                .set_size(md.len())
+                .set_params(p);
+            up.upload(files, f).await
+        } else {
+            let mut data = Vec::with_capacity(md.len() as usize);
+            let mut f = f;
+            f.read_to_end(&mut data).await?;
+            p.add_int("mtime", mtime as isize);
+            p.add_str("fields", FILE_FIELDS);
+            if overwrite {
+                files
+                    .upload(self.remote_id(dir), name, data, Some(&p))
+                    .await
+            } else {
+                files
+                    .upload_no_overwrite(self.remote_id(dir), name, data, Some(&p))
+                    .await
+            }
+        }
+    }
+
+    /// Download to a temporary file next to the destination, which replaces the destination
+    /// once complete.
+    async fn download(&self, files: &HiDriveFiles<'_>, rel: &str) -> Result<Item> {
+        let path = self.local_path(rel);
+        let md = files
+            .metadata(self.remote_id(rel), FILE_FIELDS, None)
+            .await?;
+        let tmp = path.with_file_name(format!(
+            ".{}.hd_sync",
+            path.file_name().unwrap_or_default().to_string_lossy()
+        ));
+        let mut f = fs::File::create(&tmp).await?;
+        let r = files.get(self.remote_id(rel), &mut f, None).await;
+        f.flush().await?;
+        drop(f);
+        if let Err(e) = r {
+            let _ = fs::remove_file(&tmp).await;
+            return Err(e);
+        }
+        filetime::set_file_mtime(&tmp, filetime::FileTime::from_unix_time(mtime(&md), 0))?;
+        fs::rename(&tmp, &path).await?;
+        Ok(md)
+    }
+}
+
+fn action_path(a: &Action) -> &str {
+    match a {
+        Action::MkdirRemote(r)
+        | Action::MkdirLocal(r)
+        | Action::Upload(r)
+        | Action::Download(r)
+        | Action::DeleteRemote(r)
+        | Action::DeleteRemoteDir(r)
+        | Action::DeleteLocal(r)
+        | Action::SetRemoteMtime(r)
+        | Action::SetLocalMtime(r)
+        | Action::KeepBoth(r) => r,
+    }
+}
+
+/// The action deleting the remote entry `r` at `rel`.
+fn delete_remote(rel: String, r: &Item) -> Action {
+    if is_dir(r) {
+        Action::DeleteRemoteDir(rel)
+    } else {
+        Action::DeleteRemote(rel)
+    }
+}
+
+/// The directory containing `rel`, unless `rel` is the root.
+fn parent(rel: &str) -> Option<&str> {
+    match rel.rsplit_once('/') {
+        Some((d, _)) => Some(d),
+        None if rel.is_empty() => None,
+        None => Some(""),
+    }
+}
+
+/// The mtimes of remote directories, by relative path, expected as `parent_mtime` precondition
+/// of writes into them, and the IDs of the entries written so far.
+struct Guards {
+    mtimes: BTreeMap<String, i64>,
+    written: HashSet<String>,
+}
+
+impl Guards {
+    /// Parameters for writing `rel`, with the mtime of its remote parent directory as
+    /// precondition.
+    fn params(&self, rel: &str) -> Params {
+        let mut p = Params::new();
+        if let Some(m) = parent(rel).and_then(|d| self.mtimes.get(d)) {
+            p.add_int("parent_mtime", *m as isize);
+        }
+        p
+    }
+
+    /// Record the entry created by writing `rel`. Its `ctime` is the new mtime of the parent.
+    fn written(&mut self, rel: &str, it: &Item) -> Result<()> {
+        if let Some(ref id) = it.id {
+            self.written.insert(id.clone());
+        }
+        if let Some(dir) = parent(rel).filter(|d| self.mtimes.contains_key(*d)) {
+            let ctime = it
+                .ctime
+                .ok_or_else(|| Error::msg(format!("sync: no ctime for {}", rel)))?;
+            self.mtimes.insert(dir.into(), ctime.unix_timestamp());
+        }
+        Ok(())
+    }
+}
+
+/// A conflict error for a directory changed by someone else during execution.
+fn concurrent_change(dir: &str, id: &str) -> Error {
+    Error::Api {
+        status: reqwest::StatusCode::CONFLICT,
+        error: ApiError {
+            code: 409,
+            msg: format!("directory {:?} changed concurrently (entry {})", dir, id),
+            auth: None,
+        },
+    }
+}
+
+async fn local_mtime(path: &Path) -> Result<i64> {
+    hashing::mtime_secs(&fs::metadata(path).await?)
+}
+
+struct Planner<'a, 'b> {
+    sync: &'a DirSync,
+    files: &'a HiDriveFiles<'b>,
+    base: Option<&'a SyncState>,
+    actions: Vec<Action>,
+    conflicts: Vec<(Conflict, Resolution)>,
+    settled: Vec<(String, EntryState)>,
+    remote_mtimes: BTreeMap<String, i64>,
+    // Remote directories whose mtime has to be set after the plan has been executed.
+    dirs: BTreeSet<String>,
+}
+
+impl Planner<'_, '_> {
+    fn finish(mut self) -> Plan {
+        for a in self.actions.iter() {
+            let rel = action_path(a);
+            // Directories are created with the local mtime, but their content changes it.
+            if matches!(a, Action::MkdirRemote(_) | Action::MkdirLocal(_)) {
+                self.dirs.insert(rel.into());
+            }
+            let mut parent = rel;
+            while let Some((p, _)) = parent.rsplit_once('/') {
+                self.dirs.insert(p.into());
+                parent = p;
+            }
+        }
+        // The root's own mtime doesn't contribute to any hash compared here.
+        self.dirs.remove("");
+        // Deepest directories first.
+        let mut dirs: Vec<String> = self.dirs.into_iter().collect();
+        dirs.sort_by_key(|d| std::cmp::Reverse(d.matches('/').count()));
+        let fixup = match self.sync.direction {
+            Direction::Download => Action::SetLocalMtime,
+            _ => Action::SetRemoteMtime,
+        };
+        self.actions.extend(dirs.into_iter().map(fixup));
+        Plan {
+            actions: self.actions,
+            conflicts: self.conflicts,
+            settled: self.settled,
+            remote_mtimes: self.remote_mtimes,
+        }
+    }
+
+    /// Record `es` as state of `rel`, unless it is known already.
+    fn settle(&mut self, rel: &str, es: EntryState) {
+        if let Some(base) = self.base {
+            if base.get(rel).map(|b| !b.same_content(&es)).unwrap_or(true) {
+                self.settled.push((rel.into(), es));
+            }
+        }
+    }
+
+    /// Record a subtree which is identical on both sides.
+    fn settle_local(&mut self, rel: &str, l: &LocalEntry) {
+        if self.base.is_none() {
+            return;
+        }
+        if !rel.is_empty() {
+            self.settle(rel, l.state());
+        }
+        for (name, c) in l.children.iter().flatten() {
+            self.settle_local(&join(rel, name), c);
+        }
+    }
+
+    fn base(&self, rel: &str) -> Option<&'_ EntryState> {
+        match self.sync.direction {
+            Direction::Both => self.base?.get(rel),
+            _ => None,
+        }
+    }
+
+    /// Whether a local entry, which has been deleted remotely, is unchanged since the last sync.
+    fn local_unchanged(&self, rel: &str, l: &LocalEntry) -> bool {
+        match (self.base(rel), l.children.as_ref()) {
+            (Some(b), None) => b.matches(Some(&l.mhash), Some(&l.chash)),
+            (Some(b), Some(children)) => {
+                b.dir
+                    && children
+                        .iter()
+                        .all(|(n, c)| self.local_unchanged(&join(rel, n), c))
+            }
+            (None, _) => false,
+        }
+    }
+
+    /// Whether a remote entry, which has been deleted locally, is unchanged since the last sync.
+    fn remote_unchanged<'s>(&'s self, rel: &'s str, r: &'s Item) -> BoxFuture<'s, Result<bool>> {
+        async move {
+            let b = match self.base(rel) {
+                Some(b) => b,
+                None => return Ok(false),
+            };
+            if !is_dir(r) {
+                return Ok(b.matches(r.mhash.as_ref(), r.chash.as_ref()));
+            }
+            if !b.dir {
+                return Ok(false);
+            }
+            let listing = self.sync.list_remote(self.files, rel).await?;
+            for m in listing.members.iter() {
+                let name = m.name.as_deref().unwrap_or_default();
+                if !self.remote_unchanged(&join(rel, name), m).await? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        .boxed()
+    }
+
+    fn compare_dirs<'s>(
+        &'s mut self,
+        rel: &'s str,
+        local: &'s LocalEntry,
+        remote: Item,
+    ) -> BoxFuture<'s, Result<()>> {
+        async move {
+            if Some(&local.chash) == remote.chash.as_ref() {
+                self.settle_local(rel, local);
+                return Ok(());
+            }
+            self.remote_mtimes.insert(rel.into(), mtime(&remote));
+            let empty = BTreeMap::new();
+            let lchildren = local.children.as_ref().unwrap_or(&empty);
+            let mut rchildren: BTreeMap<String, Item> = remote
+                .members
+                .into_iter()
+                .filter_map(|m| Some((m.name.clone()?, m)))
+                .collect();
+            let names: BTreeSet<String> =
+                lchildren.keys().chain(rchildren.keys()).cloned().collect();
+            for name in names {
+                let crel = join(rel, &name);
+                match (lchildren.get(&name), rchildren.remove(&name)) {
+                    (Some(l), Some(r)) => self.compare(&crel, l, r).await?,
+                    (Some(l), None) => match self.sync.direction {
+                        Direction::Download => self.actions.push(Action::DeleteLocal(crel)),
+                        // Deleted remotely.
+                        Direction::Both if self.local_unchanged(&crel, l) => {
+                            self.actions.push(Action::DeleteLocal(crel))
+                        }
+                        _ => self.add_local(crel, l),
+                    },
+                    (None, Some(r)) => match self.sync.direction {
+                        Direction::Upload => self.actions.push(delete_remote(crel, &r)),
+                        // Deleted locally.
+                        Direction::Both if self.remote_unchanged(&crel, &r).await? => {
+                            self.actions.push(delete_remote(crel, &r))
+                        }
+                        _ => self.add_remote(crel, r).await?,
+                    },
+                    (None, None) => (),
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    /// Compare a local and a remote entry of the same name.
+    async fn compare(&mut self, rel: &str, l: &LocalEntry, r: Item) -> Result<()> {
+        match (l.children.is_some(), is_dir(&r)) {
+            (true, true) => {
+                if Some(&l.chash) == r.chash.as_ref() {
+                    if Some(&l.mhash) != r.mhash.as_ref() {
+                        self.dirs.insert(rel.into());
+                    }
+                    self.settle_local(rel, l);
+                    return Ok(());
+                }
+                self.settle(rel, EntryState::dir(r.id.clone()));
+                let r = self.sync.list_remote(self.files, rel).await?;
+                if Some(&l.mhash) != r.mhash.as_ref() {
+                    self.dirs.insert(rel.into());
+                }
+                self.compare_dirs(rel, l, r).await
+            }
+            (false, false) => {
+                if Some(&l.mhash) == r.mhash.as_ref() || Some(&l.chash) == r.chash.as_ref() {
+                    self.settle(rel, EntryState::from_item(&r));
+                    return Ok(());
+                }
+                let action = match self.sync.direction {
+                    Direction::Upload => Action::Upload(rel.into()),
+                    Direction::Download => Action::Download(rel.into()),
+                    Direction::Both => match self.changed(rel, l, &r) {
+                        (true, false) => Action::Upload(rel.into()),
+                        (false, true) => Action::Download(rel.into()),
+                        _ => match self.resolve(rel, l, r).await? {
+                            Resolution::KeepBoth => Action::KeepBoth(rel.into()),
+                            Resolution::KeepLocal => Action::Upload(rel.into()),
+                            Resolution::KeepRemote => Action::Download(rel.into()),
+                        },
+                    },
+                };
+                self.actions.push(action);
+                Ok(())
+            }
+            // A file on one side, a directory on the other.
+            _ => {
+                if self.local_wins(rel, l, &r) {
+                    self.actions.push(delete_remote(rel.into(), &r));
+                    self.add_local(rel.into(), l);
+                    Ok(())
+                } else {
+                    self.actions.push(Action::DeleteLocal(rel.into()));
+                    self.add_remote(rel.into(), r).await
+                }
+            }
+        }
+    }
+
+    /// Whether the local and the remote version of a file have changed since the last sync.
+    /// Without a recorded state, both count as changed.
+    fn changed(&self, rel: &str, l: &LocalEntry, r: &Item) -> (bool, bool) {
+        match self.base(rel) {
+            Some(b) => (
+                !b.matches(Some(&l.mhash), Some(&l.chash)),
+                !b.matches(r.mhash.as_ref(), r.chash.as_ref()),
+            ),
+            None => (true, true),
+        }
+    }
+
+    /// Settle a conflict according to the policy.
+    async fn resolve(&mut self, rel: &str, l: &LocalEntry, r: Item) -> Result<Resolution> {
+        let conflict = Conflict {
+            path: rel.into(),
+            local_size: l.size,
+            local_mtime: l.mtime,
+            remote: r,
+        };
+        let resolution = match self.sync.conflicts {
+            ConflictPolicy::KeepBoth => Resolution::KeepBoth,
+            ConflictPolicy::PreferLocal => Resolution::KeepLocal,
+            ConflictPolicy::PreferRemote => Resolution::KeepRemote,
+            ConflictPolicy::PreferNewer if l.mtime >= mtime(&conflict.remote) => {
+                Resolution::KeepLocal
+            }
+            ConflictPolicy::PreferNewer => Resolution::KeepRemote,
+            ConflictPolicy::Ask(ref h) => h
+                .resolve(&conflict)
+                .await
+                .with_context(|| format!("resolving conflict at {:?}", rel))?,
+        };
+        info!(target: "hd_api::sync", "conflict at {:?}: {:?}", rel, resolution);
+        self.conflicts.push((conflict, resolution));
+        Ok(resolution)
+    }
+
+    /// Decide whether a local entry replaces a remote one of different type.
+    fn local_wins(&self, rel: &str, l: &LocalEntry, r: &Item) -> bool {
+        match self.sync.direction {
+            Direction::Upload => true,
+            Direction::Download => false,
+            Direction::Both => match self.changed(rel, l, r) {
+                (true, false) => true,
+                (false, true) => false,
+                _ => l.mtime >= mtime(r),
+            },
+        }
+    }
+
+    /// Plan copying a local entry which doesn't exist remotely.
+    fn add_local(&mut self, rel: String, l: &LocalEntry) {
+        match l.children {
+            None => self.actions.push(Action::Upload(rel)),
+            Some(_) => {
+                self.actions.push(Action::MkdirRemote(rel.clone()));
+                self.add_local_children(&rel, l);
+            }
+        }
+    }
+
+    fn add_local_children(&mut self, rel: &str, l: &LocalEntry) {
+        for (name, c) in l.children.iter().flatten() {
+            self.add_local(join(rel, name), c);
+        }
+    }
+
+    /// Plan copying a remote entry which doesn't exist locally.
+    async fn add_remote(&mut self, rel: String, r: Item) -> Result<()> {
+        if !is_dir(&r) {
+            self.actions.push(Action::Download(rel));
+            return Ok(());
+        }
+        self.actions.push(Action::MkdirLocal(rel.clone()));
+        let r = self.sync.list_remote(self.files, &rel).await?;
+        self.add_remote_children(&rel, r).await
+    }
+
+    fn add_remote_children<'s>(&'s mut self, rel: &'s str, r: Item) -> BoxFuture<'s, Result<()>> {
+        async move {
+            for m in r.members {
+                if let Some(name) = m.name.clone() {
+                    self.add_remote(join(rel, &name), m).await?;
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockServer;
+
+    fn tempdir(name: &str) -> PathBuf {
+        let d = std::env::temp_dir().join(format!("hd_api_sync_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&d);
+        std::fs::create_dir_all(&d).unwrap();
+        d
+    }
+
+    fn write(path: &Path, data: &[u8], mtime: i64) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+        filetime::set_file_mtime(path, filetime::FileTime::from_unix_time(mtime, 0)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_upload_converges() {
+        let srv = MockServer::start().await;
+        let hd = srv.hidrive();
+        let files = hd.files();
+        let local = tempdir("upload");
+        write(&local.join("a.txt"), b"aaa", 1_600_000_000);
+        write(&local.join("sub/b.txt"), b"bbb", 1_600_000_100);
+        write(&local.join("sub/deeper/c.txt"), b"ccc", 1_600_000_200);
+        std::fs::create_dir_all(local.join("empty")).unwrap();
+        srv.put_file("/users/test/sync/stale.txt", b"old", 1);
+        srv.put_file("/users/test/sync/old/x.txt", b"old", 1);
+
+        let mut sync = DirSync::new(&local, "/users/test/sync");
+        sync.set_direction(Direction::Upload);
+        let plan = sync.run(&files).await.unwrap();
+        assert!(plan
+            .actions
+            .contains(&Action::DeleteRemote("stale.txt".into())));
+        assert!(plan
+            .actions
+            .contains(&Action::DeleteRemoteDir("old".into())));
+        assert!(plan
+            .actions
+            .contains(&Action::MkdirRemote("sub/deeper".into())));
+        assert_eq!(
+            Some(b"ccc".to_vec()),
+            srv.read_file("/users/test/sync/sub/deeper/c.txt")
+        );
+        assert!(srv.item("/users/test/sync/empty").is_some());
+        assert!(srv.item("/users/test/sync/stale.txt").is_none());
+        assert!(srv.item("/users/test/sync/old").is_none());
+
+        // Both trees now have the same hashes.
+        assert!(sync.plan(&files).await.unwrap().is_empty());
+
+        write(&local.join("sub/b.txt"), b"changed", 1_600_000_300);
+        let plan = sync.run(&files).await.unwrap();
+        assert_eq!(
+            vec![
+                Action::Upload("sub/b.txt".into()),
+                Action::SetRemoteMtime("sub".into())
+            ],
+            plan.actions
+        );
+        assert!(sync.plan(&files).await.unwrap().is_empty());
+        std::fs::remove_dir_all(&local).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_download_and_both_directions() {
+        let srv = MockServer::start().await;
+        let hd = srv.hidrive();
+        let files = hd.files();
+        srv.put_file("/users/test/sync/x.txt", b"xxx", 1_600_000_000);
+        srv.put_file("/users/test/sync/d/y.txt", b"yyy", 1_600_000_000);
+        let local = tempdir("download");
+        write(&local.join("local_only.txt"), b"local", 1_600_000_000);
+
+        let mut sync = DirSync::new(&local, "/users/test/sync");
+        sync.set_direction(Direction::Download);
+        sync.run(&files).await.unwrap();
+        assert_eq!(
+            b"yyy".to_vec(),
+            std::fs::read(local.join("d/y.txt")).unwrap()
+        );
+        assert!(!local.join("local_only.txt").exists());
+        assert!(sync.plan(&files).await.unwrap().is_empty());
+
+        // The newer side wins, and new files are copied both ways.
+        sync.set_direction(Direction::Both);
+        write(&local.join("x.txt"), b"newer local", 1_700_000_000);
+        srv.put_file("/users/test/sync/d/y.txt", b"newer remote", 1_700_000_000);
+        write(&local.join("d/z.txt"), b"zzz", 1_600_000_000);
+        sync.run(&files).await.unwrap();
+        assert_eq!(
+            Some(b"newer local".to_vec()),
+            srv.read_file("/users/test/sync/x.txt")
+        );
+        assert_eq!(
+            Some(b"zzz".to_vec()),
+            srv.read_file("/users/test/sync/d/z.txt")
+        );
+        assert_eq!(
+            b"newer remote".to_vec(),
+            std::fs::read(local.join("d/y.txt")).unwrap()
+        );
+        assert!(sync.plan(&files).await.unwrap().is_empty());
+        std::fs::remove_dir_all(&local).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_state_propagates_deletions() {
+        let srv = MockServer::start().await;
+        let hd = srv.hidrive();
+        let files = hd.files();
+        let local = tempdir("state");
+        let state_path = local.with_extension("hdsync");
+        let _ = std::fs::remove_file(&state_path);
+        write(&local.join("a.txt"), b"aaa", 1_600_000_000);
+        write(&local.join("d/b.txt"), b"bbb", 1_600_000_000);
+        write(&local.join("d/c.txt"), b"ccc", 1_600_000_000);
+        srv.put_file("/users/test/sync/r.txt", b"rrr", 1_600_000_000);
+
+        let mut sync = DirSync::new(&local, "/users/test/sync");
+        sync.set_state(SyncState::open(&state_path).await.unwrap());
+        sync.run(&files).await.unwrap();
+        assert!(sync.plan(&files).await.unwrap().is_empty());
+        assert_eq!(5, sync.state().unwrap().lock().await.len());
+
+        // Deleted on one side and unchanged on the other.
+        std::fs::remove_file(local.join("a.txt")).unwrap();
+        srv.remove("/users/test/sync/d/b.txt");
+        // Changed remotely only, so the older version wins.
+        srv.put_file("/users/test/sync/r.txt", b"remote change", 1_500_000_000);
+        let plan = sync.run(&files).await.unwrap();
+        assert!(plan.actions.contains(&Action::DeleteRemote("a.txt".into())));
+        assert!(plan
+            .actions
+            .contains(&Action::DeleteLocal("d/b.txt".into())));
+        assert!(plan.actions.contains(&Action::Download("r.txt".into())));
+        assert!(srv.item("/users/test/sync/a.txt").is_none());
+        assert!(!local.join("d/b.txt").exists());
+        assert_eq!(
+            b"remote change".to_vec(),
+            std::fs::read(local.join("r.txt")).unwrap()
+        );
+        assert!(sync.plan(&files).await.unwrap().is_empty());
+
+        // An interrupted sync leaves a state which the next run continues from.
+        write(&local.join("e.txt"), b"eee", 1_600_000_000);
+        write(&local.join("f.txt"), b"fff", 1_600_000_000);
+        let mut plan = sync.plan(&files).await.unwrap();
+        assert_eq!(2, plan.actions.len());
+        plan.actions.truncate(1);
+        sync.execute(&files, &plan).await.unwrap();
+        drop(sync);
+
+        let mut sync = DirSync::new(&local, "/users/test/sync");
+        sync.set_state(SyncState::open(&state_path).await.unwrap());
+        let plan = sync.run(&files).await.unwrap();
+        assert!(!plan.actions.iter().any(|a| matches!(
+            a,
+            Action::DeleteLocal(_) | Action::DeleteRemote(_) | Action::DeleteRemoteDir(_)
+        )));
+        assert_eq!(
+            Some(b"fff".to_vec()),
+            srv.read_file("/users/test/sync/f.txt")
+        );
+        let st = sync.state().unwrap();
+        let st = st.lock().await;
+        assert!(st.get("e.txt").is_some() && st.get("f.txt").is_some());
+        assert!(st.get("a.txt").is_none() && st.get("d/b.txt").is_none());
+        std::fs::remove_dir_all(&local).unwrap();
+        std::fs::remove_file(&state_path).unwrap();
+    }
+
+    struct AlwaysRemote(std::sync::atomic::AtomicUsize);
+
+    #[async_trait::async_trait]
+    impl ConflictHandler for AlwaysRemote {
+        async fn resolve(&self, c: &Conflict) -> Result<Resolution> {
+            assert_eq!("x.txt", c.path);
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(Resolution::KeepRemote)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_conflicts() {
+        let srv = MockServer::start().await;
+        let hd = srv.hidrive();
+        let files = hd.files();
+        let local = tempdir("conflicts");
+        write(&local.join("x.txt"), b"base", 1_600_000_000);
+
+        let mut sync = DirSync::new(&local, "/users/test/sync");
+        sync.set_state(SyncState::in_memory())
+            .set_conflict_policy(ConflictPolicy::KeepBoth);
+        sync.run(&files).await.unwrap();
+
+        // Changed on both sides: the local version is kept under a new name.
+        write(&local.join("x.txt"), b"local", 1_700_000_100);
+        srv.put_file("/users/test/sync/x.txt", b"remote", 1_700_000_000);
+        let plan = sync.run(&files).await.unwrap();
+        assert_eq!(vec![Action::KeepBoth("x.txt".into())], plan.actions);
+        assert_eq!(1, plan.conflicts.len());
+        assert_eq!(
+            b"remote".to_vec(),
+            std::fs::read(local.join("x.txt")).unwrap()
+        );
+        assert_eq!(
+            b"local".to_vec(),
+            std::fs::read(local.join("x (1).txt")).unwrap()
+        );
+        assert_eq!(
+            Some(b"local".to_vec()),
+            srv.read_file("/users/test/sync/x (1).txt")
+        );
+        assert!(sync.plan(&files).await.unwrap().is_empty());
+
+        let handler = Arc::new(AlwaysRemote(0.into()));
+        sync.set_conflict_policy(ConflictPolicy::Ask(handler.clone()));
+        write(&local.join("x.txt"), b"local 2", 1_700_000_300);
+        srv.put_file("/users/test/sync/x.txt", b"remote 2", 1_700_000_200);
+        let plan = sync.run(&files).await.unwrap();
+        assert_eq!(vec![Action::Download("x.txt".into())], plan.actions);
+        assert_eq!(1, handler.0.load(std::sync::atomic::Ordering::SeqCst));
+        assert_eq!(
+            b"remote 2".to_vec(),
+            std::fs::read(local.join("x.txt")).unwrap()
+        );
+
+        // A remote change after planning makes the upload fail instead of overwriting it.
+        let mut p = Params::new();
+        p.add_int("mtime", 1);
+        files
+            .set_metadata(Identifier::Path("/users/test/sync".into()), Some(&p))
+            .await
+            .unwrap();
+        write(&local.join("x.txt"), b"local 3", 1_700_000_400);
+        let plan = sync.plan(&files).await.unwrap();
+        assert_eq!(vec![Action::Upload("x.txt".into())], plan.actions);
+        srv.put_file("/users/test/sync/new.txt", b"new", 1_700_000_000);
+        let err = sync.execute(&files, &plan).await.unwrap_err();
+        assert!(err.is_conflict());
+        assert_eq!(
+            Some(b"remote 2".to_vec()),
+            srv.read_file("/users/test/sync/x.txt")
+        );
+        let plan = sync.run(&files).await.unwrap();
+        assert!(plan.actions.contains(&Action::Download("new.txt".into())));
+        assert_eq!(
+            Some(b"local 3".to_vec()),
+            srv.read_file("/users/test/sync/x.txt")
+        );
+        std::fs::remove_dir_all(&local).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_change_during_execution() {
+        let srv = MockServer::start().await;
+        let hd = srv.hidrive();
+        let files = hd.files();
+        let local = tempdir("concurrent");
+        write(&local.join("a.txt"), b"a", 1_600_000_000);
+        write(&local.join("b.txt"), b"b", 1_600_000_000);
+        let mut sync = DirSync::new(&local, "/users/test/sync");
+        sync.set_state(SyncState::in_memory());
+        sync.run(&files).await.unwrap();
+
+        // Another client changes the directory right after the first upload.
+        write(&local.join("c.txt"), b"c", 1_600_000_000);
+        write(&local.join("d.txt"), b"d", 1_600_000_000);
+        let plan = sync.plan(&files).await.unwrap();
+        assert_eq!(
+            vec![
+                Action::Upload("c.txt".into()),
+                Action::Upload("d.txt".into())
+            ],
+            plan.actions
+        );
+        srv.set_mtime_after(1, "/users/test/sync", 1);
+        assert!(sync.execute(&files, &plan).await.unwrap_err().is_conflict());
+        assert_eq!(None, srv.read_file("/users/test/sync/d.txt"));
+        sync.run(&files).await.unwrap();
+
+        // Another client creates a file right after the first deletion. The directory's mtime
+        // is set back, so that the creation happens in a later second.
+        let mut p = Params::new();
+        p.add_int("mtime", 1);
+        files
+            .set_metadata(Identifier::Path("/users/test/sync".into()), Some(&p))
+            .await
+            .unwrap();
+        std::fs::remove_file(local.join("a.txt")).unwrap();
+        std::fs::remove_file(local.join("b.txt")).unwrap();
+        let plan = sync.plan(&files).await.unwrap();
+        assert_eq!(
+            vec![
+                Action::DeleteRemote("a.txt".into()),
+                Action::DeleteRemote("b.txt".into())
+            ],
+            plan.actions
+        );
+        // Type lookup and deletion of a.txt.
+        srv.put_file_after(2, "/users/test/sync/x.txt", b"x", 1_600_000_000);
+        assert!(sync.execute(&files, &plan).await.unwrap_err().is_conflict());
+        assert!(srv.item("/users/test/sync/a.txt").is_none());
+        assert!(srv.item("/users/test/sync/b.txt").is_some());
+        std::fs::remove_dir_all(&local).unwrap();
+    }
+}
@@ -0,0 +1,301 @@
+//! The state of a synchronized tree as of the last sync, used by `sync::DirSync` to tell local
+//! from remote changes (three-way comparison).
+//!
+//! The state is kept in a single file as a log of JSON lines, each recording the state of one
+//! path or its removal. Every change is appended and flushed to disk before the next action of a
+//! sync runs, so that an interrupted sync loses at most the record of the action in progress. A
+//! torn last line, as left by a crash during a write, is ignored when loading. On opening and after
+//! every completed sync, the log is compacted by writing a snapshot to a temporary file which then
+//! atomically replaces the log.
+
+use crate::error::{Context, Error, Result};
+use crate::hashing::Hash;
+use crate::types::Item;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+/// State of a file or directory, which was identical locally and remotely after the last sync.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EntryState {
+    /// Remote ID, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub dir: bool,
+    pub size: u64,
+    pub mtime: i64,
+    /// Not recorded for directories, whose hashes change with their content.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chash: Option<Hash>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mhash: Option<Hash>,
+}
+
+impl EntryState {
+    /// State of a directory.
+    pub fn dir(id: Option<String>) -> EntryState {
+        EntryState {
+            id,
+            dir: true,
+            ..Default::default()
+        }
+    }
+
+    /// State of a remote file or directory.
+    pub fn from_item(it: &Item) -> EntryState {
+        if it.typ.as_deref() == Some("dir") {
+            return EntryState::dir(it.id.clone());
+        }
+        EntryState {
+            id: it.id.clone(),
+            dir: false,
+            size: it.size.unwrap_or(0) as u64,
+            mtime: it.mtime.map(|t| t.unix_timestamp()).unwrap_or(0),
+            chash: it.chash.clone(),
+            mhash: it.mhash.clone(),
+        }
+    }
+
+    /// A file with the given hashes is unchanged since this state was recorded, if either its
+    /// metadata (`mhash`) or its content (`chash`) is the same.
+    pub fn matches(&self, mhash: Option<&Hash>, chash: Option<&Hash>) -> bool {
+        let eq = |a: Option<&Hash>, b: Option<&Hash>| a.is_some() && a == b;
+        !self.dir && (eq(self.mhash.as_ref(), mhash) || eq(self.chash.as_ref(), chash))
+    }
+
+    /// Same type and hashes; the remote ID isn't compared.
+    pub(crate) fn same_content(&self, other: &EntryState) -> bool {
+        self.dir == other.dir
+            && (self.dir || (self.mhash == other.mhash && self.chash == other.chash))
+    }
+}
+
+/// One line of the log. A missing state removes the path and everything below it.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    path: String,
+    state: Option<EntryState>,
+}
+
+/// Durable sync state. Paths are relative to the synchronized roots, as in `sync::Action`.
+#[derive(Debug)]
+pub struct SyncState {
+    path: Option<PathBuf>,
+    entries: BTreeMap<String, EntryState>,
+    log: Option<fs::File>,
+}
+
+impl SyncState {
+    /// A state which isn't persisted, e.g. for a single run or for tests.
+    pub fn in_memory() -> SyncState {
+        SyncState {
+            path: None,
+            entries: BTreeMap::new(),
+            log: None,
+        }
+    }
+
+    /// Open the state file at `path`, creating it if necessary.
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<SyncState> {
+        let path = path.as_ref().to_path_buf();
+        let mut entries = BTreeMap::new();
+        match fs::read(&path).await {
+            Ok(data) => load(&data, &mut entries)
+                .with_context(|| format!("SyncState: reading {:?}", path))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        info!(target: "hd_api::sync_state", "loaded {} entries from {:?}", entries.len(), path);
+        let mut st = SyncState {
+            path: Some(path),
+            entries,
+            log: None,
+        };
+        st.compact().await?;
+        Ok(st)
+    }
+
+    pub fn get(&self, path: &str) -> Option<&EntryState> {
+        self.entries.get(path)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &EntryState)> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Record the state of `path`. Nothing is written if the state is unchanged.
+    pub async fn put(&mut self, path: &str, state: EntryState) -> Result<()> {
+        if let Some(old) = self.entries.get(path) {
+            if old.same_content(&state) && (state.id.is_none() || old.id == state.id) {
+                return Ok(());
+            }
+        }
+        self.append(path, Some(&state)).await?;
+        self.entries.insert(path.into(), state);
+        Ok(())
+    }
+
+    /// Forget `path` and everything below it.
+    pub async fn remove(&mut self, path: &str) -> Result<()> {
+        if !self.entries.keys().any(|k| is_below(k, path)) {
+            return Ok(());
+        }
+        self.append(path, None).await?;
+        remove_below(&mut self.entries, path);
+        Ok(())
+    }
+
+    /// Rewrite the state file, dropping superseded records.
+    pub async fn compact(&mut self) -> Result<()> {
+        let path = match self.path {
+            Some(ref p) => p.clone(),
+            None => return Ok(()),
+        };
+        self.log = None;
+        let mut data = vec![];
+        for (p, s) in self.entries.iter() {
+            serde_json::to_writer(
+                &mut data,
+                &Record {
+                    path: p.clone(),
+                    state: Some(s.clone()),
+                },
+            )?;
+            data.push(b'\n');
+        }
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut f = fs::File::create(&tmp)
+            .await
+            .context("SyncState: creating temporary file")?;
+        f.write_all(&data).await?;
+        f.sync_all().await?;
+        drop(f);
+        fs::rename(&tmp, &path)
+            .await
+            .context("SyncState: replacing state file")?;
+        Ok(())
+    }
+
+    async fn append(&mut self, path: &str, state: Option<&EntryState>) -> Result<()> {
+        let file = match self.path {
+            Some(ref p) => p,
+            None => return Ok(()),
+        };
+        if self.log.is_none() {
+            self.log = Some(
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(file)
+                    .await?,
+            );
+        }
+        let mut line = serde_json::to_vec(&Record {
+            path: path.into(),
+            state: state.cloned(),
+        })?;
+        line.push(b'\n');
+        let log = self.log.as_mut().unwrap();
+        log.write_all(&line).await?;
+        log.sync_data().await?;
+        Ok(())
+    }
+}
+
+fn is_below(key: &str, path: &str) -> bool {
+    path.is_empty()
+        || key == path
+        || (key.starts_with(path) && key.as_bytes().get(path.len()) == Some(&b'/'))
+}
+
+fn remove_below(entries: &mut BTreeMap<String, EntryState>, path: &str) {
+    entries.retain(|k, _| !is_below(k, path));
+}
+
+fn load(data: &[u8], entries: &mut BTreeMap<String, EntryState>) -> Result<()> {
+    let lines: Vec<&[u8]> = data.split(|b| *b == b'\n').collect();
+    for (i, line) in lines.iter().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let rec: Record = match serde_json::from_slice(line) {
+            Ok(r) => r,
+            // The last line lacks its newline if the process died while writing it.
+            Err(e) if i == lines.len() - 1 => {
+                warn!(target: "hd_api::sync_state", "ignoring incomplete last record: {}", e);
+                break;
+            }
+            Err(e) => return Err(Error::from(e).context(format!("line {}", i + 1))),
+        };
+        match rec.state {
+            Some(s) => {
+                entries.insert(rec.path, s);
+            }
+            None => remove_below(entries, &rec.path),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(n: u8) -> EntryState {
+        EntryState {
+            size: n as u64,
+            mtime: n as i64,
+            chash: Some(Hash::for_string([n])),
+            mhash: Some(Hash::for_string([n, n])),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_log_survives_reopen_and_torn_write() {
+        let path = std::env::temp_dir().join(format!("hd_api_state_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut st = SyncState::open(&path).await.unwrap();
+        st.put("a", EntryState::dir(None)).await.unwrap();
+        st.put("a/x", file(1)).await.unwrap();
+        st.put("a/y", file(2)).await.unwrap();
+        st.put("b", file(3)).await.unwrap();
+        st.remove("a").await.unwrap();
+        st.put("a", EntryState::dir(None)).await.unwrap();
+        drop(st);
+
+        // A crash in the middle of appending a record.
+        let mut data = std::fs::read(&path).unwrap();
+        data.extend_from_slice(br#"{"path":"c","sta"#);
+        std::fs::write(&path, data).unwrap();
+
+        let st = SyncState::open(&path).await.unwrap();
+        assert_eq!(2, st.len());
+        assert_eq!(Some(&file(3)), st.get("b"));
+        assert!(st.get("a/x").is_none());
+        assert!(st.get("a").unwrap().dir);
+        // Compacted on opening.
+        assert_eq!(2, std::fs::read_to_string(&path).unwrap().lines().count());
+
+        std::fs::write(&path, "garbage\n{}\n").unwrap();
+        assert!(SyncState::open(&path).await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
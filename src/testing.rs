@@ -0,0 +1,1243 @@
+//! An in-process HiDrive server for hermetic tests (cargo feature `testing`).
+//!
+//! `MockServer` implements the endpoints used by `HiDriveFiles`, `HiDriveUser`,
+//! `HiDrivePermission` and `HiDriveShareLinks` as well as the OAuth2 token endpoint, backed by an
+//! in-memory file tree.
+//! `chash`, `mhash`, `nhash` and `mohash` are computed with the `hashing` module, the same way
+//! the HiDrive server computes them.
+//!
+//! ```ignore
+//! let srv = MockServer::start().await;
+//! srv.put_file("/users/test/a.txt", b"hello", 1234567890);
+//! let hd = srv.hidrive();
+//! let it = hd.files().metadata(Identifier::Path("/users/test/a.txt".into()), "size", None).await?;
+//! assert_eq!(Some(5), it.size);
+//! ```
+//!
+//! Paths are absolute and start with `/`. The user's home directory is `MockServer::HOME`.
+//! Ranges of `/file/hash` are given as block numbers on the requested level.
+
+use crate::hashing::{self, Hash, Hashes};
+use crate::hidrive::{HiDrive, HiDriveBuilder};
+use crate::oauth2::{Authorizer, ClientSecret, Credentials};
+use crate::types::*;
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use futures_util::FutureExt;
+use hyper::{Body, Method, Response, StatusCode};
+use log::info;
+use serde::Serialize;
+use serde_json::json;
+use time::OffsetDateTime;
+use tokio::sync::oneshot;
+
+const API_PREFIX: &str = "/2.1";
+const TOKEN_PATH: &str = "/oauth2/token";
+
+type Params = HashMap<String, String>;
+type Reply = Response<Body>;
+
+enum Kind {
+    // Member name to ID.
+    Dir(BTreeMap<String, String>),
+    File { data: Vec<u8>, chash: Hash },
+}
+
+struct Node {
+    name: String,
+    parent: Option<String>,
+    ctime: i64,
+    mtime: i64,
+    kind: Kind,
+}
+
+impl Node {
+    fn members(&self) -> Option<&BTreeMap<String, String>> {
+        match self.kind {
+            Kind::Dir(ref m) => Some(m),
+            Kind::File { .. } => None,
+        }
+    }
+}
+
+type Change = Box<dyn FnOnce(&mut State) + Send>;
+
+struct State {
+    nodes: HashMap<String, Node>,
+    root: String,
+    next_id: usize,
+
+    access_tokens: HashSet<String>,
+    refresh_token: String,
+    next_token: usize,
+
+    shares: BTreeMap<String, Share>,
+    next_share: usize,
+
+    failures: VecDeque<StatusCode>,
+    // Changes applied before handling the API request with the given number.
+    changes: Vec<(usize, Change)>,
+    requests: usize,
+    refreshes: usize,
+    connections: usize,
+}
+
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+fn content_hash(data: &[u8]) -> Hashes {
+    let mut hasher = hashing::ChashHasher::new();
+    hasher.set_retained_level(0);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+fn reply<T: Serialize>(status: StatusCode, body: &T) -> Reply {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(body).unwrap()))
+        .unwrap()
+}
+
+/// An API error, answered with an `ApiError` object.
+struct Fail {
+    status: StatusCode,
+    msg: String,
+}
+
+impl Fail {
+    fn reply(self) -> Reply {
+        reply(
+            self.status,
+            &json!({"code": self.status.as_u16(), "msg": self.msg}),
+        )
+    }
+}
+
+fn error(status: StatusCode, msg: impl Into<String>) -> Fail {
+    Fail {
+        status,
+        msg: msg.into(),
+    }
+}
+
+/// Apply the parameters `ttl`, `maxcount` and `password` (empty to remove it) to a share.
+fn update_share(mut share: Share, p: &Params) -> Result<Share, Fail> {
+    let number = |name: &str| -> Result<Option<usize>, Fail> {
+        p.get(name)
+            .map(|v| v.parse())
+            .transpose()
+            .map_err(|_| error(StatusCode::BAD_REQUEST, format!("invalid {}", name)))
+    };
+    if let Some(ttl) = number("ttl")? {
+        share.ttl = Some(ttl);
+        share.valid_until = OffsetDateTime::from_unix_timestamp(now() + ttl as i64).ok();
+    }
+    if let Some(max) = number("maxcount")? {
+        share.maxcount = Some(max);
+        share.remaining = Some(max.saturating_sub(share.count.unwrap_or(0)));
+    }
+    if let Some(pw) = p.get("password") {
+        share.has_password = Some(!pw.is_empty());
+    }
+    Ok(share)
+}
+
+fn no_content() -> Reply {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|c| !c.is_empty() && *c != ".")
+}
+
+/// "name.ext" -> "name (n).ext"
+fn numbered_name(name: &str, n: usize) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{} ({}).{}", stem, n, ext),
+        _ => format!("{} ({})", name, n),
+    }
+}
+
+impl State {
+    fn new() -> State {
+        let mut st = State {
+            nodes: HashMap::new(),
+            root: String::new(),
+            next_id: 1,
+            access_tokens: HashSet::new(),
+            refresh_token: "rt-0".into(),
+            next_token: 1,
+            shares: BTreeMap::new(),
+            next_share: 1,
+            failures: VecDeque::new(),
+            changes: vec![],
+            requests: 0,
+            refreshes: 0,
+            connections: 0,
+        };
+        st.root = st.add_node(None, "", Kind::Dir(BTreeMap::new()), 0);
+        st.mkdir_p(MockServer::HOME);
+        st
+    }
+
+    fn add_node(&mut self, parent: Option<&str>, name: &str, kind: Kind, mtime: i64) -> String {
+        let id = format!(
+            "{}{}.{}",
+            if matches!(kind, Kind::Dir(_)) {
+                "b"
+            } else {
+                "a"
+            },
+            self.next_id,
+            self.next_id % 7
+        );
+        self.next_id += 1;
+        // The parent's mtime is the new node's ctime.
+        let ctime = now();
+        if let Some(p) = parent {
+            if let Some(Node {
+                kind: Kind::Dir(ref mut m),
+                ref mut mtime,
+                ..
+            }) = self.nodes.get_mut(p)
+            {
+                m.insert(name.into(), id.clone());
+                *mtime = ctime;
+            }
+        }
+        self.nodes.insert(
+            id.clone(),
+            Node {
+                name: name.into(),
+                parent: parent.map(String::from),
+                ctime,
+                mtime,
+                kind,
+            },
+        );
+        id
+    }
+
+    fn put_file(&mut self, path: &str, data: &[u8], mtime: i64) -> String {
+        let (dir, name) = path.rsplit_once('/').expect("absolute path");
+        let dir = self.mkdir_p(dir);
+        if let Some(old) = self.child(&dir, name) {
+            self.remove(&old);
+        }
+        let chash = content_hash(data).top_hash().clone();
+        let kind = Kind::File {
+            data: data.into(),
+            chash,
+        };
+        self.add_node(Some(&dir), name, kind, mtime)
+    }
+
+    fn mkdir_p(&mut self, path: &str) -> String {
+        let mut cur = self.root.clone();
+        for c in split_path(path) {
+            cur = match self.child(&cur, c) {
+                Some(id) => id,
+                None => self.add_node(Some(&cur), c, Kind::Dir(BTreeMap::new()), now()),
+            };
+        }
+        cur
+    }
+
+    fn child(&self, dir: &str, name: &str) -> Option<String> {
+        self.nodes.get(dir)?.members()?.get(name).cloned()
+    }
+
+    fn walk(&self, from: &str, path: &str) -> Option<String> {
+        let mut cur = from.to_string();
+        for c in split_path(path) {
+            cur = if c == ".." {
+                self.nodes.get(&cur)?.parent.clone()?
+            } else {
+                self.child(&cur, c)?
+            };
+        }
+        Some(cur)
+    }
+
+    /// Resolve an object given by ID and/or path parameters.
+    fn resolve(&self, p: &Params, id_param: &str, path_param: &str) -> Result<String, Fail> {
+        let found = match (p.get(id_param), p.get(path_param)) {
+            (Some(id), None) => self.nodes.get(id).map(|_| id.clone()),
+            (Some(id), Some(path)) => self.walk(id, path),
+            (None, Some(path)) => self.walk(&self.root, path),
+            (None, None) => {
+                return Err(error(
+                    StatusCode::BAD_REQUEST,
+                    format!("missing parameter {} or {}", id_param, path_param),
+                ))
+            }
+        };
+        found.ok_or_else(|| error(StatusCode::NOT_FOUND, "Not Found"))
+    }
+
+    /// Resolve the parent directory and name of an object which may not exist yet.
+    fn resolve_new(
+        &self,
+        p: &Params,
+        id_param: &str,
+        path_param: &str,
+    ) -> Result<(String, String), Fail> {
+        let path = p.get(path_param).map(String::as_str).unwrap_or("");
+        let (dir, name) = match path.trim_end_matches('/').rsplit_once('/') {
+            Some((dir, name)) => (dir, name),
+            None => ("", path),
+        };
+        if name.is_empty() {
+            return Err(error(StatusCode::BAD_REQUEST, "invalid path"));
+        }
+        let from = match p.get(id_param) {
+            Some(id) => id.clone(),
+            None if path.starts_with('/') => self.root.clone(),
+            None => return Err(error(StatusCode::BAD_REQUEST, "relative path without id")),
+        };
+        let parent = self
+            .walk(&from, dir)
+            .filter(|d| self.nodes[d].members().is_some())
+            .ok_or_else(|| error(StatusCode::NOT_FOUND, "Parent directory not found"))?;
+        Ok((parent, name.into()))
+    }
+
+    /// Check `parent_mtime`-style preconditions.
+    fn check_mtime(&self, p: &Params, param: &str, dir: &str) -> Result<(), Fail> {
+        match p.get(param).map(|m| m.parse::<i64>()) {
+            Some(Ok(m)) if m != self.nodes[dir].mtime => {
+                Err(error(StatusCode::CONFLICT, format!("{} mismatch", param)))
+            }
+            Some(Err(_)) => Err(error(StatusCode::BAD_REQUEST, format!("invalid {}", param))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Apply `on_exist` to a name about to be created in `dir`. Returns the name to use.
+    fn on_exist(
+        &mut self,
+        p: &Params,
+        dir: &str,
+        name: &str,
+        overwrite: bool,
+    ) -> Result<String, Fail> {
+        if self.child(dir, name).is_none() {
+            return Ok(name.into());
+        }
+        match p.get("on_exist").map(String::as_str) {
+            Some("autoname") => Ok((1..)
+                .map(|n| numbered_name(name, n))
+                .find(|n| self.child(dir, n).is_none())
+                .unwrap()),
+            Some("overwrite") => {
+                self.remove(&self.child(dir, name).unwrap());
+                Ok(name.into())
+            }
+            _ if overwrite => {
+                self.remove(&self.child(dir, name).unwrap());
+                Ok(name.into())
+            }
+            _ => Err(error(StatusCode::CONFLICT, "Conflict: target exists")),
+        }
+    }
+
+    fn remove(&mut self, id: &str) {
+        if let Some(node) = self.nodes.remove(id) {
+            if let Kind::Dir(ref m) = node.kind {
+                for c in m.values() {
+                    self.remove(c);
+                }
+            }
+            if let Some(Node {
+                kind: Kind::Dir(ref mut m),
+                ref mut mtime,
+                ..
+            }) = node.parent.and_then(|p| self.nodes.get_mut(&p))
+            {
+                m.remove(&node.name);
+                *mtime = now();
+            }
+        }
+    }
+
+    fn deep_copy(&mut self, id: &str, dst: &str, name: &str, preserve_mtime: bool) -> String {
+        let (kind, mtime) = {
+            let n = &self.nodes[id];
+            let kind = match n.kind {
+                Kind::Dir(_) => Kind::Dir(BTreeMap::new()),
+                Kind::File {
+                    ref data,
+                    ref chash,
+                } => Kind::File {
+                    data: data.clone(),
+                    chash: chash.clone(),
+                },
+            };
+            (kind, if preserve_mtime { n.mtime } else { now() })
+        };
+        let new = self.add_node(Some(dst), name, kind, mtime);
+        let members: Vec<(String, String)> = self.nodes[id]
+            .members()
+            .map(|m| m.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+            .unwrap_or_default();
+        for (n, c) in members {
+            self.deep_copy(&c, &new, &n, preserve_mtime);
+        }
+        new
+    }
+
+    fn path(&self, id: &str) -> String {
+        let mut parts = vec![];
+        let mut cur = &self.nodes[id];
+        while let Some(ref p) = cur.parent {
+            parts.push(cur.name.as_str());
+            cur = &self.nodes[p];
+        }
+        parts.reverse();
+        format!("/{}", parts.join("/"))
+    }
+
+    fn size(&self, id: &str) -> usize {
+        match self.nodes[id].kind {
+            Kind::File { ref data, .. } => data.len(),
+            Kind::Dir(ref m) => m.values().map(|c| self.size(c)).sum(),
+        }
+    }
+
+    fn mhash(&self, id: &str) -> Option<Hash> {
+        let n = &self.nodes[id];
+        n.parent.as_ref()?;
+        Some(match n.kind {
+            Kind::File { ref data, .. } => {
+                hashing::mhash(&n.name, n.mtime, Some(data.len() as u64))
+            }
+            Kind::Dir(_) => hashing::mhash(&n.name, n.mtime, None),
+        })
+    }
+
+    fn chash(&self, id: &str) -> Hash {
+        match self.nodes[id].kind {
+            Kind::File { ref chash, .. } => chash.clone(),
+            Kind::Dir(ref m) => {
+                let mh: Vec<Hash> = m.values().filter_map(|c| self.mhash(c)).collect();
+                let ch: Vec<Hash> = m.values().map(|c| self.chash(c)).collect();
+                hashing::chash_dir(&mh, &ch)
+            }
+        }
+    }
+
+    fn item(&self, id: &str, with_members: bool) -> Item {
+        let n = &self.nodes[id];
+        let ts = |t| OffsetDateTime::from_unix_timestamp(t).ok();
+        let mut it = Item {
+            path: self.path(id),
+            name: Some(n.name.clone()),
+            size: Some(self.size(id)),
+            id: Some(id.into()),
+            parent_id: n.parent.clone(),
+            ctime: ts(n.ctime),
+            mtime: ts(n.mtime),
+            chash: Some(self.chash(id)),
+            mhash: self.mhash(id),
+            nhash: n.parent.as_ref().map(|_| hashing::nhash(&n.name)),
+            readable: Some(true),
+            writable: Some(true),
+            ..Default::default()
+        };
+        match n.kind {
+            Kind::File { .. } => it.typ = Some("file".into()),
+            Kind::Dir(ref m) => {
+                it.typ = Some("dir".into());
+                it.nmembers = Some(m.len());
+                it.has_dirs = Some(m.values().any(|c| self.nodes[c].members().is_some()));
+                let mh: Vec<Hash> = m.values().filter_map(|c| self.mhash(c)).collect();
+                it.mohash = Some(hashing::mohash_dir(&mh));
+                if with_members {
+                    it.members = m.values().map(|c| self.item(c, false)).collect();
+                }
+            }
+        }
+        it
+    }
+
+    fn file_data(&mut self, id: &str) -> Result<&mut Vec<u8>, Fail> {
+        match self.nodes.get_mut(id).map(|n| &mut n.kind) {
+            Some(Kind::File { ref mut data, .. }) => Ok(data),
+            _ => Err(error(StatusCode::BAD_REQUEST, "not a file")),
+        }
+    }
+
+    fn update_file(&mut self, id: &str, f: impl FnOnce(&mut Vec<u8>), mtime: Option<i64>) {
+        if let Some(n) = self.nodes.get_mut(id) {
+            if let Kind::File {
+                ref mut data,
+                ref mut chash,
+            } = n.kind
+            {
+                f(data);
+                *chash = content_hash(data).top_hash().clone();
+                n.mtime = mtime.unwrap_or_else(now);
+            }
+        }
+    }
+
+    fn issue_token(&mut self) -> serde_json::Value {
+        let access_token = format!("at-{}", self.next_token);
+        self.refresh_token = format!("rt-{}", self.next_token);
+        self.next_token += 1;
+        self.access_tokens.insert(access_token.clone());
+        json!({
+            "refresh_token": self.refresh_token,
+            "expires_in": 3600,
+            "userid": "mock.user",
+            "access_token": access_token,
+            "alias": "test",
+            "token_type": "Bearer",
+            "scope": "rw,user",
+        })
+    }
+}
+
+/// The HiDrive mock server. It runs until dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockServer {
+    /// Home directory of the mock user.
+    pub const HOME: &'static str = "/users/test";
+
+    /// Start a server on a free port of 127.0.0.1.
+    pub async fn start() -> MockServer {
+        let state = Arc::new(Mutex::new(State::new()));
+        let st = state.clone();
+        let mk = hyper::service::make_service_fn(move |_| {
+            let st = st.clone();
+            st.lock().unwrap().connections += 1;
+            async move {
+                Ok::<_, Infallible>(hyper::service::service_fn(move |rq| {
+                    handle(st.clone(), rq).map(Ok::<_, Infallible>)
+                }))
+            }
+        });
+        let srv = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(mk);
+        let addr = srv.local_addr();
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(srv.with_graceful_shutdown(rx.map(|_| ())));
+        info!(target: "hd_api::testing", "MockServer listening on {}", addr);
+        MockServer {
+            addr,
+            state,
+            shutdown: Some(tx),
+        }
+    }
+
+    /// Base URL of the API, to be used with `HiDrive::set_base_url`.
+    pub fn api_url(&self) -> String {
+        format!("http://{}{}", self.addr, API_PREFIX)
+    }
+
+    /// URL of the OAuth2 token endpoint, to be used with `Authorizer::set_token_url`.
+    pub fn token_url(&self) -> String {
+        format!("http://{}{}", self.addr, TOKEN_PATH)
+    }
+
+    /// Credentials with the currently valid refresh token and no access token.
+    pub fn credentials(&self) -> Credentials {
+        let st = self.state.lock().unwrap();
+        serde_json::from_value(json!({
+            "refresh_token": st.refresh_token,
+            "expires_in": 3600,
+            "userid": "mock.user",
+            "access_token": "",
+            "alias": "test",
+            "token_type": "Bearer",
+        }))
+        .unwrap()
+    }
+
+    pub fn client_secret(&self) -> ClientSecret {
+        serde_json::from_value(json!({"client_id": "mock-id", "client_secret": "mock-secret"}))
+            .unwrap()
+    }
+
+    /// A `HiDriveBuilder` with the API and token endpoints pointing to this server.
+    pub fn builder(&self) -> HiDriveBuilder {
+        let mut b = HiDrive::builder();
+        b.base_url(self.api_url()).token_url(self.token_url());
+        b
+    }
+
+    /// An `Authorizer` obtaining tokens from this server.
+    pub fn authorizer(&self) -> Authorizer {
+        self.builder()
+            .authorizer(self.credentials(), self.client_secret())
+            .expect("default HTTP client")
+    }
+
+    /// A `HiDrive` client talking to this server.
+    pub fn hidrive(&self) -> HiDrive {
+        self.builder()
+            .build(self.authorizer())
+            .expect("default HTTP client")
+    }
+
+    /// Create a directory and all missing parents. Returns the directory's ID.
+    pub fn mkdir_p(&self, path: &str) -> String {
+        self.state.lock().unwrap().mkdir_p(path)
+    }
+
+    /// Create or replace a file, creating missing parent directories. Returns the file's ID.
+    pub fn put_file(&self, path: &str, data: &[u8], mtime: i64) -> String {
+        self.state.lock().unwrap().put_file(path, data, mtime)
+    }
+
+    /// Content of the file at `path`.
+    pub fn read_file(&self, path: &str) -> Option<Vec<u8>> {
+        let st = self.state.lock().unwrap();
+        let id = st.walk(&st.root, path)?;
+        match st.nodes[&id].kind {
+            Kind::File { ref data, .. } => Some(data.clone()),
+            Kind::Dir(_) => None,
+        }
+    }
+
+    /// Metadata of the object at `path`, as the API would return it.
+    pub fn item(&self, path: &str) -> Option<Item> {
+        let st = self.state.lock().unwrap();
+        let id = st.walk(&st.root, path)?;
+        Some(st.item(&id, true))
+    }
+
+    /// Remove a file or directory.
+    pub fn remove(&self, path: &str) {
+        let mut st = self.state.lock().unwrap();
+        if let Some(id) = st.walk(&st.root, path) {
+            st.remove(&id);
+        }
+    }
+
+    /// Invalidate all access tokens, as if the server had revoked them.
+    pub fn revoke_tokens(&self) {
+        self.state.lock().unwrap().access_tokens.clear();
+    }
+
+    /// Set the mtime of the object at `path` once `requests` further API requests have been
+    /// answered, as if another client changed it in between.
+    pub fn set_mtime_after(&self, requests: usize, path: &str, mtime: i64) {
+        let path = path.to_string();
+        self.change_after(requests, move |st| {
+            if let Some(id) = st.walk(&st.root, &path) {
+                st.nodes.get_mut(&id).unwrap().mtime = mtime;
+            }
+        });
+    }
+
+    /// Like `put_file`, once `requests` further API requests have been answered.
+    pub fn put_file_after(&self, requests: usize, path: &str, data: &[u8], mtime: i64) {
+        let (path, data) = (path.to_string(), data.to_vec());
+        self.change_after(requests, move |st| {
+            st.put_file(&path, &data, mtime);
+        });
+    }
+
+    fn change_after<F: FnOnce(&mut State) + Send + 'static>(&self, requests: usize, f: F) {
+        let mut st = self.state.lock().unwrap();
+        let at = st.requests + requests + 1;
+        st.changes.push((at, Box::new(f)));
+    }
+
+    /// Answer the next API request with `status`, before looking at it.
+    pub fn fail_next(&self, status: u16) {
+        let status = StatusCode::from_u16(status).expect("valid status");
+        self.state.lock().unwrap().failures.push_back(status);
+    }
+
+    /// Number of API requests received (excluding the token endpoint).
+    pub fn requests(&self) -> usize {
+        self.state.lock().unwrap().requests
+    }
+
+    /// Number of tokens issued by the token endpoint.
+    pub fn token_refreshes(&self) -> usize {
+        self.state.lock().unwrap().refreshes
+    }
+
+    /// Number of TCP connections accepted so far.
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().connections
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(tx) = self.shutdown.take() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+async fn handle(state: Arc<Mutex<State>>, rq: hyper::Request<Body>) -> Reply {
+    let (parts, body) = rq.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(b) => b,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e.to_string()).reply(),
+    };
+    let params: Params = parts
+        .uri
+        .query()
+        .map(|q| {
+            reqwest::Url::parse(&format!("http://localhost/?{}", q))
+                .unwrap()
+                .query_pairs()
+                .into_owned()
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut st = state.lock().unwrap();
+    info!(target: "hd_api::testing", "{} {} {:?}", parts.method, parts.uri.path(), params);
+
+    if parts.uri.path() == TOKEN_PATH {
+        return token(&mut st, &params);
+    }
+    st.requests += 1;
+    let n = st.requests;
+    while let Some(i) = st.changes.iter().position(|c| c.0 == n) {
+        let (_, change) = st.changes.remove(i);
+        change(&mut st);
+    }
+    if let Some(status) = st.failures.pop_front() {
+        return error(status, "injected failure").reply();
+    }
+    let authorized = parts
+        .headers
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(|t| st.access_tokens.contains(t))
+        .unwrap_or(false);
+    if !authorized {
+        return reply(
+            StatusCode::UNAUTHORIZED,
+            &json!({"code": 401, "msg": "Unauthorized", "auth": "invalid_token"}),
+        );
+    }
+    let range = parts
+        .headers
+        .get("Range")
+        .and_then(|h| h.to_str().ok())
+        .map(String::from);
+    let path = parts
+        .uri
+        .path()
+        .strip_prefix(API_PREFIX)
+        .unwrap_or("")
+        .to_string();
+    api(&mut st, &parts.method, &path, &params, range, &body).unwrap_or_else(Fail::reply)
+}
+
+fn token(st: &mut State, p: &Params) -> Reply {
+    let ok = match p.get("grant_type").map(String::as_str) {
+        Some("refresh_token") => p.get("refresh_token") == Some(&st.refresh_token),
+        Some("authorization_code") => p.contains_key("code"),
+        _ => false,
+    };
+    if !ok {
+        return reply(
+            StatusCode::BAD_REQUEST,
+            &json!({"error": "invalid_grant", "error_description": "invalid grant"}),
+        );
+    }
+    st.refreshes += 1;
+    reply(StatusCode::OK, &st.issue_token())
+}
+
+fn api(
+    st: &mut State,
+    method: &Method,
+    path: &str,
+    p: &Params,
+    range: Option<String>,
+    body: &[u8],
+) -> Result<Reply, Fail> {
+    let with_members = p
+        .get("fields")
+        .map(|f| f.contains("members"))
+        .unwrap_or(true);
+    match (method, path) {
+        (&Method::GET, "/user/me") => {
+            let home_id = st.walk(&st.root, MockServer::HOME).unwrap();
+            let user = User {
+                account: "mock.user".into(),
+                alias: "test".into(),
+                home: MockServer::HOME.into(),
+                folder: st.item(&home_id, false),
+                home_id,
+                ..Default::default()
+            };
+            Ok(reply(StatusCode::OK, &user))
+        }
+        (&Method::GET, "/permission") | (&Method::PUT, "/permission") => {
+            let id = st.resolve(p, "pid", "path")?;
+            let flag = |k: &str| p.get(k).map(|v| v == "true").unwrap_or(true);
+            let perm = Permissions {
+                account: p.get("account").cloned().unwrap_or("mock.user".into()),
+                readable: flag("readable"),
+                writable: flag("writable"),
+                path: st.path(&id),
+            };
+            Ok(reply(StatusCode::OK, &perm))
+        }
+        (&Method::GET, "/meta") => {
+            let id = st.resolve(p, "pid", "path")?;
+            Ok(reply(StatusCode::OK, &st.item(&id, false)))
+        }
+        (&Method::PUT, "/meta") => {
+            let id = st.resolve(p, "pid", "path")?;
+            if let Some(parent) = st.nodes[&id].parent.clone() {
+                st.check_mtime(p, "parent_mtime", &parent)?;
+            }
+            if let Some(m) = p.get("mtime") {
+                let m = m
+                    .parse()
+                    .map_err(|_| error(StatusCode::BAD_REQUEST, "invalid mtime"))?;
+                st.nodes.get_mut(&id).unwrap().mtime = m;
+            }
+            Ok(reply(StatusCode::OK, &st.item(&id, false)))
+        }
+        (&Method::GET, "/file") => {
+            let id = st.resolve(p, "pid", "path")?;
+            let data = st.file_data(&id)?;
+            get_file(data, range)
+        }
+        (&Method::POST, "/file") | (&Method::PUT, "/file") => {
+            let dir = st.resolve(p, "dir_id", "dir")?;
+            let name = p
+                .get("name")
+                .ok_or_else(|| error(StatusCode::BAD_REQUEST, "missing name"))?;
+            st.check_mtime(p, "parent_mtime", &dir)?;
+            let name = st.on_exist(p, &dir, name, method == Method::PUT)?;
+            let mtime = p
+                .get("mtime")
+                .and_then(|m| m.parse().ok())
+                .unwrap_or_else(now);
+            let kind = Kind::File {
+                data: body.to_vec(),
+                chash: content_hash(body).top_hash().clone(),
+            };
+            let id = st.add_node(Some(&dir), &name, kind, mtime);
+            Ok(reply(StatusCode::CREATED, &st.item(&id, false)))
+        }
+        (&Method::PATCH, "/file") => {
+            let id = st.resolve(p, "pid", "path")?;
+            let offset: usize = p
+                .get("offset")
+                .and_then(|o| o.parse().ok())
+                .ok_or_else(|| error(StatusCode::BAD_REQUEST, "missing offset"))?;
+            if offset > st.file_data(&id)?.len() {
+                return Err(error(
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    "offset beyond end of file",
+                ));
+            }
+            let mtime = p.get("mtime").and_then(|m| m.parse().ok());
+            st.update_file(
+                &id,
+                |data| {
+                    let end = offset + body.len();
+                    if data.len() < end {
+                        data.resize(end, 0);
+                    }
+                    data[offset..end].copy_from_slice(body);
+                },
+                mtime,
+            );
+            Ok(no_content())
+        }
+        (&Method::DELETE, "/file") => {
+            let id = st.resolve(p, "pid", "path")?;
+            st.file_data(&id)?;
+            let parent = st.nodes[&id].parent.clone().unwrap();
+            st.check_mtime(p, "parent_mtime", &parent)?;
+            st.remove(&id);
+            Ok(no_content())
+        }
+        (&Method::POST, "/file/truncate") => {
+            let id = st.resolve(p, "pid", "path")?;
+            let size: usize = p
+                .get("size")
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| error(StatusCode::BAD_REQUEST, "missing size"))?;
+            st.file_data(&id)?;
+            st.update_file(&id, |data| data.resize(size, 0), None);
+            Ok(reply(StatusCode::OK, &st.item(&id, false)))
+        }
+        (&Method::GET, "/file/hash") => file_hash(st, p),
+        (_, "/file/rename") | (&Method::POST, "/dir/rename") => {
+            let id = st.resolve(p, "pid", "path")?;
+            let name = p
+                .get("name")
+                .ok_or_else(|| error(StatusCode::BAD_REQUEST, "missing name"))?;
+            let parent = st.nodes[&id]
+                .parent
+                .clone()
+                .ok_or_else(|| error(StatusCode::FORBIDDEN, "can't rename root"))?;
+            st.check_mtime(p, "parent_mtime", &parent)?;
+            let new = st.on_exist(p, &parent, name, false)?;
+            let old = std::mem::replace(&mut st.nodes.get_mut(&id).unwrap().name, new.clone());
+            if let Some(Node {
+                kind: Kind::Dir(ref mut m),
+                ref mut mtime,
+                ..
+            }) = st.nodes.get_mut(&parent)
+            {
+                m.remove(&old);
+                m.insert(new, id.clone());
+                *mtime = now();
+            }
+            Ok(reply(StatusCode::OK, &st.item(&id, false)))
+        }
+        (&Method::POST, "/file/copy")
+        | (&Method::POST, "/file/move")
+        | (&Method::POST, "/dir/copy")
+        | (&Method::POST, "/dir/move") => {
+            let src = st.resolve(p, "src_id", "src")?;
+            let (dst, name) = st.resolve_new(p, "dst_id", "dst")?;
+            st.check_mtime(p, "dst_parent_mtime", &dst)?;
+            if let Some(ref sp) = st.nodes[&src].parent {
+                st.check_mtime(p, "src_parent_mtime", &sp.clone())?;
+            }
+            let name = st.on_exist(p, &dst, &name, false)?;
+            let preserve = p
+                .get("preserve_mtime")
+                .map(|v| v == "true")
+                .unwrap_or(false);
+            let id = if path.ends_with("copy") {
+                st.deep_copy(&src, &dst, &name, preserve)
+            } else {
+                let id = st.deep_copy(&src, &dst, &name, true);
+                st.remove(&src);
+                id
+            };
+            Ok(reply(StatusCode::OK, &st.item(&id, false)))
+        }
+        (&Method::GET, "/dir") => {
+            let id = st.resolve(p, "pid", "path")?;
+            if st.nodes[&id].members().is_none() {
+                return Err(error(StatusCode::BAD_REQUEST, "not a directory"));
+            }
+            let mut it = st.item(&id, with_members);
+            if let Some(limit) = p.get("limit") {
+                let (offset, count) = match limit.split_once(',') {
+                    Some((o, c)) => (o.parse(), c.parse()),
+                    None => (Ok(0), limit.parse()),
+                };
+                let (offset, count): (usize, usize) = match (offset, count) {
+                    (Ok(o), Ok(c)) => (o, c),
+                    _ => return Err(error(StatusCode::BAD_REQUEST, "invalid limit")),
+                };
+                it.members = it.members.into_iter().skip(offset).take(count).collect();
+            }
+            Ok(reply(StatusCode::OK, &it))
+        }
+        (&Method::GET, "/dir/home") => {
+            let id = st.walk(&st.root, MockServer::HOME).unwrap();
+            Ok(reply(StatusCode::OK, &st.item(&id, with_members)))
+        }
+        (&Method::POST, "/dir") => {
+            let (parent, name) = st.resolve_new(p, "pid", "path")?;
+            st.check_mtime(p, "parent_mtime", &parent)?;
+            let name = st.on_exist(p, &parent, &name, false)?;
+            let mtime = p
+                .get("mtime")
+                .and_then(|m| m.parse().ok())
+                .unwrap_or_else(now);
+            let id = st.add_node(Some(&parent), &name, Kind::Dir(BTreeMap::new()), mtime);
+            Ok(reply(StatusCode::CREATED, &st.item(&id, false)))
+        }
+        (&Method::DELETE, "/dir") => {
+            let id = st.resolve(p, "pid", "path")?;
+            let parent = st.nodes[&id]
+                .parent
+                .clone()
+                .ok_or_else(|| error(StatusCode::FORBIDDEN, "can't delete root"))?;
+            let empty = match st.nodes[&id].members() {
+                Some(m) => m.is_empty(),
+                None => return Err(error(StatusCode::BAD_REQUEST, "not a directory")),
+            };
+            if !empty && p.get("recursive").map(|r| r != "true").unwrap_or(true) {
+                return Err(error(StatusCode::CONFLICT, "directory not empty"));
+            }
+            st.check_mtime(p, "parent_mtime", &parent)?;
+            st.remove(&id);
+            Ok(no_content())
+        }
+        (&Method::GET, "/search") => {
+            let root = st.resolve(p, "pid", "path")?;
+            let pattern = p.get("pattern").cloned().unwrap_or_default();
+            let mut result = vec![];
+            let mut queue = VecDeque::from([root]);
+            while let Some(d) = queue.pop_front() {
+                for c in st.nodes[&d].members().into_iter().flat_map(|m| m.values()) {
+                    if glob_match(&pattern, &st.nodes[c].name) {
+                        result.push(st.item(c, false));
+                    }
+                    if st.nodes[c].members().is_some() {
+                        queue.push_back(c.clone());
+                    }
+                }
+            }
+            Ok(reply(StatusCode::OK, &SearchResult { result }))
+        }
+        (&Method::POST, "/sharelink") => {
+            let id = st.resolve(p, "pid", "path")?;
+            let n = &st.nodes[&id];
+            let share_id = format!("s{}", st.next_share);
+            st.next_share += 1;
+            let share = Share {
+                id: Some(share_id.clone()),
+                name: Some(n.name.clone()),
+                path: Some(st.path(&id)),
+                pid: Some(id.clone()),
+                size: Some(st.size(&id)),
+                status: Some("valid".into()),
+                share_type: Some("sharelink".into()),
+                file_type: Some(if n.members().is_some() { "dir" } else { "file" }.into()),
+                created: OffsetDateTime::from_unix_timestamp(now()).ok(),
+                uri: Some(format!("https://my.hidrive.com/share/{}", share_id)),
+                count: Some(0),
+                ..Default::default()
+            };
+            let share = update_share(share, p)?;
+            st.shares.insert(share_id, share.clone());
+            Ok(reply(StatusCode::CREATED, &share))
+        }
+        (&Method::GET, "/sharelink") => {
+            let filter = match p.get("id") {
+                Some(_) => None,
+                None if p.contains_key("pid") || p.contains_key("path") => {
+                    Some(st.resolve(p, "pid", "path")?)
+                }
+                None => None,
+            };
+            let shares: Vec<&Share> = st
+                .shares
+                .values()
+                .filter(|s| {
+                    p.get("id")
+                        .map(|id| s.id.as_ref() == Some(id))
+                        .unwrap_or(true)
+                })
+                .filter(|s| filter.is_none() || s.pid == filter)
+                .collect();
+            if p.contains_key("id") && shares.is_empty() {
+                return Err(error(StatusCode::NOT_FOUND, "Not Found"));
+            }
+            Ok(reply(StatusCode::OK, &shares))
+        }
+        (&Method::PUT, "/sharelink") => {
+            let share = p
+                .get("id")
+                .and_then(|id| st.shares.get(id))
+                .cloned()
+                .ok_or_else(|| error(StatusCode::NOT_FOUND, "Not Found"))?;
+            let share = update_share(share, p)?;
+            st.shares.insert(share.id.clone().unwrap(), share.clone());
+            Ok(reply(StatusCode::OK, &share))
+        }
+        (&Method::DELETE, "/sharelink") => {
+            p.get("id")
+                .and_then(|id| st.shares.remove(id))
+                .ok_or_else(|| error(StatusCode::NOT_FOUND, "Not Found"))?;
+            Ok(no_content())
+        }
+        _ => Err(error(StatusCode::NOT_FOUND, "no such endpoint")),
+    }
+}
+
+fn get_file(data: &[u8], range: Option<String>) -> Result<Reply, Fail> {
+    let range = match range {
+        None => return Ok(Response::new(Body::from(data.to_vec()))),
+        Some(r) => r,
+    };
+    let unsatisfiable = || error(StatusCode::RANGE_NOT_SATISFIABLE, "invalid range");
+    let (a, b) = range
+        .strip_prefix("bytes=")
+        .and_then(|r| r.split_once('-'))
+        .ok_or_else(unsatisfiable)?;
+    let start: usize = a.parse().map_err(|_| unsatisfiable())?;
+    let end: usize = if b.is_empty() {
+        data.len().saturating_sub(1)
+    } else {
+        b.parse::<usize>()
+            .map_err(|_| unsatisfiable())?
+            .min(data.len().saturating_sub(1))
+    };
+    if start >= data.len() || end < start {
+        return Err(unsatisfiable());
+    }
+    Ok(Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(
+            "Content-Range",
+            format!("bytes {}-{}/{}", start, end, data.len()),
+        )
+        .body(Body::from(data[start..=end].to_vec()))
+        .unwrap())
+}
+
+fn file_hash(st: &mut State, p: &Params) -> Result<Reply, Fail> {
+    let id = st.resolve(p, "pid", "path")?;
+    let hashes = content_hash(st.file_data(&id)?);
+    let level: usize = p.get("level").and_then(|l| l.parse().ok()).unwrap_or(0);
+    let hl = hashes
+        .l
+        .get(level)
+        .ok_or_else(|| error(StatusCode::BAD_REQUEST, "level too high"))?;
+    let ranges: Vec<(usize, usize)> = match p.get("ranges").map(String::as_str) {
+        None | Some("-") => vec![(0, hl.h.len().min(256).saturating_sub(1))],
+        Some(r) => r
+            .split(',')
+            .map(|r| {
+                let (a, b) = r.split_once('-')?;
+                Some((a.parse().ok()?, b.parse().ok()?))
+            })
+            .collect::<Option<_>>()
+            .ok_or_else(|| error(StatusCode::BAD_REQUEST, "invalid ranges"))?,
+    };
+    let list: Vec<Vec<HashedBlock>> = ranges
+        .into_iter()
+        .map(|(a, b)| {
+            (a..=b.min(hl.h.len().saturating_sub(1)))
+                .filter(|i| *i < hl.h.len() && !hl.h[*i].is_zero_hash())
+                .map(|i| HashedBlock {
+                    hash: hl.h[i].clone(),
+                    level,
+                    block: i,
+                })
+                .collect()
+        })
+        .collect();
+    Ok(reply(
+        StatusCode::OK,
+        &FileHash {
+            level,
+            chash: hashes.top_hash().clone(),
+            list,
+        },
+    ))
+}
+
+/// Match a name against a pattern with `*` wildcards; without wildcards, match substrings.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    if !pattern.contains('*') {
+        return name.contains(pattern);
+    }
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let mut rest = name;
+    for (i, part) in parts.iter().enumerate() {
+        if i == 0 {
+            match rest.strip_prefix(part) {
+                Some(r) => rest = r,
+                None => return false,
+            }
+        } else if i == parts.len() - 1 {
+            return rest.ends_with(part);
+        } else {
+            match rest.find(part) {
+                Some(pos) => rest = &rest[pos + part.len()..],
+                None => return false,
+            }
+        }
+    }
+    rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MockServer;
+    use crate::types::{Identifier, Params};
+
+    #[tokio::test]
+    async fn test_files_and_dirs() {
+        let srv = MockServer::start().await;
+        let hd = srv.hidrive();
+        let files = hd.files();
+        let home = Identifier::Path(MockServer::HOME.into());
+
+        files.mkdir(home.join("d"), None).await.unwrap();
+        let it = files
+            .upload_no_overwrite(home.join("d"), "a.txt", b"hello".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(Some(5), it.size);
+        assert!(files
+            .upload_no_overwrite(home.join("d"), "a.txt", b"again".to_vec(), None)
+            .await
+            .unwrap_err()
+            .is_conflict());
+        let mut p = Params::new();
+        p.add_str("on_exist", "autoname");
+        let it = files
+            .upload_no_overwrite(home.join("d"), "a.txt", b"again".to_vec(), Some(&p))
+            .await
+            .unwrap();
+        assert_eq!(Some("a (1).txt".into()), it.name);
+
+        let dir = files.get_dir(home.join("d"), None).await.unwrap();
+        assert_eq!(2, dir.members.len());
+        assert_eq!(srv.item("/users/test/d").unwrap().chash, dir.chash);
+
+        let mut out = vec![];
+        files
+            .get(home.join("d/a.txt"), &mut out, None)
+            .await
+            .unwrap();
+        assert_eq!(b"hello".to_vec(), out);
+
+        files.delete(home.join("d/a.txt"), None).await.unwrap();
+        assert!(files
+            .metadata(home.join("d/a.txt"), "id", None)
+            .await
+            .unwrap_err()
+            .is_not_found());
+    }
+
+    #[tokio::test]
+    async fn test_reauthorize_after_revocation() {
+        let srv = MockServer::start().await;
+        let hd = srv.hidrive();
+        hd.user().me(None).await.unwrap();
+        assert_eq!(1, srv.token_refreshes());
+
+        srv.revoke_tokens();
+        let me = hd.user().me(None).await.unwrap();
+        assert_eq!(MockServer::HOME, me.home);
+        assert_eq!(2, srv.token_refreshes());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_share_one_refresh() {
+        let srv = MockServer::start().await;
+        let hd = srv.hidrive();
+        let (a, b) = (hd.clone(), hd.clone());
+        let (ra, rb) = tokio::join!(async move { a.user().me(None).await }, async move {
+            b.user().me(None).await
+        });
+        ra.unwrap();
+        rb.unwrap();
+        assert_eq!(1, srv.token_refreshes());
+    }
+}
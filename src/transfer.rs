@@ -0,0 +1,925 @@
+//! Transfers of large files.
+//!
+//! `HiDriveFiles::upload` sends a file in a single request, which the API limits to 2 gigabytes.
+//! `ChunkedUpload` creates the file with the first chunk, and appends the remaining data using
+//! partial writes (`PATCH /file`). As every acknowledged chunk extends the remote file, an
+//! interrupted upload can be resumed from the remote file's current size.
+//!
+//! `DeltaUpload` updates an existing remote file by rewriting only the 4 KiB blocks which differ
+//! from the local file. The differing blocks are found by comparing the local hash tree (see
+//! `hashing::chash`) with the remote one (`/file/hash`) from the top level down, descending only
+//! into differing subtrees; appending to a large file thus only transfers the new tail.
+//!
+//! In the other direction, `download_resumable` continues a download from the length of the
+//! local file using ranged requests, and verifies the result against the remote `chash`.
+//!
+//! A `TransferControl` reports the progress of a transfer to a callback or a `watch` channel, and
+//! aborts it when its `CancellationToken` is cancelled:
+//!
+//! ```ignore
+//! let mut ctl = TransferControl::new();
+//! let mut progress = ctl.watch();
+//! let cancel = ctl.cancellation_token();
+//! tokio::spawn(async move {
+//!     while progress.changed().await.is_ok() {
+//!         let p = progress.borrow().clone();
+//!         println!("{}/{:?} bytes, {:.0} B/s", p.done, p.total, p.rate);
+//!     }
+//! });
+//! hd.files().get_with_control(id, out, &ctl, None).await?;
+//! ```
+
+use crate::error::{Context, Error, Result};
+use crate::hashing;
+use crate::hidrive::HiDriveFiles;
+use crate::types::*;
+
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+use log::{info, warn};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::watch;
+pub use tokio_util::sync::CancellationToken;
+
+/// Default size of a chunk sent in one request.
+pub const DEFAULT_CHUNK_SIZE: usize = 32 * 1024 * 1024;
+
+const RESULT_FIELDS: &str = "id,parent_id,name,path,size,ctime,mtime,chash,mhash,nhash";
+
+/// Progress of a transfer.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Progress {
+    /// Bytes transferred, including those transferred before a transfer was resumed.
+    pub done: u64,
+    /// Size of the file, if known.
+    pub total: Option<u64>,
+    /// Average rate in bytes per second since the transfer (or its resumption) started.
+    pub rate: f64,
+}
+
+type ProgressCallback = Arc<dyn Fn(&Progress) + Send + Sync>;
+
+/// Observes and cancels transfers. Clones share the callback, channel and cancellation token.
+#[derive(Clone, Default)]
+pub struct TransferControl {
+    callback: Option<ProgressCallback>,
+    watch: Option<Arc<watch::Sender<Progress>>>,
+    cancel: CancellationToken,
+}
+
+impl TransferControl {
+    pub fn new() -> TransferControl {
+        Default::default()
+    }
+
+    /// Call `f` whenever data has been transferred.
+    pub fn on_progress<F: Fn(&Progress) + Send + Sync + 'static>(&mut self, f: F) -> &mut Self {
+        self.callback = Some(Arc::new(f));
+        self
+    }
+
+    /// Return a channel receiving the latest progress. Replaces a previously created channel.
+    pub fn watch(&mut self) -> watch::Receiver<Progress> {
+        let (tx, rx) = watch::channel(Progress::default());
+        self.watch = Some(Arc::new(tx));
+        rx
+    }
+
+    /// Use `token` instead of the control's own token, e.g. a child token of an application-wide
+    /// one.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) -> &mut Self {
+        self.cancel = token;
+        self
+    }
+
+    /// The token cancelling transfers using this control.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Abort transfers using this control. They fail with `Error::Cancelled`.
+    pub fn cancel(&self) {
+        self.cancel.cancel()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Run `f` unless or until the transfer is cancelled.
+    pub(crate) async fn run<T, F: Future<Output = Result<T>>>(&self, f: F) -> Result<T> {
+        tokio::select! {
+            biased;
+            _ = self.cancel.cancelled() => Err(Error::Cancelled),
+            r = f => r,
+        }
+    }
+
+    /// Start tracking a transfer beginning at byte `start`.
+    pub(crate) fn tracker(&self, start: u64, total: Option<u64>) -> ProgressTracker {
+        ProgressTracker {
+            ctl: self.clone(),
+            started: Instant::now(),
+            start,
+            progress: Progress {
+                done: start,
+                total,
+                rate: 0.,
+            },
+        }
+    }
+}
+
+impl std::fmt::Debug for TransferControl {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("TransferControl")
+            .field("callback", &self.callback.is_some())
+            .field("watch", &self.watch.is_some())
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+/// Accumulates the progress of one transfer and reports it to a `TransferControl`.
+pub(crate) struct ProgressTracker {
+    ctl: TransferControl,
+    started: Instant,
+    start: u64,
+    progress: Progress,
+}
+
+impl ProgressTracker {
+    pub(crate) fn set_total(&mut self, total: u64) {
+        self.progress.total = Some(total);
+    }
+
+    pub(crate) fn total(&self) -> Option<u64> {
+        self.progress.total
+    }
+
+    /// Record `n` more bytes as transferred.
+    pub(crate) fn add(&mut self, n: usize) {
+        self.progress.done += n as u64;
+        let secs = self.started.elapsed().as_secs_f64();
+        if secs > 0. {
+            self.progress.rate = (self.progress.done - self.start) as f64 / secs;
+        }
+        if let Some(ref cb) = self.ctl.callback {
+            cb(&self.progress);
+        }
+        if let Some(ref w) = self.ctl.watch {
+            w.send_replace(self.progress.clone());
+        }
+    }
+}
+
+/// Upload a file of arbitrary size in chunks.
+///
+/// ```ignore
+/// let mut up = ChunkedUpload::new(Identifier::Path("/users/me/backup".into()), "disk.img");
+/// up.set_mtime(mtime).set_overwrite(true);
+/// let item = up.upload(&hd.files(), tokio::fs::File::open("disk.img").await?).await?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct ChunkedUpload {
+    dir: Identifier,
+    name: String,
+    chunk_size: usize,
+    mtime: Option<i64>,
+    overwrite: bool,
+    size: Option<u64>,
+    params: Option<Params>,
+    control: TransferControl,
+}
+
+impl ChunkedUpload {
+    /// Prepare uploading a file called `name` into the directory `dir`.
+    pub fn new<S: AsRef<str>>(dir: Identifier, name: S) -> ChunkedUpload {
+        ChunkedUpload {
+            dir,
+            name: name.as_ref().into(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            mtime: None,
+            overwrite: false,
+            size: None,
+            params: None,
+            control: TransferControl::default(),
+        }
+    }
+
+    /// Set the number of bytes sent per request (at least 1).
+    pub fn set_chunk_size(&mut self, chunk_size: usize) -> &mut Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Set the modification time (seconds since epoch) of the uploaded file. It is applied after
+    /// the last chunk has been written.
+    pub fn set_mtime(&mut self, mtime: i64) -> &mut Self {
+        self.mtime = Some(mtime);
+        self
+    }
+
+    /// Replace an existing file instead of failing with code 409.
+    pub fn set_overwrite(&mut self, overwrite: bool) -> &mut Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Pass further parameters to the request creating the file, e.g. `parent_mtime` or
+    /// `on_exist`.
+    pub fn set_params(&mut self, p: Params) -> &mut Self {
+        self.params = Some(p);
+        self
+    }
+
+    /// Report progress to, and allow cancellation through `ctl`. Progress is reported whenever a
+    /// chunk has been acknowledged.
+    pub fn set_control(&mut self, ctl: TransferControl) -> &mut Self {
+        self.control = ctl;
+        self
+    }
+
+    /// Set the total size reported as progress by `upload`. `resume` uses the size of its source.
+    pub fn set_size(&mut self, size: u64) -> &mut Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// Identifier of the uploaded file.
+    pub fn file_id(&self) -> Identifier {
+        self.dir.join(&self.name)
+    }
+
+    /// Upload the entire content of `src`, creating the remote file. The `chash` of the data is
+    /// computed while it is sent, and compared to the remote file's afterwards.
+    pub async fn upload<R: AsyncRead + Unpin>(
+        &self,
+        files: &HiDriveFiles<'_>,
+        mut src: R,
+    ) -> Result<Item> {
+        let mut progress = self.control.tracker(0, self.size);
+        let chunk = read_chunk(&mut src, self.chunk_size).await?;
+        let len = chunk.len();
+        let mut hasher = hashing::ChashHasher::new();
+        hasher.update(&chunk);
+        info!(target: "hd_api::transfer", "ChunkedUpload: creating {} with {} bytes", self.name, len);
+        let created = self
+            .control
+            .run(async {
+                if self.overwrite {
+                    files
+                        .upload(self.dir.clone(), &self.name, chunk, self.params.as_ref())
+                        .await
+                } else {
+                    files
+                        .upload_no_overwrite(
+                            self.dir.clone(),
+                            &self.name,
+                            chunk,
+                            self.params.as_ref(),
+                        )
+                        .await
+                }
+            })
+            .await
+            .context("ChunkedUpload: creating file")?;
+        progress.add(len);
+        let id = match created.id {
+            Some(id) => Identifier::Id(id),
+            None => self.file_id(),
+        };
+        self.upload_chunks(files, id, len, src, progress, Some(hasher))
+            .await
+    }
+
+    /// Continue an interrupted upload. The remote file's size is taken as the last acknowledged
+    /// offset; `src` is positioned there and the remaining data is appended. If the remote file
+    /// doesn't exist yet, the upload is started from scratch.
+    ///
+    /// The remote prefix isn't checked before appending, but all of `src` is hashed and compared
+    /// to the remote file's `chash` afterwards: resuming onto a file which doesn't hold the start
+    /// of `src`, e.g. an older version left by an interrupted overwrite, fails with an error.
+    pub async fn resume<R: AsyncRead + AsyncSeek + Unpin>(
+        &self,
+        files: &HiDriveFiles<'_>,
+        mut src: R,
+    ) -> Result<Item> {
+        let remote = match files.metadata(self.file_id(), "id,size", None).await {
+            Ok(it) => it,
+            Err(e) if e.is_not_found() => {
+                src.seek(SeekFrom::Start(0)).await?;
+                return self.upload(files, src).await;
+            }
+            Err(e) => return Err(e),
+        };
+        let offset = remote.size.unwrap_or(0);
+        let local_len = src.seek(SeekFrom::End(0)).await?;
+        if offset as u64 > local_len {
+            return Err(Error::msg(format!(
+                "ChunkedUpload: remote file has {} bytes, but local file only {}",
+                offset, local_len
+            )));
+        }
+        src.seek(SeekFrom::Start(0)).await?;
+        let mut hasher = hashing::ChashHasher::new();
+        let mut prefix = (&mut src).take(offset as u64);
+        loop {
+            let chunk = read_chunk(&mut prefix, self.chunk_size).await?;
+            if chunk.is_empty() {
+                break;
+            }
+            hasher.update(&chunk);
+        }
+        info!(target: "hd_api::transfer", "ChunkedUpload: resuming {} at offset {}", self.name, offset);
+        let id = match remote.id {
+            Some(id) => Identifier::Id(id),
+            None => self.file_id(),
+        };
+        let progress = self.control.tracker(offset as u64, Some(local_len));
+        self.upload_chunks(files, id, offset, src, progress, Some(hasher))
+            .await
+    }
+
+    /// Append the content of `src` to the existing file `id`, starting at remote offset `offset`,
+    /// and finalize the file. Use this if the acknowledged offset has been tracked by the caller.
+    pub async fn upload_from<R: AsyncRead + Unpin>(
+        &self,
+        files: &HiDriveFiles<'_>,
+        id: Identifier,
+        offset: usize,
+        src: R,
+    ) -> Result<Item> {
+        let progress = self.control.tracker(offset as u64, self.size);
+        self.upload_chunks(files, id, offset, src, progress, None)
+            .await
+    }
+
+    async fn upload_chunks<R: AsyncRead + Unpin>(
+        &self,
+        files: &HiDriveFiles<'_>,
+        id: Identifier,
+        mut offset: usize,
+        mut src: R,
+        mut progress: ProgressTracker,
+        mut hasher: Option<hashing::ChashHasher>,
+    ) -> Result<Item> {
+        loop {
+            let chunk = read_chunk(&mut src, self.chunk_size).await?;
+            if chunk.is_empty() {
+                break;
+            }
+            let len = chunk.len();
+            if let Some(ref mut h) = hasher {
+                h.update(&chunk);
+            }
+            self.control
+                .run(files.patch(id.clone(), offset, chunk, None))
+                .await
+                .with_context(|| format!("ChunkedUpload: writing at offset {}", offset))?;
+            offset += len;
+            progress.add(len);
+            info!(target: "hd_api::transfer", "ChunkedUpload: {} bytes acknowledged", offset);
+        }
+        let item = self.finalize(files, id).await?;
+        if let (Some(h), Some(ref remote)) = (hasher, &item.chash) {
+            let local = h.finalize();
+            if local.top_hash() != remote {
+                return Err(Error::msg(format!(
+                    "ChunkedUpload: chash mismatch after upload: local {}, remote {}",
+                    local.top_hash(),
+                    remote
+                )));
+            }
+        }
+        Ok(item)
+    }
+
+    async fn finalize(&self, files: &HiDriveFiles<'_>, id: Identifier) -> Result<Item> {
+        if let Some(mtime) = self.mtime {
+            let mut p = Params::new();
+            p.add_int("mtime", mtime as isize);
+            files
+                .set_metadata(id.clone(), Some(&p))
+                .await
+                .context("ChunkedUpload: setting mtime")?;
+        }
+        files.metadata(id, RESULT_FIELDS, None).await
+    }
+}
+
+/// Update an existing remote file to the content of a local file, transferring only the 4 KiB
+/// blocks which differ. The remote file is truncated if the local file is shorter.
+///
+/// The local file is hashed with `hashing::chash_file_parallel`, so it must not be truncated during
+/// the upload. The partial writes carry no precondition: a concurrent change of the remote file is
+/// only detected by comparing the `chash` at the end, when the file has already been modified.
+///
+/// ```ignore
+/// let mut up = DeltaUpload::new(Identifier::Path("/users/me/logs/server.log".into()));
+/// up.set_mtime(mtime);
+/// let report = up.upload(&hd.files(), "server.log").await?;
+/// println!("sent {} bytes", report.bytes);
+/// ```
+#[derive(Debug, Clone)]
+pub struct DeltaUpload {
+    id: Identifier,
+    chunk_size: usize,
+    mtime: Option<i64>,
+    control: TransferControl,
+}
+
+/// What a `DeltaUpload` has changed.
+#[derive(Debug)]
+pub struct DeltaReport {
+    /// The remote file after the upload.
+    pub item: Item,
+    /// Byte ranges which have been rewritten, in ascending order.
+    pub ranges: Vec<Range<u64>>,
+    /// Number of bytes sent.
+    pub bytes: u64,
+    /// Whether the remote file has been truncated.
+    pub truncated: bool,
+}
+
+impl DeltaUpload {
+    /// Prepare updating the remote file `id`.
+    pub fn new(id: Identifier) -> DeltaUpload {
+        DeltaUpload {
+            id,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            mtime: None,
+            control: TransferControl::default(),
+        }
+    }
+
+    /// Set the maximum number of bytes sent per request (at least 1).
+    pub fn set_chunk_size(&mut self, chunk_size: usize) -> &mut Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Set the modification time (seconds since epoch) of the remote file after the upload.
+    pub fn set_mtime(&mut self, mtime: i64) -> &mut Self {
+        self.mtime = Some(mtime);
+        self
+    }
+
+    /// Report progress to, and allow cancellation through `ctl`. The total is the number of
+    /// bytes to be rewritten.
+    pub fn set_control(&mut self, ctl: TransferControl) -> &mut Self {
+        self.control = ctl;
+        self
+    }
+
+    /// Make the remote file equal to the local file at `path`. Afterwards, the remote `chash` is
+    /// compared to the local one; a mismatch, e.g. due to a concurrent change, is reported as
+    /// error.
+    pub async fn upload<P: AsRef<Path>>(
+        &self,
+        files: &HiDriveFiles<'_>,
+        path: P,
+    ) -> Result<DeltaReport> {
+        let path = path.as_ref();
+        let local = hashing::chash_file_parallel(path).await?;
+        let mut f = fs::File::open(path).await?;
+        let size = f.metadata().await?.len();
+        let remote = files
+            .metadata(self.id.clone(), "size,chash", None)
+            .await
+            .context("DeltaUpload: fetching metadata")?;
+        let remote_size = remote.size.unwrap_or(0) as u64;
+
+        let unchanged = size == remote_size && remote.chash.as_ref() == Some(local.top_hash());
+        let mut blocks = if unchanged || remote_size == 0 {
+            BTreeSet::new()
+        } else {
+            self.differing_blocks(files, &local, remote_size).await?
+        };
+        // Blocks beyond the remote end are written even if they only contain zeros, as partial
+        // writes can't start behind the end of the file.
+        if size > remote_size {
+            let from = remote_size / BLOCK as u64;
+            blocks.extend(from as usize..size.div_ceil(BLOCK as u64) as usize);
+        }
+        let ranges = block_ranges(&blocks, size);
+        let total = ranges.iter().map(|r| r.end - r.start).sum();
+        info!(target: "hd_api::transfer", "DeltaUpload: {} of {} bytes differ in {} ranges", total, size, ranges.len());
+
+        let mut progress = self.control.tracker(0, Some(total));
+        for r in ranges.iter() {
+            f.seek(SeekFrom::Start(r.start)).await?;
+            let mut src = (&mut f).take(r.end - r.start);
+            let mut offset = r.start as usize;
+            loop {
+                let chunk = read_chunk(&mut src, self.chunk_size).await?;
+                let len = chunk.len();
+                if len == 0 {
+                    break;
+                }
+                self.control
+                    .run(files.patch(self.id.clone(), offset, chunk, None))
+                    .await
+                    .with_context(|| format!("DeltaUpload: writing at offset {}", offset))?;
+                offset += len;
+                progress.add(len);
+            }
+        }
+        let truncated = size < remote_size;
+        if truncated {
+            files
+                .truncate(self.id.clone(), size as usize, None)
+                .await
+                .context("DeltaUpload: truncating")?;
+        }
+        if let Some(mtime) = self.mtime {
+            let mut p = Params::new();
+            p.add_int("mtime", mtime as isize);
+            files
+                .set_metadata(self.id.clone(), Some(&p))
+                .await
+                .context("DeltaUpload: setting mtime")?;
+        }
+        let item = files.metadata(self.id.clone(), RESULT_FIELDS, None).await?;
+        if size > 0 && item.chash.as_ref() != Some(local.top_hash()) {
+            return Err(Error::msg(format!(
+                "DeltaUpload: chash mismatch after upload: local {}, remote {:?}",
+                local.top_hash(),
+                item.chash
+            )));
+        }
+        Ok(DeltaReport {
+            item,
+            ranges,
+            bytes: total,
+            truncated,
+        })
+    }
+
+    /// Find the level-0 blocks whose hashes differ, descending from the top of both trees into
+    /// differing blocks only. Remote hashes are fetched for the blocks compared at each level.
+    async fn differing_blocks(
+        &self,
+        files: &HiDriveFiles<'_>,
+        local: &hashing::Hashes,
+        remote_size: u64,
+    ) -> Result<BTreeSet<usize>> {
+        let mut remote = hashing::Hashes::new_partial(remote_size);
+        let levels = usize::max(local.l.len(), remote.l.len());
+        // Levels missing on either side belong to a larger file, and differ.
+        let common = usize::min(local.l.len(), remote.l.len());
+        // The top level consists of a single block.
+        let mut candidates: Vec<Range<usize>> = std::iter::once(0..1).collect();
+        for level in (0..levels).rev() {
+            if level < common {
+                for batch in candidates.chunks(HASH_RANGES_PER_REQUEST) {
+                    let batch: Vec<(usize, usize)> =
+                        batch.iter().map(|r| (r.start, r.end - 1)).collect();
+                    let fh = self
+                        .control
+                        .run(files.hash(self.id.clone(), level, &batch, None))
+                        .await
+                        .with_context(|| {
+                            format!("DeltaUpload: fetching hashes at level {}", level)
+                        })?;
+                    remote.add_api_hashes(&fh, &batch);
+                }
+            }
+            let differing = local.differing(&remote, level, &candidates);
+            if level == 0 {
+                return Ok(differing.into_iter().collect());
+            }
+            candidates = local.children(&remote, level, &differing);
+        }
+        Ok(BTreeSet::new())
+    }
+}
+
+/// Number of block ranges requested from `/file/hash` at once.
+const HASH_RANGES_PER_REQUEST: usize = 16;
+const BLOCK: usize = hashing::BLOCK_SIZE;
+
+/// Merge adjacent blocks into byte ranges, ending at `size`.
+fn block_ranges(blocks: &BTreeSet<usize>, size: u64) -> Vec<Range<u64>> {
+    let mut ranges: Vec<Range<u64>> = vec![];
+    for b in blocks.iter() {
+        let start = (*b * BLOCK) as u64;
+        let end = u64::min(start + BLOCK as u64, size);
+        if start >= end {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(r) if r.end == start => r.end = end,
+            _ => ranges.push(start..end),
+        }
+    }
+    ranges
+}
+
+/// Download the file `id` to the local path `dst`, continuing from the current length of `dst`
+/// if it exists. Once complete, the local file's `chash` is compared to the remote one. On a
+/// mismatch, e.g. because the remote file changed since the partial download, `dst` is removed and
+/// an error is returned; calling this function again downloads the file from the start.
+///
+/// Returns the number of bytes downloaded by this call. `p` is passed on to both the metadata and
+/// the download request, and may specify `snapshot, snaptime`.
+pub async fn download_resumable<D: AsRef<Path>>(
+    files: &HiDriveFiles<'_>,
+    id: Identifier,
+    dst: D,
+    p: Option<&Params>,
+) -> Result<usize> {
+    download_resumable_with_control(files, id, dst, &TransferControl::default(), p).await
+}
+
+/// Like `download_resumable`, reporting progress to and allowing cancellation through `ctl`. A
+/// cancelled download can be continued by calling this function again.
+pub async fn download_resumable_with_control<D: AsRef<Path>>(
+    files: &HiDriveFiles<'_>,
+    id: Identifier,
+    dst: D,
+    ctl: &TransferControl,
+    p: Option<&Params>,
+) -> Result<usize> {
+    let remote = files
+        .metadata(id.clone(), "size,chash", p)
+        .await
+        .context("download_resumable: fetching metadata")?;
+    let size = remote.size.unwrap_or(0);
+
+    let mut f = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dst.as_ref())
+        .await?;
+    let mut have = f.metadata().await?.len() as usize;
+    if have > size {
+        warn!(target: "hd_api::transfer", "local file {:?} is larger than remote file: restarting download", dst.as_ref());
+        f.set_len(0).await?;
+        have = 0;
+    }
+    f.seek(SeekFrom::Start(have as u64)).await?;
+
+    let n = if have < size {
+        info!(target: "hd_api::transfer", "downloading {:?} from offset {}", dst.as_ref(), have);
+        let r = files
+            .get_range_with_control(id, &mut f, have, None, ctl, p)
+            .await;
+        // Keep what has been received, so that the download can be continued.
+        f.flush().await?;
+        r?
+    } else {
+        0
+    };
+    f.flush().await?;
+    drop(f);
+
+    if let Some(chash) = remote.chash {
+        let local = hashing::chash_file(dst.as_ref()).await?;
+        if *local.top_hash() != chash {
+            fs::remove_file(dst.as_ref()).await?;
+            return Err(Error::msg(format!(
+                "download_resumable: chash mismatch for {:?}: local {}, remote {}",
+                dst.as_ref(),
+                local.top_hash(),
+                chash
+            )));
+        }
+    }
+    Ok(n)
+}
+
+/// Read up to `n` bytes; fewer are only returned at the end of `r`.
+async fn read_chunk<R: AsyncRead + Unpin>(r: &mut R, n: usize) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(n);
+    r.take(n as u64).read_to_end(&mut buf).await?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockServer;
+
+    #[tokio::test]
+    async fn test_read_chunk() {
+        let data: Vec<u8> = (0..10u8).collect();
+        let mut src = &data[..];
+        let mut chunks = vec![];
+        loop {
+            let c = read_chunk(&mut src, 4).await.unwrap();
+            if c.is_empty() {
+                break;
+            }
+            chunks.push(c);
+        }
+        assert_eq!(
+            vec![4, 4, 2],
+            chunks.iter().map(Vec::len).collect::<Vec<_>>()
+        );
+        assert_eq!(data, chunks.concat());
+    }
+
+    #[test]
+    fn test_file_id() {
+        let file_id = |dir| match ChunkedUpload::new(dir, "a.bin").file_id() {
+            Identifier::Path(p) => (None, p),
+            Identifier::Relative { id, path } => (Some(id), path),
+            Identifier::Id(id) => panic!("unexpected ID {}", id),
+        };
+        assert_eq!(
+            (None, "/users/me/a.bin".into()),
+            file_id(Identifier::Path("/users/me/".into()))
+        );
+        assert_eq!(
+            (Some("b1".into()), "a.bin".into()),
+            file_id(Identifier::Id("b1".into()))
+        );
+        assert_eq!(
+            (Some("b1".into()), "sub/a.bin".into()),
+            file_id(Identifier::Relative {
+                id: "b1".into(),
+                path: "sub".into()
+            })
+        );
+    }
+
+    fn data(n: usize) -> Vec<u8> {
+        (0..n).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn test_chunked_upload_and_resume() {
+        let srv = MockServer::start().await;
+        let hd = srv.hidrive();
+        let files = hd.files();
+        let src = data(10_000);
+
+        let mut up = ChunkedUpload::new(Identifier::Path(MockServer::HOME.into()), "f.bin");
+        up.set_chunk_size(3000).set_mtime(1_600_000_000);
+        let it = up.upload(&files, &src[..]).await.unwrap();
+        assert_eq!(Some(src.len()), it.size);
+        assert_eq!(1_600_000_000, it.mtime.unwrap().unix_timestamp());
+        assert_eq!(Some(src.clone()), srv.read_file("/users/test/f.bin"));
+
+        // Simulate an interruption after the first chunk.
+        srv.put_file("/users/test/f.bin", &src[..3000], 0);
+        up.resume(&files, std::io::Cursor::new(src.clone()))
+            .await
+            .unwrap();
+        assert_eq!(Some(src.clone()), srv.read_file("/users/test/f.bin"));
+
+        // The previous version of the file is still there, unrelated to the new data.
+        srv.put_file("/users/test/f.bin", &[0xaa; 4000], 0);
+        let err = up
+            .resume(&files, std::io::Cursor::new(src))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("chash mismatch"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_download_resumable() {
+        let srv = MockServer::start().await;
+        let hd = srv.hidrive();
+        let src = data(20_000);
+        srv.put_file("/users/test/g.bin", &src, 0);
+        let dst = std::env::temp_dir().join(format!("hd_api_download_{}", std::process::id()));
+        std::fs::write(&dst, &src[..5000]).unwrap();
+
+        let id = Identifier::Path("/users/test/g.bin".into());
+        let n = download_resumable(&hd.files(), id.clone(), &dst, None)
+            .await
+            .unwrap();
+        assert_eq!(15_000, n);
+        assert_eq!(src, std::fs::read(&dst).unwrap());
+
+        // A corrupted prefix is detected by the chash comparison, and the download restarts.
+        let mut bad = src.clone();
+        bad[0] ^= 1;
+        std::fs::write(&dst, &bad[..5000]).unwrap();
+        assert!(download_resumable(&hd.files(), id.clone(), &dst, None)
+            .await
+            .is_err());
+        assert!(!dst.exists());
+        let n = download_resumable(&hd.files(), id, &dst, None)
+            .await
+            .unwrap();
+        assert_eq!(20_000, n);
+        assert_eq!(src, std::fs::read(&dst).unwrap());
+        std::fs::remove_file(&dst).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delta_upload() {
+        let srv = MockServer::start().await;
+        let hd = srv.hidrive();
+        let files = hd.files();
+        // Spans two level-1 groups, so that the comparison has to descend.
+        let old = data(300 * 4096 + 100);
+        srv.put_file("/users/test/log.bin", &old, 0);
+        let src = std::env::temp_dir().join(format!("hd_api_delta_{}", std::process::id()));
+        let id = Identifier::Path("/users/test/log.bin".into());
+
+        let mut new = old.clone();
+        new[5 * 4096 + 17] ^= 0xff;
+        new.extend_from_slice(&data(10_000));
+        std::fs::write(&src, &new).unwrap();
+        let mut up = DeltaUpload::new(id.clone());
+        up.set_chunk_size(4096).set_mtime(1_600_000_000);
+        let report = up.upload(&files, &src).await.unwrap();
+        assert_eq!(
+            vec![5 * 4096..6 * 4096, 300 * 4096..new.len() as u64],
+            report.ranges
+        );
+        assert!(!report.truncated);
+        assert_eq!(Some(new.clone()), srv.read_file("/users/test/log.bin"));
+        assert_eq!(1_600_000_000, report.item.mtime.unwrap().unix_timestamp());
+
+        // Nothing to do.
+        let report = up.upload(&files, &src).await.unwrap();
+        assert_eq!(0, report.bytes);
+
+        // Shrinking rewrites the partial last block and truncates.
+        new.truncate(200 * 4096 + 10);
+        std::fs::write(&src, &new).unwrap();
+        let report = up.upload(&files, &src).await.unwrap();
+        assert_eq!(vec![200 * 4096..new.len() as u64], report.ranges);
+        assert!(report.truncated);
+        assert_eq!(Some(new), srv.read_file("/users/test/log.bin"));
+        std::fs::remove_file(&src).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_progress_and_cancellation() {
+        let srv = MockServer::start().await;
+        let hd = srv.hidrive();
+        let files = hd.files();
+        let src = data(50_000);
+        let home = Identifier::Path(MockServer::HOME.into());
+
+        let mut ctl = TransferControl::new();
+        let mut rx = ctl.watch();
+        let it = files
+            .upload_with_control(
+                home.clone(),
+                "p.bin",
+                std::io::Cursor::new(src.clone()),
+                Some(50_000),
+                &ctl,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(Some(50_000), it.size);
+        assert!(rx.has_changed().unwrap());
+        let p = rx.borrow_and_update().clone();
+        assert_eq!((50_000, Some(50_000)), (p.done, p.total));
+
+        let mut out = vec![];
+        files
+            .get_with_control(home.join("p.bin"), &mut out, &ctl, None)
+            .await
+            .unwrap();
+        assert_eq!(src, out);
+        assert_eq!(Some(50_000), rx.borrow().total);
+
+        // Cancel a chunked upload once the first chunk has been acknowledged, then resume it.
+        let mut ctl = TransferControl::new();
+        let token = ctl.cancellation_token();
+        ctl.on_progress(move |p| {
+            if p.done >= 20_000 {
+                token.cancel();
+            }
+        });
+        let mut up = ChunkedUpload::new(home.clone(), "c.bin");
+        up.set_chunk_size(20_000).set_control(ctl);
+        let err = up.upload(&files, &src[..]).await.unwrap_err();
+        assert!(err.is_cancelled());
+        assert_eq!(
+            Some(src[..20_000].to_vec()),
+            srv.read_file("/users/test/c.bin")
+        );
+        up.set_control(TransferControl::new());
+        up.resume(&files, std::io::Cursor::new(src.clone()))
+            .await
+            .unwrap();
+        assert_eq!(Some(src), srv.read_file("/users/test/c.bin"));
+
+        let ctl = TransferControl::new();
+        ctl.cancel();
+        assert!(files
+            .get_with_control(home.join("p.bin"), vec![], &ctl, None)
+            .await
+            .unwrap_err()
+            .is_cancelled());
+    }
+}
@@ -7,7 +7,7 @@ use serde::ser::SerializeSeq;
 use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
 
-#[derive(Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ParamValue {
     String(String),
     Bool(bool),
@@ -26,7 +26,7 @@ impl Display for ParamValue {
     }
 }
 
-#[derive(Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Param {
     name: String,
     val: ParamValue,
@@ -43,7 +43,7 @@ impl Display for Param {
 /// Use Params to supply optional query parameters to API calls. This implements the required trait
 /// of `P` parameters in API methods. Alternatively, you can use constructs like `&[("key",
 /// "value")]`.
-#[derive(Default, Clone)]
+#[derive(Debug, Default, Clone)]
 pub struct Params {
     p: LinkedList<Param>,
 }
@@ -126,15 +126,12 @@ impl std::error::Error for ApiError {}
 
 impl Display for ApiError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        if self.auth.is_none() {
-            f.write_fmt(format_args!("ApiError {}: {}", self.code, self.msg))
-        } else {
-            f.write_fmt(format_args!(
+        match self.auth {
+            None => f.write_fmt(format_args!("ApiError {}: {}", self.code, self.msg)),
+            Some(ref auth) => f.write_fmt(format_args!(
                 "ApiError {}: {} {}",
-                self.code,
-                self.msg,
-                self.auth.as_ref().unwrap()
-            ))
+                self.code, self.msg, auth
+            )),
         }
     }
 }
@@ -151,6 +148,28 @@ pub enum Identifier {
 }
 
 impl Identifier {
+    /// Returns the identifier of the entry `name` within the directory identified by `self`.
+    pub fn join<S: AsRef<str>>(&self, name: S) -> Identifier {
+        let name = name.as_ref();
+        match self {
+            Identifier::Id(ref id) => Identifier::Relative {
+                id: id.clone(),
+                path: name.into(),
+            },
+            Identifier::Path(ref p) => {
+                Identifier::Path(format!("{}/{}", p.trim_end_matches('/'), name))
+            }
+            Identifier::Relative { ref id, ref path } if path.is_empty() => Identifier::Relative {
+                id: id.clone(),
+                path: name.into(),
+            },
+            Identifier::Relative { ref id, ref path } => Identifier::Relative {
+                id: id.clone(),
+                path: format!("{}/{}", path.trim_end_matches('/'), name),
+            },
+        }
+    }
+
     pub fn to_params<S: AsRef<str>>(&self, p: &mut Params, id_parameter: S, path_parameter: S) {
         match self {
             Identifier::Id(ref s) => p.add_str(id_parameter.as_ref(), s),
@@ -198,7 +217,7 @@ pub struct Protocols {
     pub git: bool,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Item {
     pub path: String,
@@ -232,7 +251,7 @@ pub struct Item {
     pub rshare: Option<Share>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Share {
     pub name: Option<String>,
@@ -299,22 +318,137 @@ pub struct SearchResult {
     pub result: Vec<Item>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+/// Arguments of a message on the notification WebSocket, in both directions.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct WebsocketArgs {
-    code: Option<String>,
-    event: Option<String>,
-    id: Option<usize>,
-    pid: Option<String>,
-    recursive: Option<bool>,
-    subs_id: Option<usize>,
-    tld_chash: Option<String>,
-    tld_mhash: Option<String>,
+    /// Status of a reply.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    /// Kind of change, see `ChangeKind`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<String>,
+    /// Request ID, chosen by the client and repeated in the reply.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<usize>,
+    /// ID of a directory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recursive: Option<bool>,
+    /// Subscription ID, assigned by the server.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subs_id: Option<usize>,
+    /// `chash` of the subscribed (top level) directory after the change.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tld_chash: Option<Hash>,
+    /// `mhash` of the subscribed directory after the change.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tld_mhash: Option<Hash>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+/// A message on the notification WebSocket as sent over the wire. See `Notification` for the
+/// decoded form.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct WebsocketNotification {
-    name: String,
-    args: WebsocketArgs,
+    pub name: String,
+    pub args: WebsocketArgs,
+}
+
+/// Kind of a change reported by a notification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Deleted,
+    Moved,
+    /// An event not known to this crate.
+    Other(String),
+}
+
+impl From<&str> for ChangeKind {
+    fn from(s: &str) -> ChangeKind {
+        match s.to_ascii_lowercase().as_str() {
+            "create" | "created" => ChangeKind::Created,
+            "modify" | "modified" => ChangeKind::Modified,
+            "delete" | "deleted" => ChangeKind::Deleted,
+            "move" | "moved" => ChangeKind::Moved,
+            _ => ChangeKind::Other(s.into()),
+        }
+    }
+}
+
+impl Serialize for ChangeKind {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(match self {
+            ChangeKind::Created => "created",
+            ChangeKind::Modified => "modified",
+            ChangeKind::Deleted => "deleted",
+            ChangeKind::Moved => "moved",
+            ChangeKind::Other(ev) => ev,
+        })
+    }
+}
+
+/// A change below a subscribed directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChangeEvent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subs_id: Option<usize>,
+    pub kind: ChangeKind,
+    /// ID of the directory containing the changed object.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tld_chash: Option<Hash>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tld_mhash: Option<Hash>,
+}
+
+/// A decoded message received on the notification WebSocket. It serializes as an object keyed by
+/// the lowercase variant name, e.g. `{"change":{"kind":"created",...}}`, or as `"gap"`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Notification {
+    Change(ChangeEvent),
+    /// The reply to a request, such as `subscribe`.
+    Reply {
+        id: usize,
+        subs_id: Option<usize>,
+        code: Option<String>,
+    },
+    /// A message which is neither of the above.
+    Other(WebsocketNotification),
+    /// Emitted by `notify::NotificationStream` after reconnecting: changes may have been missed
+    /// while the connection was down, so that watched directories should be rescanned.
+    Gap,
+}
+
+impl From<WebsocketNotification> for Notification {
+    fn from(n: WebsocketNotification) -> Notification {
+        let a = n.args;
+        match (a.event, a.id) {
+            (Some(ev), _) => Notification::Change(ChangeEvent {
+                subs_id: a.subs_id,
+                kind: ChangeKind::from(ev.as_str()),
+                pid: a.pid,
+                tld_chash: a.tld_chash,
+                tld_mhash: a.tld_mhash,
+            }),
+            (None, Some(id)) => Notification::Reply {
+                id,
+                subs_id: a.subs_id,
+                code: a.code,
+            },
+            (None, None) => Notification::Other(WebsocketNotification {
+                name: n.name,
+                args: WebsocketArgs {
+                    event: None,
+                    id: None,
+                    ..a
+                },
+            }),
+        }
+    }
 }
@@ -0,0 +1,266 @@
+//! Recursive traversal of remote trees, see `HiDriveFiles::walk`.
+//!
+//! ```ignore
+//! let mut walk = hd.files().walk(Identifier::Path("/users/me".into()), "path,size");
+//! walk.set_concurrency(8).set_max_depth(3);
+//! let mut items = walk.stream();
+//! while let Some(it) = items.next().await {
+//!     println!("{}", it?.path);
+//! }
+//! ```
+
+use crate::error::Result;
+use crate::hashing::Hash;
+use crate::hidrive::{self, HiDriveFiles, DIR_PAGE_SIZE};
+use crate::types::{Identifier, Item, Params};
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use futures_util::future::{BoxFuture, FutureExt};
+use futures_util::stream::{BoxStream, FuturesUnordered, StreamExt};
+
+/// Fields the walker needs for itself.
+const WALK_FIELDS: &[&str] = &["id", "type"];
+
+/// A breadth-first traversal of a remote tree, listing up to `concurrency` directories at once.
+/// Directories are listed in pages of `DIR_PAGE_SIZE` members, see `HiDriveFiles::list_dir`.
+///
+/// Items are yielded in the order their directory listings arrive: first the root, then the
+/// members of each directory. A failed listing is yielded as error, and the walk continues with
+/// the other directories.
+pub struct Walk<'a> {
+    files: HiDriveFiles<'a>,
+    root: Identifier,
+    fields: String,
+    concurrency: usize,
+    max_depth: Option<usize>,
+    page_size: usize,
+    known: HashMap<String, Hash>,
+}
+
+impl<'a> Walk<'a> {
+    pub(crate) fn new(files: HiDriveFiles<'a>, root: Identifier, fields: &str) -> Walk<'a> {
+        Walk {
+            files,
+            root,
+            fields: fields.into(),
+            concurrency: 4,
+            max_depth: None,
+            page_size: DIR_PAGE_SIZE,
+            known: HashMap::new(),
+        }
+    }
+
+    /// Number of concurrent directory listings (default: 4).
+    pub fn set_concurrency(&mut self, n: usize) -> &mut Self {
+        self.concurrency = n.max(1);
+        self
+    }
+
+    /// Only list directories up to `depth` levels below the root. With depth 0, only the root
+    /// is yielded; with depth 1, also its members.
+    pub fn set_max_depth(&mut self, depth: usize) -> &mut Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Number of members requested per listing (default: `DIR_PAGE_SIZE`).
+    pub fn set_page_size(&mut self, n: usize) -> &mut Self {
+        self.page_size = n.max(1);
+        self
+    }
+
+    /// Directories whose `chash` is given in `known`, by path, are yielded but not listed; e.g.
+    /// the hashes recorded by a previous walk.
+    pub fn set_known_chashes(&mut self, known: HashMap<String, Hash>) -> &mut Self {
+        self.known = known;
+        self
+    }
+
+    /// Start the walk.
+    pub fn stream(self) -> BoxStream<'a, Result<Item>> {
+        let mut fields: BTreeSet<&str> = self
+            .fields
+            .split(',')
+            .map(str::trim)
+            .filter(|f| !f.is_empty())
+            .collect();
+        fields.extend(WALK_FIELDS);
+        if !self.known.is_empty() {
+            fields.extend(["path", "chash"]);
+        }
+        let mut all: Vec<String> = fields.iter().map(|f| f.to_string()).collect();
+        all.extend(fields.iter().map(|f| format!("members.{}", f)));
+        let mut params = Params::new();
+        params.add_str("fields", all.join(","));
+
+        let state = WalkState {
+            files: self.files,
+            params,
+            concurrency: self.concurrency,
+            max_depth: self.max_depth,
+            page_size: self.page_size,
+            known: self.known,
+            queue: VecDeque::from([(self.root, 0, 0)]),
+            running: FuturesUnordered::new(),
+            ready: VecDeque::new(),
+        };
+        futures_util::stream::unfold(state, |mut st| async move {
+            let r = st.next().await?;
+            Some((r, st))
+        })
+        .boxed()
+    }
+}
+
+/// A page of a directory listing to request: directory, its depth, and offset.
+type Page = (Identifier, usize, usize);
+
+struct WalkState<'a> {
+    files: HiDriveFiles<'a>,
+    params: Params,
+    concurrency: usize,
+    max_depth: Option<usize>,
+    page_size: usize,
+    known: HashMap<String, Hash>,
+    queue: VecDeque<Page>,
+    running: FuturesUnordered<BoxFuture<'a, (Page, Result<Item>)>>,
+    ready: VecDeque<Item>,
+}
+
+impl<'a> WalkState<'a> {
+    async fn next(&mut self) -> Option<Result<Item>> {
+        loop {
+            if let Some(it) = self.ready.pop_front() {
+                return Some(Ok(it));
+            }
+            while self.running.len() < self.concurrency {
+                let page = match self.queue.pop_front() {
+                    Some(p) => p,
+                    None => break,
+                };
+                let files = self.files.clone();
+                let mut params = self.params.clone();
+                params.add_str("limit", hidrive::dir_limit(page.2, self.page_size));
+                self.running.push(
+                    async move {
+                        let r = files.get_dir(page.0.clone(), Some(&params)).await;
+                        (page, r)
+                    }
+                    .boxed(),
+                );
+            }
+            let ((id, depth, offset), dir) = self.running.next().await?;
+            let mut dir = match dir {
+                Ok(d) => d,
+                Err(e) => return Some(Err(e)),
+            };
+            let members = std::mem::take(&mut dir.members);
+            if depth == 0 && offset == 0 {
+                self.ready.push_back(dir);
+            }
+            if self.max_depth.map(|d| depth >= d).unwrap_or(false) {
+                continue;
+            }
+            if members.len() == self.page_size {
+                self.queue.push_front((id, depth, offset + self.page_size));
+            }
+            for m in members {
+                if self.descend(&m, depth + 1) {
+                    self.queue
+                        .push_back((Identifier::Id(m.id.clone().unwrap()), depth + 1, 0));
+                }
+                self.ready.push_back(m);
+            }
+        }
+    }
+
+    fn descend(&self, it: &Item, depth: usize) -> bool {
+        it.typ.as_deref() == Some("dir")
+            && it.id.is_some()
+            && self.max_depth.map(|d| depth < d).unwrap_or(true)
+            && (it.chash.is_none() || self.known.get(&it.path) != it.chash.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockServer;
+
+    async fn paths(walk: Walk<'_>) -> Vec<String> {
+        let mut paths: Vec<String> = walk.stream().map(|it| it.unwrap().path).collect().await;
+        paths.sort();
+        paths
+    }
+
+    #[tokio::test]
+    async fn test_walk() {
+        let srv = MockServer::start().await;
+        let hd = srv.hidrive();
+        let files = hd.files();
+        srv.put_file("/users/test/t/a.txt", b"a", 1);
+        srv.put_file("/users/test/t/d1/b.txt", b"b", 1);
+        srv.put_file("/users/test/t/d1/d2/c.txt", b"c", 1);
+        srv.put_file("/users/test/t/e/f.txt", b"f", 1);
+        srv.mkdir_p("/users/test/t/empty");
+        let root = || Identifier::Path("/users/test/t".into());
+
+        let mut walk = files.walk(root(), "path,size");
+        walk.set_concurrency(2);
+        assert_eq!(
+            vec![
+                "/users/test/t",
+                "/users/test/t/a.txt",
+                "/users/test/t/d1",
+                "/users/test/t/d1/b.txt",
+                "/users/test/t/d1/d2",
+                "/users/test/t/d1/d2/c.txt",
+                "/users/test/t/e",
+                "/users/test/t/e/f.txt",
+                "/users/test/t/empty",
+            ],
+            paths(walk).await
+        );
+
+        // Listing in pages of two members.
+        let mut walk = files.walk(root(), "path");
+        walk.set_page_size(2);
+        assert_eq!(9, paths(walk).await.len());
+
+        let mut walk = files.walk(root(), "path");
+        walk.set_max_depth(1);
+        assert_eq!(5, paths(walk).await.len());
+        let mut walk = files.walk(root(), "path");
+        walk.set_max_depth(0);
+        assert_eq!(vec!["/users/test/t"], paths(walk).await);
+
+        // An unchanged subtree isn't listed.
+        let d1 = srv.item("/users/test/t/d1").unwrap();
+        let e = srv.item("/users/test/t/e").unwrap();
+        srv.put_file("/users/test/t/e/g.txt", b"g", 1);
+        let known = HashMap::from([(d1.path, d1.chash.unwrap()), (e.path, e.chash.unwrap())]);
+        let mut walk = files.walk(root(), "path");
+        walk.set_known_chashes(known);
+        let requests = srv.requests();
+        assert_eq!(
+            vec![
+                "/users/test/t",
+                "/users/test/t/a.txt",
+                "/users/test/t/d1",
+                "/users/test/t/e",
+                "/users/test/t/e/f.txt",
+                "/users/test/t/e/g.txt",
+                "/users/test/t/empty",
+            ],
+            paths(walk).await
+        );
+        assert_eq!(3, srv.requests() - requests);
+
+        let mut items = files
+            .walk(Identifier::Path("/users/test/none".into()), "path")
+            .stream();
+        assert!(items.next().await.unwrap().unwrap_err().is_not_found());
+        assert!(items.next().await.is_none());
+    }
+}
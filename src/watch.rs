@@ -0,0 +1,489 @@
+//! A feed of the changes below a remote directory.
+//!
+//! Notifications only tell that something changed below some directory. `RemoteWatcher` keeps a
+//! snapshot of the watched tree and, when notified, lists the tree again to find out what exactly
+//! was added, removed or modified. Like `sync::DirSync`, it only descends into directories whose
+//! `chash` changed. Notifications whose `tld_chash` and `tld_mhash` match the snapshot are
+//! ignored, and notifications arriving in quick succession are combined into one rescan, so that
+//! each change is reported once.
+//!
+//! If the notification channel fails or is switched off, the watcher polls the `chash` of the
+//! watched directory instead.
+//!
+//! ```ignore
+//! let mut w = RemoteWatcher::new(&hd, "/users/me/Documents");
+//! w.set_debounce(Duration::from_secs(5));
+//! loop {
+//!     for change in w.next().await? {
+//!         println!("{:?}", change);
+//!     }
+//! }
+//! ```
+
+use crate::error::Result;
+use crate::hidrive::{HiDrive, DIR_PAGE_SIZE};
+use crate::notify::NotificationStream;
+use crate::types::{Identifier, Item, Notification};
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use futures_util::stream::{BoxStream, StreamExt};
+use log::{info, warn};
+
+/// While notifications keep arriving, a rescan is delayed by at most this many debounce
+/// intervals.
+const MAX_SETTLE: u32 = 5;
+
+const DIR_FIELDS: &str = "id,name,type,mtime,size,chash,mhash,members.id,members.name,members.type,members.mtime,members.size,members.chash,members.mhash";
+
+/// A change below the watched directory. Paths are relative to it.
+///
+/// Only files are reported as modified; a changed directory is described by the changes below
+/// it. Moves are reported as removal and addition. If a directory is removed, only the directory
+/// is reported, not its contents.
+#[derive(Debug, Clone)]
+pub enum RemoteChange {
+    Added { path: String, item: Item },
+    Removed { path: String, id: Option<String> },
+    Modified { path: String, item: Item },
+}
+
+impl RemoteChange {
+    pub fn path(&self) -> &str {
+        match self {
+            RemoteChange::Added { path, .. }
+            | RemoteChange::Removed { path, .. }
+            | RemoteChange::Modified { path, .. } => path,
+        }
+    }
+}
+
+/// Watches a remote directory tree; see the module documentation.
+pub struct RemoteWatcher {
+    hd: HiDrive,
+    root: String,
+    debounce: Duration,
+    poll_interval: Duration,
+    use_notifications: bool,
+    notifications: Option<NotificationStream>,
+    // Items by relative path; the root is "". `None` before the first scan.
+    snapshot: Option<BTreeMap<String, Item>>,
+}
+
+impl RemoteWatcher {
+    /// Watch the directory at path `root`.
+    pub fn new<S: Into<String>>(hd: &HiDrive, root: S) -> RemoteWatcher {
+        RemoteWatcher {
+            hd: hd.clone(),
+            root: root.into(),
+            debounce: Duration::from_secs(2),
+            poll_interval: Duration::from_secs(60),
+            use_notifications: true,
+            notifications: None,
+            snapshot: None,
+        }
+    }
+
+    /// How long to wait for further notifications before rescanning (default: 2 seconds). Steady
+    /// notifications delay a rescan by at most five times as long.
+    pub fn set_debounce(&mut self, debounce: Duration) -> &mut Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// Interval of checking the hash of the watched directory if no notifications arrive
+    /// (default: 60 seconds).
+    pub fn set_poll_interval(&mut self, interval: Duration) -> &mut Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Whether to subscribe to notifications (default), or to rely on polling alone.
+    pub fn set_notifications(&mut self, enable: bool) -> &mut Self {
+        self.use_notifications = enable;
+        self
+    }
+
+    /// Subscribe to notifications and take the initial snapshot. Called by `next` if necessary;
+    /// changes made after `start` returns are reported by `next`.
+    pub async fn start(&mut self) -> Result<()> {
+        if self.use_notifications && self.notifications.is_none() {
+            let root = self
+                .hd
+                .files()
+                .metadata(Identifier::Path(self.root.clone()), "id", None)
+                .await?;
+            let stream = self.hd.notification_stream();
+            match stream
+                .subscribe(root.id.as_deref().unwrap_or_default(), true)
+                .await
+            {
+                Ok(_) => self.notifications = Some(stream),
+                Err(e) => {
+                    warn!(target: "hd_api::watch", "subscribing failed, polling instead: {:#}", e)
+                }
+            }
+        }
+        self.snapshot = Some(self.scan().await?);
+        Ok(())
+    }
+
+    /// The current snapshot of the watched tree, by relative path.
+    pub fn snapshot(&self) -> Option<&BTreeMap<String, Item>> {
+        self.snapshot.as_ref()
+    }
+
+    /// Wait for the next changes.
+    pub async fn next(&mut self) -> Result<Vec<RemoteChange>> {
+        if self.snapshot.is_none() {
+            self.start().await?;
+        }
+        loop {
+            self.wait_for_change().await?;
+            self.settle().await;
+            let new = self.scan().await?;
+            let changes = diff(self.snapshot.as_ref().unwrap(), &new);
+            self.snapshot = Some(new);
+            if !changes.is_empty() {
+                info!(target: "hd_api::watch", "{} changes below {}", changes.len(), self.root);
+                return Ok(changes);
+            }
+        }
+    }
+
+    /// The changes as a stream of batches.
+    pub fn into_stream(self) -> BoxStream<'static, Result<Vec<RemoteChange>>> {
+        futures_util::stream::unfold(self, |mut w| async move {
+            let r = w.next().await;
+            Some((r, w))
+        })
+        .boxed()
+    }
+
+    fn root_item(&self) -> Option<&Item> {
+        self.snapshot.as_ref()?.get("")
+    }
+
+    /// Return once the tree may have changed.
+    async fn wait_for_change(&mut self) -> Result<()> {
+        // Ignored notifications don't postpone polling.
+        let mut poll = tokio::time::interval(self.poll_interval);
+        poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        poll.tick().await;
+        loop {
+            let n = match self.notifications {
+                Some(ref mut ns) => {
+                    tokio::select! {
+                        n = ns.next() => Some(n),
+                        _ = poll.tick() => None,
+                    }
+                }
+                None => {
+                    poll.tick().await;
+                    None
+                }
+            };
+            match n {
+                Some(Some(Ok(Notification::Change(c)))) => {
+                    let root = self.root_item();
+                    let seen = c.tld_chash.is_some()
+                        && c.tld_chash == root.and_then(|r| r.chash.clone())
+                        && (c.tld_mhash.is_none()
+                            || c.tld_mhash == root.and_then(|r| r.mhash.clone()));
+                    if !seen {
+                        return Ok(());
+                    }
+                }
+                Some(Some(Ok(Notification::Gap))) => return Ok(()),
+                Some(Some(Ok(_))) => (),
+                Some(Some(Err(e))) => {
+                    warn!(target: "hd_api::watch", "notifications failed, polling instead: {:#}", e);
+                    self.notifications = None;
+                    return Ok(());
+                }
+                Some(None) => {
+                    self.notifications = None;
+                    return Ok(());
+                }
+                None => {
+                    let it = self
+                        .hd
+                        .files()
+                        .metadata(Identifier::Path(self.root.clone()), "chash", None)
+                        .await?;
+                    if it.chash != self.root_item().and_then(|r| r.chash.clone()) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Wait until no notification arrived for the debounce interval, or `MAX_SETTLE` intervals
+    /// passed.
+    async fn settle(&mut self) {
+        let ns = match self.notifications {
+            Some(ref mut ns) => ns,
+            None => return tokio::time::sleep(self.debounce).await,
+        };
+        let deadline = tokio::time::sleep(MAX_SETTLE * self.debounce);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                n = ns.next() => match n {
+                    Some(Ok(_)) => continue,
+                    Some(Err(_)) | None => break,
+                },
+                _ = tokio::time::sleep(self.debounce) => return,
+                _ = &mut deadline => return,
+            }
+        }
+        // The channel failed; it is dropped by the next `wait_for_change`.
+        tokio::time::sleep(self.debounce).await;
+    }
+
+    /// List the tree, skipping directories whose `chash` is the same as in the snapshot.
+    async fn scan(&self) -> Result<BTreeMap<String, Item>> {
+        let files = self.hd.files();
+        let mut new = BTreeMap::new();
+        let mut todo = vec![(String::new(), Identifier::Path(self.root.clone()))];
+        while let Some((rel, id)) = todo.pop() {
+            let mut dir = files.get_dir_paged(id, DIR_FIELDS, DIR_PAGE_SIZE).await?;
+            for m in dir.members.drain(..) {
+                let path = join(&rel, m.name.as_deref().unwrap_or_default());
+                if m.typ.as_deref() == Some("dir") {
+                    match self.snapshot.as_ref().and_then(|s| s.get(&path)) {
+                        Some(old)
+                            if old.id == m.id && old.chash.is_some() && old.chash == m.chash =>
+                        {
+                            copy_below(self.snapshot.as_ref().unwrap(), &path, &mut new);
+                        }
+                        _ => {
+                            if let Some(ref id) = m.id {
+                                todo.push((path.clone(), Identifier::Id(id.clone())));
+                            }
+                        }
+                    }
+                }
+                new.insert(path, m);
+            }
+            new.insert(rel, dir);
+        }
+        Ok(new)
+    }
+}
+
+fn join(rel: &str, name: &str) -> String {
+    if rel.is_empty() {
+        name.into()
+    } else {
+        format!("{}/{}", rel, name)
+    }
+}
+
+fn parent(path: &str) -> Option<&str> {
+    path.rfind('/').map(|i| &path[..i])
+}
+
+fn copy_below(from: &BTreeMap<String, Item>, dir: &str, to: &mut BTreeMap<String, Item>) {
+    // '0' follows '/'.
+    let range = format!("{}/", dir)..format!("{}0", dir);
+    for (k, v) in from.range(range) {
+        to.insert(k.clone(), v.clone());
+    }
+}
+
+fn diff(old: &BTreeMap<String, Item>, new: &BTreeMap<String, Item>) -> Vec<RemoteChange> {
+    // A path whose ID changes, e.g. by uploading to a temporary name and renaming, counts as
+    // modified, unless its type changes.
+    let replaced = |o: &Item, n: &Item| o.typ != n.typ;
+    let mut changes = vec![];
+    for (path, o) in old.iter() {
+        let gone = match new.get(path) {
+            None => true,
+            Some(n) => replaced(o, n),
+        };
+        let parent_gone = parent(path)
+            .map(|p| match (old.get(p), new.get(p)) {
+                (Some(o), Some(n)) => replaced(o, n),
+                _ => true,
+            })
+            .unwrap_or(false);
+        if gone && !parent_gone {
+            changes.push(RemoteChange::Removed {
+                path: path.clone(),
+                id: o.id.clone(),
+            });
+        }
+    }
+    for (path, n) in new.iter() {
+        match old.get(path) {
+            Some(o) if !replaced(o, n) => {
+                if n.typ.as_deref() != Some("dir") && (o.chash != n.chash || o.mhash != n.mhash) {
+                    changes.push(RemoteChange::Modified {
+                        path: path.clone(),
+                        item: n.clone(),
+                    });
+                }
+            }
+            _ => changes.push(RemoteChange::Added {
+                path: path.clone(),
+                item: n.clone(),
+            }),
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockServer;
+
+    fn summary(changes: &[RemoteChange]) -> Vec<String> {
+        changes
+            .iter()
+            .map(|c| match c {
+                RemoteChange::Added { path, .. } => format!("+{}", path),
+                RemoteChange::Removed { path, .. } => format!("-{}", path),
+                RemoteChange::Modified { path, .. } => format!("~{}", path),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_polling() {
+        let srv = MockServer::start().await;
+        let hd = srv.hidrive();
+        srv.put_file("/users/test/w/a.txt", b"a", 1);
+        srv.put_file("/users/test/w/gone/x.txt", b"x", 1);
+        srv.put_file("/users/test/w/gone/y.txt", b"y", 1);
+        srv.put_file("/users/test/w/keep/z.txt", b"z", 1);
+
+        let mut w = RemoteWatcher::new(&hd, "/users/test/w");
+        w.set_notifications(false)
+            .set_poll_interval(Duration::from_millis(20))
+            .set_debounce(Duration::from_millis(20));
+        w.start().await.unwrap();
+        assert_eq!(7, w.snapshot().unwrap().len());
+
+        srv.put_file("/users/test/w/a.txt", b"changed", 2);
+        srv.put_file("/users/test/w/new/b.txt", b"b", 1);
+        srv.remove("/users/test/w/gone");
+        let changes = w.next().await.unwrap();
+        assert_eq!(
+            vec!["-gone", "~a.txt", "+new", "+new/b.txt"],
+            summary(&changes)
+        );
+
+        // The unchanged directory hasn't been listed again.
+        let requests = srv.requests();
+        srv.put_file("/users/test/w/new/c.txt", b"c", 1);
+        let changes = w.next().await.unwrap();
+        assert_eq!(vec!["+new/c.txt"], summary(&changes));
+        // A poll, the root and "new".
+        assert!(
+            srv.requests() - requests <= 4,
+            "{}",
+            srv.requests() - requests
+        );
+    }
+
+    #[tokio::test]
+    async fn test_notifications() {
+        use futures_util::SinkExt;
+        use tokio::net::TcpListener;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let srv = MockServer::start().await;
+        srv.put_file("/users/test/w/a.txt", b"a", 1);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut b = srv.builder();
+        b.ws_url(format!("ws://{}/", listener.local_addr().unwrap()));
+        let hd = b.build(srv.authorizer()).unwrap();
+
+        let (notify, mut notified) = tokio::sync::mpsc::unbounded_channel::<String>();
+        let server = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+            ws.next().await.unwrap().unwrap();
+            let reply = r#"{"name":"subscribe","args":{"id":1,"subs_id":1}}"#;
+            ws.send(Message::Text(reply.into())).await.unwrap();
+            while let Some(n) = notified.recv().await {
+                ws.send(Message::Text(n)).await.unwrap();
+            }
+        });
+
+        let mut w = RemoteWatcher::new(&hd, "/users/test/w");
+        w.set_poll_interval(Duration::from_secs(3600))
+            .set_debounce(Duration::from_millis(20));
+        w.start().await.unwrap();
+        let event = |chash: &Option<crate::hashing::Hash>| {
+            format!(
+                r#"{{"name":"notify","args":{{"subs_id":1,"event":"modified","tld_chash":"{}"}}}}"#,
+                chash.as_ref().unwrap()
+            )
+        };
+
+        // An event for the state already seen is ignored.
+        let seen = srv.item("/users/test/w").unwrap().chash;
+        srv.put_file("/users/test/w/b.txt", b"b", 1);
+        let current = srv.item("/users/test/w").unwrap().chash;
+        notify.send(event(&seen)).unwrap();
+        notify.send(event(&current)).unwrap();
+        notify.send(event(&current)).unwrap();
+        let changes = w.next().await.unwrap();
+        assert_eq!(vec!["+b.txt"], summary(&changes));
+        assert_eq!(current, w.snapshot().unwrap()[""].chash);
+        drop(notify);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_steady_notifications() {
+        use futures_util::SinkExt;
+        use tokio::net::TcpListener;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let srv = MockServer::start().await;
+        srv.put_file("/users/test/w/a.txt", b"a", 1);
+        let seen = srv.item("/users/test/w").unwrap().chash.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut b = srv.builder();
+        b.ws_url(format!("ws://{}/", listener.local_addr().unwrap()));
+        let hd = b.build(srv.authorizer()).unwrap();
+
+        // Notifications for the state already seen keep arriving faster than the debounce
+        // interval until the watcher goes away.
+        let server = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+            ws.next().await.unwrap().unwrap();
+            let reply = r#"{"name":"subscribe","args":{"id":1,"subs_id":1}}"#;
+            ws.send(Message::Text(reply.into())).await.unwrap();
+            let event = format!(
+                r#"{{"name":"notify","args":{{"subs_id":1,"event":"modified","tld_chash":"{}"}}}}"#,
+                seen
+            );
+            while ws.send(Message::Text(event.clone())).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        });
+
+        let mut w = RemoteWatcher::new(&hd, "/users/test/w");
+        w.set_poll_interval(Duration::from_millis(50))
+            .set_debounce(Duration::from_millis(20));
+        w.start().await.unwrap();
+
+        // Neither polling nor the rescan are put off indefinitely.
+        srv.put_file("/users/test/w/b.txt", b"b", 1);
+        let changes = tokio::time::timeout(Duration::from_secs(5), w.next())
+            .await
+            .expect("change not picked up")
+            .unwrap();
+        assert_eq!(vec!["+b.txt"], summary(&changes));
+        drop(w);
+        server.await.unwrap();
+    }
+}